@@ -1,25 +1,27 @@
-FREQ = 16000000
+FREQ = frequency()
 time = 10
 led_freq = 2
 led_period = FREQ / led_freq
-led_pin = "mcu:15"
+led_pin = "mcu:D13"
 
-step = FREQ / 1000
+-- Tolerance on the timing of the edges, in cycles
+margin = FREQ / 1000
 
--- Wait until on
-while get_wire(led_pin) ~= true do
-    execute(step)
-end
--- Wait until off
-while get_wire(led_pin) ~= false do
-    execute(step)
-end
-
-execute(led_period/4)
+-- Wait until on, then off
+assert(wait_for_edge(led_pin, "rising", FREQ))
+local fall = wait_for_edge(led_pin, "falling", led_period)
+assert(fall)
 
 for i = 0, 10 do
-    execute(led_period/2)
+    local rise = wait_for_edge(led_pin, "rising", led_period)
+    assert(rise and math.abs(rise - fall - led_period / 2) < margin)
     assert(get_wire(led_pin) == true)
-    execute(led_period/2)
+    fall = wait_for_edge(led_pin, "falling", led_period)
+    assert(fall and math.abs(fall - rise - led_period / 2) < margin)
     assert(get_wire(led_pin) == false)
-end
\ No newline at end of file
+end
+
+-- Checked every 1000 cycles, the next rise is seen up to 999 cycles late
+local step = 1000
+local on = wait_until(function() return get_wire(led_pin) end, led_period, step)
+assert(on and on - fall >= led_period / 2 - margin and on - fall < led_period / 2 + margin + step)
@@ -0,0 +1,20 @@
+-- Halves the frequency of `in` on `out`, and pulses `strobe` for
+-- `params.strobe` cycles on every rising edge of `out`.
+local out = false
+
+function init()
+    set_pin("out", out)
+    set_pin("strobe", false)
+end
+
+function on_pin(pin, value, t)
+    if pin ~= "in" or not value then
+        return
+    end
+    out = not out
+    set_pin("out", out)
+    if out then
+        set_pin("strobe", true)
+        after(params.strobe, function() set_pin("strobe", false) end)
+    end
+end
@@ -0,0 +1,20 @@
+FREQ = frequency()
+led_period = FREQ / 2
+margin = FREQ / 1000
+
+local out = "mcu.divider:out"
+local strobe = "mcu.divider:strobe"
+
+-- `init` runs at the start of the simulation
+execute(100)
+assert(get_wire(out) == false)
+local rise = wait_for_edge(out, "rising", 2 * FREQ)
+assert(rise)
+for i = 1, 3 do
+    assert(get_wire(strobe) == true)
+    local fall = wait_for_edge(strobe, "falling", FREQ)
+    assert(fall == rise + 1000)
+    local next = wait_for_edge(out, "rising", 3 * led_period)
+    assert(next and math.abs(next - rise - 2 * led_period) < margin)
+    rise = next
+end
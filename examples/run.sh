@@ -1,11 +1,10 @@
 #!/bin/bash
 cd $(dirname $0)
-CMD=${1:-cargo run}
+CMD=${1:-cargo run --}
 
 for d in */ ; do
-    pushd $d
-    if [ -f test*.lua ]; then
-        $CMD test test*.lua
+    d=${d%/}
+    if [ -f $d/test*.lua ]; then
+        $CMD -c $d/input.yaml test $d/test*.lua
     fi
-    popd
-done
\ No newline at end of file
+done
@@ -0,0 +1,55 @@
+FREQ = frequency()
+uart = "mcu.uart"
+
+-- Every case starts on a fresh system, at the prompt
+setup(function()
+    assert(expect(uart, "> ", FREQ))
+end)
+
+teardown(function()
+    expect_eq(uart_read(uart), "", "nothing left unread")
+end)
+
+-- The firmware echoes each line and answers with its number of words
+for _, case in ipairs({ { "abc", 1 }, { "hello world", 2 }, { "a b c d", 4 } }) do
+    local line, words = case[1], case[2]
+    test("counts the words of '" .. line .. "'", function()
+        uart_send(uart, line .. "\n")
+        expect_true(expect(uart, line .. "\n", FREQ), "echo")
+        local answer = expect(uart, "\n> ", FREQ)
+        expect_eq(tonumber(answer), words)
+    end)
+end
+
+-- The echo is the first thing written to the data register of the USART
+test("echoes through UDR0", function()
+    local udr0 = break_on_write("mcu", 0xC6)
+    uart_send(uart, "x\n")
+    local hit = execute(FREQ)
+    cancel(udr0)
+    expect_true(hit, "UDR0 written")
+    expect_eq(hit.type, "write")
+    expect_eq(hit.value, string.byte("x"))
+    expect_true(expect(uart, "x\n1\n> ", FREQ))
+end)
+
+-- Bit 1 of the frame is the LSB of the data, which turns "a" into "`"
+test("echoes a corrupted frame", function()
+    uart_send(uart, "a\n")
+    expect_true(corrupt_frame("mcu:RXD0", 1, math.floor(FREQ / 9600), FREQ), "start bit")
+    expect_true(expect(uart, "`\n1\n> ", FREQ))
+end)
+
+test("loses the lines it doesn't receive", function()
+    local lost = drop_events("mcu.uart:TX", "mcu:RXD0")
+    uart_send(uart, "abc\n")
+    execute(FREQ / 10)
+    cancel(lost)
+    expect_eq(uart_read(uart), "")
+end)
+
+test("prompts again after a reset", function()
+    reset("mcu", 1000)
+    expect_eq(read_io("mcu", 0x34) & 0x02, 0x02, "EXTRF")
+    expect_true(expect(uart, "> ", FREQ))
+end)
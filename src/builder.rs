@@ -0,0 +1,387 @@
+//! Construction of a [System] in Rust code, without a config file.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    clock::FREQ,
+    components::{
+        avr::mcu::Mcu,
+        led::Led,
+        uart_module::{UartConfig, UartModule},
+    },
+    events::EventQueue,
+    module::{ActiveModule, WireableModule},
+    module_id::{ModuleAddress, ModuleIndex, PinAddress},
+    parser::{expand_bus, ConfigError, ConfigErrors},
+    system::{find_pin_addr, System},
+    system_tables::SystemTables,
+    vcd::VcdReceiver,
+    wiring::WiringTable,
+};
+
+/// Builds a [System] step by step:
+///
+/// ```no_run
+/// # use amber::SystemBuilder;
+/// let sys = SystemBuilder::new()
+///     .mcu("pinger", "pinger.hex")
+///     .mcu("responder", "responder.hex")
+///     .wire("pinger:TXD0", "responder:RXD0")
+///     .wire("responder:TXD0", "pinger:RXD0")
+///     .build();
+/// ```
+///
+/// Problems are collected along the way and all reported by [build](Self::build).
+pub struct SystemBuilder {
+    system_tables: SystemTables,
+    modules: Vec<Box<dyn ActiveModule>>,
+    id_map: HashMap<String, ModuleAddress>,
+    freq: i64,
+    vcd: Option<(PathBuf, bool)>,
+    traced: Vec<String>,
+    wires: Vec<(String, String)>,
+    nets: Vec<(String, Vec<String>)>,
+    errors: Vec<ConfigError>,
+}
+
+impl Default for SystemBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemBuilder {
+    pub fn new() -> Self {
+        SystemBuilder {
+            system_tables: SystemTables::new(),
+            modules: Vec::new(),
+            id_map: HashMap::new(),
+            freq: FREQ,
+            vcd: None,
+            traced: Vec::new(),
+            wires: Vec::new(),
+            nets: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, path: &str, message: impl Into<String>) {
+        self.errors.push(ConfigError {
+            path: path.to_string(),
+            message: message.into(),
+        });
+    }
+
+    /// Writes the signals of the [traced](Self::trace) components to
+    /// `out.vcd`, or `out.vcd.gz` if `compressed`, in `dir`.
+    pub fn vcd(mut self, dir: impl AsRef<Path>, compressed: bool) -> Self {
+        self.vcd = Some((dir.as_ref().to_path_buf(), compressed));
+        self
+    }
+
+    /// Sets the clock frequency of the MCUs in Hz, [FREQ] by default. It
+    /// applies to the whole system, which has a single clock.
+    pub fn frequency(mut self, hz: i64) -> Self {
+        if hz <= 0 {
+            self.error("frequency", "the frequency must be positive");
+        } else {
+            self.freq = hz;
+        }
+        self
+    }
+
+    /// Adds an MCU running the firmware in an Intel HEX file.
+    pub fn mcu(self, name: &str, firmware: impl AsRef<Path>) -> Self {
+        let firmware = firmware.as_ref();
+        self.add_mcu(name, |mcu| {
+            mcu.load_flash_hex(firmware)
+                .map_err(|err| format!("couldn't load `{}`: {}", firmware.display(), err))
+        })
+    }
+
+    /// Adds an MCU running a program given as flash words.
+    pub fn mcu_with_flash(self, name: &str, flash: &[u16]) -> Self {
+        self.add_mcu(name, |mcu| {
+            mcu.load_flash(flash);
+            Ok(())
+        })
+    }
+
+    fn add_mcu(mut self, name: &str, load: impl FnOnce(&mut Mcu) -> Result<(), String>) -> Self {
+        if self.id_map.contains_key(name) {
+            self.error(name, "defined more than once");
+            return self;
+        }
+        let Ok(index) = ModuleIndex::try_from(self.modules.len()) else {
+            self.error(name, "too many components");
+            return self;
+        };
+        let mut mcu = new_mcu(self.system_tables.clone(), index);
+        if let Err(err) = load(&mut mcu) {
+            self.error(name, err);
+        }
+        self.modules.push(Box::new(mcu));
+        self.id_map
+            .insert(name.to_string(), ModuleAddress::root().child_id(index));
+        self
+    }
+
+    /// Adds a passive component to the MCU `parent`. It is named
+    /// `parent.name`.
+    pub fn component<M, F>(mut self, parent: &str, name: &str, f: F) -> Self
+    where
+        M: WireableModule + 'static,
+        F: FnOnce(ModuleAddress) -> M,
+    {
+        let full_name = format!("{}.{}", parent, name);
+        if self.id_map.contains_key(&full_name) {
+            self.error(&full_name, "defined more than once");
+            return self;
+        }
+        let parent_addr = match self.id_map.get(parent) {
+            Some(addr) if addr.depth == 1 => *addr,
+            _ => {
+                self.error(&full_name, format!("unknown MCU `{}`", parent));
+                return self;
+            }
+        };
+        let module = self.modules[parent_addr.current() as usize]
+            .module_store()
+            .add_module(f);
+        match module {
+            Ok(module) => {
+                let address = module.address();
+                self.id_map.insert(full_name, address);
+            }
+            Err(err) => self.error(&full_name, err),
+        }
+        self
+    }
+
+    /// Adds a LED, with its input on pin 0.
+    pub fn led(self, parent: &str, name: &str) -> Self {
+        self.component(parent, name, Led::new)
+    }
+
+    /// Adds a UART console, with pins RX, TX and XCK.
+    pub fn uart(self, parent: &str, name: &str, config: UartConfig) -> Self {
+        self.component(parent, name, |id| UartModule::new(id, config))
+    }
+
+    /// Traces the signals of a component in the VCD output, if it is enabled
+    /// with [vcd](Self::vcd).
+    pub fn trace(mut self, name: &str) -> Self {
+        self.traced.push(name.to_string());
+        self
+    }
+
+    /// Connects two pins, or two buses of pins like `mcu:PA[0..7]`. A single
+    /// pin is connected to every pin of the other side.
+    pub fn wire(mut self, from: &str, to: &str) -> Self {
+        self.wires.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    /// Connects pins or buses of the same width to a named net.
+    pub fn net(mut self, name: &str, pins: &[&str]) -> Self {
+        self.nets.push((
+            name.to_string(),
+            pins.iter().map(|pin| pin.to_string()).collect(),
+        ));
+        self
+    }
+
+    fn find_bus(&self, name: &str) -> Result<Vec<PinAddress>, String> {
+        expand_bus(name)?
+            .iter()
+            .map(|pin| find_pin_addr(pin, &self.id_map, &self.modules))
+            .collect()
+    }
+
+    /// Wires the components together and returns the system, or every
+    /// problem found while building it.
+    pub fn build(mut self) -> Result<System, ConfigErrors> {
+        let mut vcd = match &self.vcd {
+            Some((dir, compressed)) => VcdReceiver::new(dir, self.freq, *compressed),
+            None => VcdReceiver::new_dummy(),
+        };
+        for name in std::mem::take(&mut self.traced) {
+            let Some(&addr) = self.id_map.get(&name) else {
+                self.error(&name, "unknown component");
+                continue;
+            };
+            if self.vcd.is_none() {
+                continue;
+            }
+            let root = self.modules[addr.current() as usize].as_mut();
+            if addr.depth == 1 {
+                vcd.register(root, &name);
+            } else {
+                let mut child = addr;
+                child.advance();
+                vcd.register(root.find_mut(child).unwrap(), &name);
+            }
+        }
+
+        let wiring = self.system_tables.wiring.clone();
+        let mut wiring = wiring.write().unwrap();
+        for (i, (from, to)) in std::mem::take(&mut self.wires).into_iter().enumerate() {
+            let path = format!("{} -> {}", from, to);
+            let result = self.find_bus(&from).and_then(|from| {
+                let to = self.find_bus(&to)?;
+                connect_buses(&mut wiring, &format!("wires[{}]", i), &from, &to)
+            });
+            if let Err(err) = result {
+                self.error(&path, err);
+            }
+        }
+        for (name, pins) in std::mem::take(&mut self.nets) {
+            let result = pins
+                .iter()
+                .map(|pin| self.find_bus(pin))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|buses| connect_net(&mut wiring, &name, &buses));
+            if let Err(err) = result {
+                self.error(&name, err);
+            }
+        }
+        drop(wiring);
+
+        if !self.errors.is_empty() {
+            return Err(ConfigErrors(self.errors));
+        }
+        Ok(System::new(
+            self.system_tables,
+            self.modules,
+            self.id_map,
+            vcd,
+            PathBuf::new(),
+            self.freq,
+        ))
+    }
+}
+
+/// Creates an MCU at the root of the system, with index `index`.
+pub(crate) fn new_mcu(system_tables: SystemTables, index: ModuleIndex) -> Mcu {
+    let recv = system_tables.inbox.write().unwrap().add_listener(index);
+    let event_queue = EventQueue::new(system_tables, 1, index, recv);
+    Mcu::new(event_queue)
+}
+
+/// Connects two buses bit by bit, or a single pin to every pin of a bus. The
+/// nets are named `name` or `name[bit]`.
+pub(crate) fn connect_buses(
+    wiring: &mut WiringTable,
+    name: &str,
+    from: &[PinAddress],
+    to: &[PinAddress],
+) -> Result<(), String> {
+    if from.len() == 1 || to.len() == 1 {
+        wiring.connect(name, &[from, to].concat());
+    } else if from.len() != to.len() {
+        return Err(format!(
+            "bus widths differ ({} and {})",
+            from.len(),
+            to.len()
+        ));
+    } else {
+        for (bit, (&from, &to)) in from.iter().zip(to).enumerate() {
+            wiring.connect(&format!("{}[{}]", name, bit), &[from, to]);
+        }
+    }
+    Ok(())
+}
+
+/// Connects buses of the same width to the net `name`, or to the nets
+/// `name[bit]` if they are wider than a pin.
+pub(crate) fn connect_net(
+    wiring: &mut WiringTable,
+    name: &str,
+    buses: &[Vec<PinAddress>],
+) -> Result<(), String> {
+    let width = buses.first().map_or(0, Vec::len);
+    if buses.iter().any(|bus| bus.len() != width) {
+        return Err("all buses on a net must have the same width".to_string());
+    }
+    for bit in 0..width {
+        let pins: Vec<_> = buses.iter().map(|bus| bus[bit]).collect();
+        if width == 1 {
+            wiring.connect(name, &pins);
+        } else {
+            wiring.connect(&format!("{}[{}]", name, bit), &pins);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pin_state::WireState, test_util::PB7_HIGH};
+
+    #[test]
+    fn build_and_run() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .led("mcu", "led")
+            .wire("mcu:PB7", "mcu.led:0")
+            .build()
+            .unwrap();
+        sys.run_for(100);
+        assert_eq!(sys.read_pin("mcu.led:0"), Ok(WireState::High));
+        assert_eq!(sys.read_memory("mcu", 0x25), Ok(0x80));
+        assert_eq!(sys.peek_memory("mcu", 0x24), Ok(0x80));
+        let unmapped = "no emulated register or SRAM at 0x35".to_string();
+        assert_eq!(sys.peek_memory("mcu", 0x35), Err(unmapped.clone()));
+        assert_eq!(sys.read_memory("mcu", 0x35), Err(unmapped.clone()));
+        assert_eq!(sys.write_memory("mcu", 0x35, 1), Err(unmapped));
+    }
+
+    #[test]
+    fn frequency() {
+        let sys = SystemBuilder::new()
+            .frequency(8_000_000)
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .build()
+            .unwrap();
+        assert_eq!(sys.freq, 8_000_000);
+
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .build()
+            .unwrap();
+        assert_eq!(sys.freq, FREQ);
+
+        let errors = SystemBuilder::new().frequency(0).build().err().unwrap();
+        assert_eq!(
+            errors.0[0].to_string(),
+            "frequency: the frequency must be positive"
+        );
+    }
+
+    #[test]
+    fn build_errors() {
+        let errors = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .led("other", "led")
+            .wire("mcu:PB7", "mcu.led:0")
+            .wire("mcu:999", "mcu:PB0")
+            .build()
+            .err()
+            .unwrap();
+        let messages: Vec<_> = errors.0.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "mcu: defined more than once",
+                "other.led: unknown MCU `other`",
+                "mcu:PB7 -> mcu.led:0: unknown component `mcu.led`",
+                "mcu:999 -> mcu:PB0: pin 999 out of range, `mcu` has 86 pins",
+            ]
+        );
+    }
+}
@@ -1,8 +1,14 @@
+use serde::{Deserialize, Serialize};
+
 pub type Timestamp = i64;
 pub type TickTimestamp = i64;
 pub type TimeDiff = i64;
 
-#[derive(Debug, Clone, Copy)]
+/// Frequency of the simulated clock, in Hz, when the config doesn't give
+/// one. See [System::freq](crate::system::System::freq).
+pub const FREQ: i64 = 16_000_000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Clock {
     current_time: Timestamp,
     current_tick: TickTimestamp,
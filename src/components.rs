@@ -1,3 +1,4 @@
 pub mod avr;
 pub mod led;
+pub mod lua_component;
 pub mod uart_module;
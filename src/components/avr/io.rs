@@ -1,6 +1,7 @@
 use std::{any::Any, mem::transmute};
 
 use kanal::Sender;
+use serde::{Deserialize, Serialize};
 use uart::Uart;
 
 use crate::{
@@ -8,8 +9,9 @@ use crate::{
     events::{EventQueue, InternalEvent},
     module::{DataModule, Module, PinId, PortId, WireableModule},
     module_holder::PassiveModuleStore,
-    module_id::ModuleAddress,
+    module_id::{ModuleAddress, ModuleIndex},
     pin_state::WireState,
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
@@ -21,7 +23,7 @@ pub mod uart;
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SleepMode {
     Idle = 0,
     ADCNoiseReduction = 1,
@@ -52,38 +54,179 @@ pub struct IoController {
 
     pub sleep_mode: SleepMode,
     pub sleep_enabled: bool,
+    /// MCUSR, the flags telling what caused the last resets.
+    reset_flags: u8,
 }
 
-const BANK_A: u8 = 1;
-const BANK_B: u8 = 2;
-const BANK_C: u8 = 3;
-const BANK_D: u8 = 4;
-const BANK_E: u8 = 5;
-const BANK_F: u8 = 6;
-const BANK_G: u8 = 7;
-const BANK_H: u8 = 8;
-const BANK_J: u8 = 9;
-const BANK_K: u8 = 10;
-const BANK_L: u8 = 11;
-
-const TIMER_1: u8 = 12;
-const TIMER_3: u8 = 13;
-const TIMER_4: u8 = 14;
-const TIMER_5: u8 = 15;
-
-const UART_0: u8 = 16;
-const UART_1: u8 = 17;
-const UART_2: u8 = 18;
-const UART_3: u8 = 19;
-
-const fn pin_id(bank: u8, pin: u8) -> u8 {
-    if bank <= BANK_G {
+/// Flags of MCUSR.
+pub const PORF: u8 = 1 << 0;
+pub const EXTRF: u8 = 1 << 1;
+pub const BORF: u8 = 1 << 2;
+
+const BANK_A: ModuleIndex = 1;
+const BANK_B: ModuleIndex = 2;
+const BANK_C: ModuleIndex = 3;
+const BANK_D: ModuleIndex = 4;
+const BANK_E: ModuleIndex = 5;
+const BANK_F: ModuleIndex = 6;
+const BANK_G: ModuleIndex = 7;
+const BANK_H: ModuleIndex = 8;
+const BANK_J: ModuleIndex = 9;
+const BANK_K: ModuleIndex = 10;
+const BANK_L: ModuleIndex = 11;
+
+const TIMER_1: ModuleIndex = 12;
+const TIMER_3: ModuleIndex = 13;
+const TIMER_4: ModuleIndex = 14;
+const TIMER_5: ModuleIndex = 15;
+
+const UART_0: ModuleIndex = 16;
+const UART_1: ModuleIndex = 17;
+const UART_2: ModuleIndex = 18;
+const UART_3: ModuleIndex = 19;
+
+const fn pin_id(bank: ModuleIndex, pin: u8) -> u8 {
+    let bank = bank as u8;
+    if bank <= BANK_G as u8 {
         (bank - 1) * 8 + pin
     } else {
         (bank - 1) * 8 - 2 + pin
     }
 }
 
+/// Letters of the GPIO ports, in bank order.
+const PORT_LETTERS: &[u8; 11] = b"ABCDEFGHJKL";
+
+/// Port pins of the Arduino Mega digital pins D0..D69, where D54..D69 are
+/// the analog pins A0..A15.
+const ARDUINO_MEGA_PINS: [(ModuleIndex, u8); 70] = [
+    (BANK_E, 0),
+    (BANK_E, 1),
+    (BANK_E, 4),
+    (BANK_E, 5),
+    (BANK_G, 5),
+    (BANK_E, 3),
+    (BANK_H, 3),
+    (BANK_H, 4),
+    (BANK_H, 5),
+    (BANK_H, 6),
+    (BANK_B, 4),
+    (BANK_B, 5),
+    (BANK_B, 6),
+    (BANK_B, 7),
+    (BANK_J, 1),
+    (BANK_J, 0),
+    (BANK_H, 1),
+    (BANK_H, 0),
+    (BANK_D, 3),
+    (BANK_D, 2),
+    (BANK_D, 1),
+    (BANK_D, 0),
+    (BANK_A, 0),
+    (BANK_A, 1),
+    (BANK_A, 2),
+    (BANK_A, 3),
+    (BANK_A, 4),
+    (BANK_A, 5),
+    (BANK_A, 6),
+    (BANK_A, 7),
+    (BANK_C, 7),
+    (BANK_C, 6),
+    (BANK_C, 5),
+    (BANK_C, 4),
+    (BANK_C, 3),
+    (BANK_C, 2),
+    (BANK_C, 1),
+    (BANK_C, 0),
+    (BANK_D, 7),
+    (BANK_G, 2),
+    (BANK_G, 1),
+    (BANK_G, 0),
+    (BANK_L, 7),
+    (BANK_L, 6),
+    (BANK_L, 5),
+    (BANK_L, 4),
+    (BANK_L, 3),
+    (BANK_L, 2),
+    (BANK_L, 1),
+    (BANK_L, 0),
+    (BANK_B, 3),
+    (BANK_B, 2),
+    (BANK_B, 1),
+    (BANK_B, 0),
+    (BANK_F, 0),
+    (BANK_F, 1),
+    (BANK_F, 2),
+    (BANK_F, 3),
+    (BANK_F, 4),
+    (BANK_F, 5),
+    (BANK_F, 6),
+    (BANK_F, 7),
+    (BANK_K, 0),
+    (BANK_K, 1),
+    (BANK_K, 2),
+    (BANK_K, 3),
+    (BANK_K, 4),
+    (BANK_K, 5),
+    (BANK_K, 6),
+    (BANK_K, 7),
+];
+
+/// Port pins of the peripheral functions available on the pins.
+const PERIPHERAL_PINS: [(&str, ModuleIndex, u8); 24] = [
+    ("OC1A", BANK_B, 5),
+    ("OC1B", BANK_B, 6),
+    ("OC1C", BANK_B, 7),
+    ("OC3A", BANK_E, 3),
+    ("OC3B", BANK_E, 4),
+    ("OC3C", BANK_E, 5),
+    ("OC4A", BANK_H, 3),
+    ("OC4B", BANK_H, 4),
+    ("OC4C", BANK_H, 5),
+    ("OC5A", BANK_L, 3),
+    ("OC5B", BANK_L, 4),
+    ("OC5C", BANK_L, 5),
+    ("RXD0", BANK_E, 0),
+    ("TXD0", BANK_E, 1),
+    ("XCK0", BANK_E, 2),
+    ("RXD1", BANK_D, 2),
+    ("TXD1", BANK_D, 3),
+    ("XCK1", BANK_D, 5),
+    ("RXD2", BANK_H, 0),
+    ("TXD2", BANK_H, 1),
+    ("XCK2", BANK_H, 2),
+    ("RXD3", BANK_J, 0),
+    ("TXD3", BANK_J, 1),
+    ("XCK3", BANK_J, 2),
+];
+
+/// Parses a port pin name like `PB5`.
+fn port_pin(name: &str) -> Option<u8> {
+    let &[b'P', letter, bit] = name.as_bytes() else {
+        return None;
+    };
+    let bank = PORT_LETTERS.iter().position(|&l| l == letter)? as ModuleIndex + 1;
+    let bit = (bit as char).to_digit(10)? as u8;
+    let width = if bank == BANK_G { 6 } else { 8 };
+    (bit < width).then(|| pin_id(bank, bit))
+}
+
+/// Parses an Arduino Mega pin name, either digital (`D13`) or analog (`A0`).
+fn arduino_pin(name: &str) -> Option<u8> {
+    let number = |s: &str| {
+        s.parse::<usize>()
+            .ok()
+            .filter(|_| s.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let index = if let Some(n) = name.strip_prefix('D') {
+        number(n)?
+    } else {
+        54 + number(name.strip_prefix('A')?).filter(|&n| n < 16)?
+    };
+    let &(bank, bit) = ARDUINO_MEGA_PINS.get(index)?;
+    Some(pin_id(bank, bit))
+}
+
 impl IoController {
     pub fn new(module_id: ModuleAddress, queue: &mut EventQueue) -> Self {
         for i in 0..3 {
@@ -163,6 +306,17 @@ impl IoController {
             ],
         );
 
+        // Pins without alternate functions are always driven by their port.
+        for bank in BANK_A..=BANK_L {
+            let width = if bank == BANK_G { 6 } else { 8 };
+            for bit in 0..width {
+                let pin = module_id.with_pin(pin_id(bank, bit));
+                if !queue.is_multiplexed(pin) {
+                    queue.register_multiplexer(pin, &[module_id.child_id(bank).with_pin(bit)]);
+                }
+            }
+        }
+
         Self {
             module_id,
             module_store: PassiveModuleStore::new(module_id.child_id(0)),
@@ -194,8 +348,41 @@ impl IoController {
 
             sleep_mode: SleepMode::Idle,
             sleep_enabled: false,
+            reset_flags: PORF,
         }
     }
+
+    /// Puts the peripherals back in their initial state, as a reset does,
+    /// and sets `flag` in MCUSR. The pins the peripherals drive are released
+    /// and become inputs, the values the pins read are kept.
+    pub fn reset(&mut self, queue: &mut EventQueue, flag: u8) {
+        let module_id = self.module_id;
+        for peripheral in [
+            TIMER_1, TIMER_3, TIMER_4, TIMER_5, UART_0, UART_1, UART_2, UART_3,
+        ] {
+            for pin in 0..3 {
+                queue.set_multiplexer_flag(module_id.child_id(peripheral).with_pin(pin), false);
+            }
+        }
+        for bank in &mut self.gpio {
+            bank.reset(queue);
+        }
+        self.interrupt = false;
+
+        self.timer1 = Timer16::new(module_id.child_id(TIMER_1), module_id.with_event_port(0));
+        self.timer3 = Timer16::new(module_id.child_id(TIMER_3), module_id.with_event_port(0));
+        self.timer4 = Timer16::new(module_id.child_id(TIMER_4), module_id.with_event_port(0));
+        self.timer5 = Timer16::new(module_id.child_id(TIMER_5), module_id.with_event_port(0));
+
+        self.uart0 = Uart::new(module_id.child_id(UART_0), module_id.with_event_port(0));
+        self.uart1 = Uart::new(module_id.child_id(UART_1), module_id.with_event_port(0));
+        self.uart2 = Uart::new(module_id.child_id(UART_2), module_id.with_event_port(0));
+        self.uart3 = Uart::new(module_id.child_id(UART_3), module_id.with_event_port(0));
+
+        self.sleep_mode = SleepMode::Idle;
+        self.sleep_enabled = false;
+        self.reset_flags |= flag;
+    }
 }
 
 impl VcdSender for IoController {
@@ -281,6 +468,65 @@ impl Module for IoController {
     fn to_wireable(&self) -> Option<&dyn WireableModule> {
         Some(self)
     }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        self.module_store.save_state(state);
+        for bank in &mut self.gpio {
+            bank.save_state(state);
+        }
+        for timer in [
+            &mut self.timer1,
+            &mut self.timer3,
+            &mut self.timer4,
+            &mut self.timer5,
+        ] {
+            timer.save_state(state);
+        }
+        for uart in [
+            &mut self.uart0,
+            &mut self.uart1,
+            &mut self.uart2,
+            &mut self.uart3,
+        ] {
+            uart.save_state(state);
+        }
+        state.write(&(
+            self.interrupt,
+            self.sleep_mode,
+            self.sleep_enabled,
+            self.reset_flags,
+        ));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        self.module_store.load_state(state)?;
+        for bank in &mut self.gpio {
+            bank.load_state(state)?;
+        }
+        for timer in [
+            &mut self.timer1,
+            &mut self.timer3,
+            &mut self.timer4,
+            &mut self.timer5,
+        ] {
+            timer.load_state(state)?;
+        }
+        for uart in [
+            &mut self.uart0,
+            &mut self.uart1,
+            &mut self.uart2,
+            &mut self.uart3,
+        ] {
+            uart.load_state(state)?;
+        }
+        (
+            self.interrupt,
+            self.sleep_mode,
+            self.sleep_enabled,
+            self.reset_flags,
+        ) = state.read()?;
+        Ok(())
+    }
 }
 
 impl WireableModule for IoController {
@@ -317,6 +563,24 @@ impl WireableModule for IoController {
             _ => panic!("Invalid port id: {}", id),
         }
     }
+
+    /// Ports A to L, port G having only 6 pins.
+    fn pin_count(&self) -> usize {
+        86
+    }
+
+    /// Accepts port pins (`PB5`), peripheral functions (`TXD0`, `OC1A`) and
+    /// Arduino Mega pin numbers (`D13`, `A0`).
+    fn pin_by_name(&self, name: &str) -> Option<PinId> {
+        let name = name.to_ascii_uppercase();
+        let pin = port_pin(&name).or_else(|| arduino_pin(&name)).or_else(|| {
+            PERIPHERAL_PINS
+                .iter()
+                .find(|(function, _, _)| *function == name)
+                .map(|&(_, bank, bit)| pin_id(bank, bit))
+        })?;
+        Some(pin as PinId)
+    }
 }
 
 impl DataModule for IoController {
@@ -346,6 +610,7 @@ impl DataModule for IoController {
                 let se = self.sleep_enabled as u8;
                 sm << 1 | se
             }
+            0x54 => self.reset_flags, // MCUSR
 
             0x6E => todo!(),
             0x6F => self.timer1.read_port(queue, Timer16::TIMSK_PORT),
@@ -414,6 +679,8 @@ impl DataModule for IoController {
                     self.sleep_mode = transmute((data >> 1) & 0x7);
                 }
             }
+            // MCUSR, whose flags are cleared by writing zeros.
+            0x54 => self.reset_flags &= data,
 
             0x6E => todo!(),
             0x6F => self.timer1.write_port(queue, Timer16::TIMSK_PORT, data),
@@ -458,6 +725,58 @@ impl DataModule for IoController {
 }
 
 impl IoController {
+    /// Value of the register at a data space address, read without side
+    /// effects: reading UDRn doesn't consume the received data. Returns `None`
+    /// for addresses without an emulated register.
+    pub fn peek_port(&self, queue: &EventQueue, id: PortId) -> Option<u8> {
+        let value = match id {
+            0x20..=0x22 => self.gpio[0].peek_port(id - 0x20), // Port A
+            0x23..=0x25 => self.gpio[1].peek_port(id - 0x23), // Port B
+            0x26..=0x28 => self.gpio[2].peek_port(id - 0x26), // Port C
+            0x29..=0x2B => self.gpio[3].peek_port(id - 0x29), // Port D
+            0x2C..=0x2E => self.gpio[4].peek_port(id - 0x2C), // Port E
+            0x2F..=0x31 => self.gpio[5].peek_port(id - 0x2F), // Port F
+            0x32..=0x34 => self.gpio[6].peek_port(id - 0x32), // Port G
+
+            0x36 => self.timer1.peek_port(queue, Timer16::TIFR_PORT)?,
+            0x38 => self.timer3.peek_port(queue, Timer16::TIFR_PORT)?,
+            0x39 => self.timer4.peek_port(queue, Timer16::TIFR_PORT)?,
+            0x3A => self.timer5.peek_port(queue, Timer16::TIFR_PORT)?,
+
+            0x53 => {
+                // SMCR
+                let sm = self.sleep_mode as u8;
+                let se = self.sleep_enabled as u8;
+                sm << 1 | se
+            }
+            0x54 => self.reset_flags, // MCUSR
+
+            0x6F => self.timer1.peek_port(queue, Timer16::TIMSK_PORT)?,
+            0x71 => self.timer3.peek_port(queue, Timer16::TIMSK_PORT)?,
+            0x72 => self.timer4.peek_port(queue, Timer16::TIMSK_PORT)?,
+            0x73 => self.timer5.peek_port(queue, Timer16::TIMSK_PORT)?,
+
+            0x80..=0x8F => self.timer1.peek_port(queue, id - 0x80)?, // Timer 1
+            0x90..=0x9F => self.timer3.peek_port(queue, id - 0x90)?, // Timer 3
+            0xA0..=0xAF => self.timer4.peek_port(queue, id - 0xA0)?, // Timer 4
+
+            0xC0..=0xC7 => self.uart0.peek_port(id - 0xC0)?, // UART 0
+            0xC8..=0xCF => self.uart1.peek_port(id - 0xC8)?, // UART 1
+            0xD0..=0xD7 => self.uart2.peek_port(id - 0xD0)?, // UART 2
+
+            0x100..=0x102 => self.gpio[7].peek_port(id - 0x100), // Port H
+            0x103..=0x105 => self.gpio[8].peek_port(id - 0x103), // Port J
+            0x106..=0x108 => self.gpio[9].peek_port(id - 0x106), // Port K
+            0x109..=0x10B => self.gpio[10].peek_port(id - 0x109), // Port L
+
+            0x120..=0x12F => self.timer5.peek_port(queue, id - 0x120)?, // Timer 5
+            0x130..=0x137 => self.uart3.peek_port(id - 0x130)?,         // UART 3
+
+            _ => return None,
+        };
+        Some(value)
+    }
+
     #[inline]
     pub fn read_port_internal(&mut self, queue: &mut EventQueue, id: PortId) -> u8 {
         assert!(id < 0x40);
@@ -2,6 +2,7 @@ use std::any::Any;
 
 use bitfield::{Bit, BitMut};
 use kanal::Sender;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     clock::Timestamp,
@@ -9,10 +10,11 @@ use crate::{
     module::{DataModule, Module, PinId, PortId, WireableModule},
     module_id::ModuleAddress,
     pin_state::{InputPinState, WireState},
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GpioBank {
     module_id: ModuleAddress,
     port_register: u8,
@@ -22,6 +24,7 @@ pub struct GpioBank {
     input_states: [InputPinState; 8],
     readable_states: [InputPinState; 8],
 
+    #[serde(skip)]
     vcd_sender: Option<Sender<VcdEvent>>,
 }
 impl GpioBank {
@@ -37,6 +40,18 @@ impl GpioBank {
         }
     }
 
+    /// Clears the registers, which makes every pin an input without pull-up.
+    /// The pins are driven again even if they already were, since other
+    /// peripherals may have been driving them instead.
+    pub fn reset(&mut self, queue: &mut EventQueue) {
+        self.port_register = 0;
+        self.ddr_register = 0;
+        for i in 0..8 {
+            self.output_states[i] = WireState::Z;
+            queue.set_wire(self.module_id.with_pin(i as u8), WireState::Z);
+        }
+    }
+
     fn read_pin(&self) -> u8 {
         let mut x = 0;
         for i in 0..8 {
@@ -138,6 +153,17 @@ impl Module for GpioBank {
     fn to_wireable(&self) -> Option<&dyn WireableModule> {
         Some(self)
     }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(self);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        let vcd_sender = self.vcd_sender.take();
+        *self = state.read()?;
+        self.vcd_sender = vcd_sender;
+        Ok(())
+    }
 }
 
 impl WireableModule for GpioBank {
@@ -151,11 +177,15 @@ impl WireableModule for GpioBank {
             receiver_id: self.module_id.with_event_port(0),
         });
     }
+
+    fn pin_count(&self) -> usize {
+        self.output_states.len()
+    }
 }
 
-impl DataModule for GpioBank {
-    type PortType = u8;
-    fn read_port(&mut self, _queue: &mut EventQueue, id: PortId) -> u8 {
+impl GpioBank {
+    /// Value of a register. Reading them has no side effects.
+    pub fn peek_port(&self, id: PortId) -> u8 {
         match id {
             0 => self.read_pin(),
             1 => self.ddr_register,
@@ -163,6 +193,13 @@ impl DataModule for GpioBank {
             _ => 0,
         }
     }
+}
+
+impl DataModule for GpioBank {
+    type PortType = u8;
+    fn read_port(&mut self, _queue: &mut EventQueue, id: PortId) -> u8 {
+        self.peek_port(id)
+    }
 
     fn write_port(&mut self, queue: &mut EventQueue, id: PortId, data: Self::PortType) {
         match id {
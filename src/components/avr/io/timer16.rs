@@ -1,6 +1,7 @@
 use std::{any::Any, mem::transmute};
 
 use kanal::Sender;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     clock::{TickTimestamp, Timestamp},
@@ -8,12 +9,13 @@ use crate::{
     module::{DataModule, Module, PinId, PortId, WireableModule},
     module_id::{EventPortAddress, ModuleAddress},
     pin_state::WireState,
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum CompareOutputMode {
     Disabled = 0,
     Toggle = 1,
@@ -23,7 +25,7 @@ enum CompareOutputMode {
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum ClockMode {
     Disabled = 0,
     Clk1 = 1,
@@ -37,7 +39,7 @@ enum ClockMode {
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 enum WaveformGenerationMode {
     Normal = 0,
     Pwm8Bit = 1,
@@ -60,14 +62,14 @@ enum WaveformGenerationMode {
     FastPwmOcrA = 15,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Timer16Interrupts {
     pub overflow: bool,
     pub oc: [bool; 3],
     pub input_capture: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Timer16 {
     last_write_t: TickTimestamp,
     last_write_counter: u16,
@@ -89,6 +91,7 @@ pub struct Timer16 {
     icnc: bool, // TODO
     ices: bool, // TODO
 
+    #[serde(skip)]
     vcd_sender: Option<Sender<VcdEvent>>,
 }
 
@@ -477,13 +480,24 @@ impl Module for Timer16 {
     fn to_wireable(&self) -> Option<&dyn WireableModule> {
         Some(self)
     }
-}
 
-impl DataModule for Timer16 {
-    type PortType = u8;
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(self);
+    }
 
-    fn read_port(&mut self, queue: &mut EventQueue, id: PortId) -> Self::PortType {
-        match id {
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        let vcd_sender = self.vcd_sender.take();
+        *self = state.read()?;
+        self.vcd_sender = vcd_sender;
+        Ok(())
+    }
+}
+
+impl Timer16 {
+    /// Value of a register, read without side effects. Returns `None` for the
+    /// registers that aren't emulated.
+    pub fn peek_port(&self, queue: &EventQueue, id: PortId) -> Option<u8> {
+        let value = match id {
             0 => {
                 // TCCRnA
                 let wgm = (self.waveform_mode as u8) & 0x3;
@@ -512,7 +526,6 @@ impl DataModule for Timer16 {
                 let cnt = self.calculate_counter(queue.clock.current_tick());
                 (cnt >> 8) as u8
             }
-            6 | 7 => todo!(),                 // ICRnL/ICRnH
             8 => (self.ocr[0] & 0xFF) as u8,  // OCRnAL
             9 => (self.ocr[0] >> 8) as u8,    // OCRnAH
             10 => (self.ocr[1] & 0xFF) as u8, // OCRnBL
@@ -541,7 +554,21 @@ impl DataModule for Timer16 {
 
                 icf << 5 | ocfa << 1 | ocfb << 2 | ocfc << 3 | tof
             }
-            _ => panic!("Invalid port {}", id),
+            _ => return None, // ICRnL/ICRnH aren't emulated
+        };
+        Some(value)
+    }
+}
+
+impl DataModule for Timer16 {
+    type PortType = u8;
+
+    fn read_port(&mut self, queue: &mut EventQueue, id: PortId) -> Self::PortType {
+        match id {
+            6 | 7 => todo!(), // ICRnL/ICRnH
+            _ => self
+                .peek_port(queue, id)
+                .unwrap_or_else(|| panic!("Invalid port {}", id)),
         }
     }
 
@@ -636,4 +663,8 @@ impl WireableModule for Timer16 {
     }
 
     fn set_pin(&mut self, _queue: &mut EventQueue, _id: PinId, _data: WireState) {}
+
+    fn pin_count(&self) -> usize {
+        self.pins.len()
+    }
 }
@@ -1,6 +1,7 @@
 use std::{any::Any, mem::transmute};
 
 use kanal::Sender;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     clock::{TickTimestamp, Timestamp},
@@ -8,12 +9,13 @@ use crate::{
     module::{DataModule, Module, PinId, PortId, WireableModule},
     module_id::{EventPortAddress, ModuleAddress},
     pin_state::{InputPinState, WireState},
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum ParityMode {
     Disabled = 0,
     Reserved = 1,
@@ -23,7 +25,7 @@ enum ParityMode {
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum CharacterSizeMode {
     Char5 = 0,
     Char6 = 1,
@@ -37,7 +39,7 @@ enum CharacterSizeMode {
 
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum UartMode {
     Async = 0,
     Sync = 1,
@@ -45,7 +47,7 @@ enum UartMode {
     MasterSpi = 3,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum FrameState {
     Idle,
     Start,
@@ -54,7 +56,7 @@ enum FrameState {
     End(u8),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Uart {
     last_write_t: TickTimestamp,
     last_write_counter: u16,
@@ -98,6 +100,7 @@ pub struct Uart {
     pub tx_interrupt_enable: bool,
     pub udr_interrupt_enable: bool,
 
+    #[serde(skip)]
     vcd_sender: Option<Sender<VcdEvent>>,
 }
 
@@ -325,10 +328,13 @@ impl Uart {
     }
 
     fn trigger_clock(&mut self, queue: &mut EventQueue) {
-        queue.set_wire(
-            self.module_id.with_pin(Self::XCK_PIN),
-            self.xck_val.to_wire_state(),
-        );
+        // Only the master drives the clock, slaves just listen to it.
+        if self.ddr_xck {
+            queue.set_wire(
+                self.module_id.with_pin(Self::XCK_PIN),
+                self.xck_val.to_wire_state(),
+            );
+        }
         if self.ucpol {
             // UCPOL = 1 => sample on rising, change on falling
             match self.xck_val {
@@ -446,13 +452,24 @@ impl Module for Uart {
     fn to_wireable(&self) -> Option<&dyn WireableModule> {
         Some(self)
     }
-}
 
-impl DataModule for Uart {
-    type PortType = u8;
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(self);
+    }
 
-    fn read_port(&mut self, _queue: &mut EventQueue, id: PortId) -> Self::PortType {
-        match id {
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        let vcd_sender = self.vcd_sender.take();
+        *self = state.read()?;
+        self.vcd_sender = vcd_sender;
+        Ok(())
+    }
+}
+
+impl Uart {
+    /// Value of a register, read without side effects: reading UDRn doesn't
+    /// consume the received data.
+    pub fn peek_port(&self, id: PortId) -> Option<u8> {
+        let value = match id {
             0 => {
                 // UCSRnA
                 let rxc = self.rx_interrupt() as u8;
@@ -496,6 +513,20 @@ impl DataModule for Uart {
             3 => 0,                                    // Reserved
             4 => (self.baud_rate & 0xFF) as u8,        // UBRRnL
             5 => ((self.baud_rate >> 8) & 0xFF) as u8, // UBRRnH
+            // UDRn, without popping the received data.
+            6 if self.rx_data_len > 0 => self.rx_data[0] as u8,
+            6 => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+impl DataModule for Uart {
+    type PortType = u8;
+
+    fn read_port(&mut self, _queue: &mut EventQueue, id: PortId) -> Self::PortType {
+        match id {
             6 => match self.rx_data_len {
                 // UDRn
                 0 => 0,
@@ -512,7 +543,9 @@ impl DataModule for Uart {
                 }
                 _ => unreachable!(),
             },
-            _ => panic!("Invalid port {}", id),
+            _ => self
+                .peek_port(id)
+                .unwrap_or_else(|| panic!("Invalid port {}", id)),
         }
     }
 
@@ -619,4 +652,45 @@ impl WireableModule for Uart {
             _ => panic!("Invalid pin {}", id),
         }
     }
+
+    fn pin_count(&self) -> usize {
+        Self::XCK_PIN as usize + 1
+    }
+
+    fn pin_by_name(&self, name: &str) -> Option<PinId> {
+        let pin = match name.to_ascii_uppercase().as_str() {
+            "RX" | "RXD" => Self::RX_PIN,
+            "TX" | "TXD" => Self::TX_PIN,
+            "XCK" => Self::XCK_PIN,
+            _ => return None,
+        };
+        Some(pin as PinId)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_tables::SystemTables;
+
+    #[test]
+    fn peek_udr() {
+        let (_, r) = kanal::bounded(0);
+        let mut queue = EventQueue::new(SystemTables::new(), 1, 0, r);
+        let root = ModuleAddress::root();
+        let mut uart = Uart::new(root, root.with_event_port(0));
+        uart.rx_data = [b'a' as u16, b'b' as u16];
+        uart.rx_data_len = 2;
+
+        // Peeking leaves the received data in place.
+        assert_eq!(uart.peek_port(6), Some(b'a'));
+        assert_eq!(uart.peek_port(6), Some(b'a'));
+        assert_eq!(uart.peek_port(0).map(|ucsra| ucsra >> 7), Some(1));
+        assert_eq!(uart.read_port(&mut queue, 6), b'a');
+        assert_eq!(uart.peek_port(6), Some(b'b'));
+        assert_eq!(uart.read_port(&mut queue, 6), b'b');
+        assert_eq!(uart.peek_port(6), Some(0));
+        assert_eq!(uart.peek_port(0).map(|ucsra| ucsra >> 7), Some(0));
+        assert_eq!(uart.peek_port(7), None);
+    }
 }
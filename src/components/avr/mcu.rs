@@ -5,9 +5,10 @@ mod hex;
 mod logical;
 mod memory_controller;
 mod mul;
+mod symbols;
 mod transfer;
 
-use std::any::Any;
+use std::{any::Any, collections::HashMap};
 
 use bitfield::Bit;
 use kanal::Sender;
@@ -19,20 +20,90 @@ use crate::{
     module_holder::PassiveModuleStore,
     module_id::ModuleAddress,
     pin_state::WireState,
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     system_tables::SystemTables,
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
 use super::{
-    bit_helpers::bit_field_combined, io::IoController, regfile::RegisterFile, sreg::StatusRegister,
+    bit_helpers::bit_field_combined,
+    io::{IoController, BORF, EXTRF},
+    regfile::RegisterFile,
+    sreg::StatusRegister,
 };
 
+/// Device simulated by [Mcu].
+pub const DEVICE: &str = "atmega2560";
+
 const SRAM_SIZE: usize = 8192;
 const SRAM_END: u16 = 0x200 + SRAM_SIZE as u16 - 1;
 const FLASH_SIZE: usize = 128 * 1024;
 const RAMPZ_MASK: u8 = 0x3;
 const EIND_MASK: u8 = 0x1;
 
+/// Change of a watched data space byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueChange {
+    /// Time at which the instruction that made the change started.
+    pub t: Timestamp,
+    /// Address of the instruction that made the change.
+    pub pc: u32,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// Condition on which an MCU requests a stop of the simulation, see
+/// [Mcu::add_breakpoint].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// The instruction at this word address is about to be executed.
+    Pc(u32),
+    /// An instruction read this address of the data space.
+    Read(u16),
+    /// An instruction wrote this address of the data space.
+    Write(u16),
+    /// An instruction read or wrote this address of the data space.
+    Access(u16),
+}
+
+/// A [Breakpoint] that was hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakpointHit {
+    /// Id given to [Mcu::add_breakpoint].
+    pub id: usize,
+    /// Time at which the MCU stopped.
+    pub t: Timestamp,
+    /// Address of the instruction about to be executed for a [Breakpoint::Pc],
+    /// of the instruction that accessed the data space otherwise.
+    pub pc: u32,
+    /// Value read or written.
+    pub value: Option<u8>,
+}
+
+/// What reset an MCU, as recorded in MCUSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// A low level on the RESET pin.
+    External,
+    /// The supply voltage dropped below the brown-out level.
+    BrownOut,
+}
+
+/// Access to the data space by an instruction, while breakpoints are set.
+#[derive(Debug, Clone, Copy)]
+struct DataAccess {
+    address: u16,
+    value: u8,
+    write: bool,
+}
+
+#[derive(Debug)]
+struct ChangeWatch {
+    address: u16,
+    value: u8,
+    last_change: Option<ValueChange>,
+}
+
 #[derive(Debug)]
 pub struct Mcu {
     reg_file: RegisterFile,
@@ -49,8 +120,19 @@ pub struct Mcu {
 
     halted: bool,
     sleeping: bool,
+    /// Held in reset, see [Mcu::hold_reset].
+    in_reset: bool,
 
     queue: EventQueue,
+    change_watch: Option<ChangeWatch>,
+    breakpoints: Vec<(usize, Breakpoint)>,
+    breakpoint_hits: Vec<BreakpointHit>,
+    /// Whether the accesses to the data space are recorded in `accesses`,
+    /// because of the breakpoints.
+    watch_accesses: bool,
+    accesses: Vec<DataAccess>,
+    /// Addresses of the symbols of the firmware, by name.
+    symbols: HashMap<String, u32>,
 
     vcd_sender: Option<Sender<VcdEvent>>,
     vcd_start_id: i32,
@@ -79,13 +161,162 @@ impl Mcu {
             sreg: StatusRegister(0),
             halted: false,
             sleeping: false,
+            in_reset: false,
 
             queue,
+            change_watch: None,
+            breakpoints: Vec::new(),
+            breakpoint_hits: Vec::new(),
+            watch_accesses: false,
+            accesses: Vec::new(),
+            symbols: HashMap::new(),
 
             vcd_sender: None,
             vcd_start_id: 0,
         }
     }
+    /// Starts recording the changes of a byte in the data space. Only
+    /// registers and SRAM can be watched. Returns `false` for other addresses.
+    pub fn watch_changes(&mut self, address: u16) -> bool {
+        self.change_watch = self.peek(address).map(|value| ChangeWatch {
+            address,
+            value,
+            last_change: None,
+        });
+        self.change_watch.is_some()
+    }
+
+    /// Stops watching and returns the last change seen since [Mcu::watch_changes].
+    pub fn take_watched_change(&mut self) -> Option<ValueChange> {
+        self.change_watch.take().and_then(|w| w.last_change)
+    }
+
+    /// Requests a stop of the simulation when `breakpoint` is hit, recording
+    /// it under `id`. For a [Breakpoint::Pc], the instruction the MCU is at
+    /// when called doesn't count, it has to come back to it.
+    pub fn add_breakpoint(&mut self, id: usize, breakpoint: Breakpoint) {
+        self.watch_accesses |= !matches!(breakpoint, Breakpoint::Pc(_));
+        self.breakpoints.push((id, breakpoint));
+    }
+
+    /// Removes all the breakpoints and returns the ones hit since they were
+    /// added, oldest first.
+    pub fn clear_breakpoints(&mut self) -> Vec<BreakpointHit> {
+        self.breakpoints.clear();
+        self.watch_accesses = false;
+        self.accesses.clear();
+        std::mem::take(&mut self.breakpoint_hits)
+    }
+
+    #[inline]
+    fn note_access(&mut self, address: u16, value: u8, write: bool) {
+        if self.watch_accesses {
+            self.accesses.push(DataAccess {
+                address,
+                value,
+                write,
+            });
+        }
+    }
+
+    /// Records the breakpoints hit by the instruction that started at `pc`,
+    /// and requests a stop if there are any.
+    fn check_breakpoints(&mut self, pc: u32, running: bool) {
+        let t = self.queue.clock.current_time();
+        let hits = self.breakpoint_hits.len();
+        for &(id, breakpoint) in &self.breakpoints {
+            let (address, read, write) = match breakpoint {
+                // A sleeping MCU stays at the same address without executing it.
+                Breakpoint::Pc(address) if address == self.pc && (running || self.pc != pc) => {
+                    self.breakpoint_hits.push(BreakpointHit {
+                        id,
+                        t,
+                        pc: self.pc,
+                        value: None,
+                    });
+                    continue;
+                }
+                Breakpoint::Pc(_) => continue,
+                Breakpoint::Read(address) => (address, true, false),
+                Breakpoint::Write(address) => (address, false, true),
+                Breakpoint::Access(address) => (address, true, true),
+            };
+            let Some(access) = self.accesses.iter().find(|access| {
+                access.address == address && if access.write { write } else { read }
+            }) else {
+                continue;
+            };
+            let value = Some(access.value);
+            self.breakpoint_hits
+                .push(BreakpointHit { id, t, pc, value });
+        }
+        self.accesses.clear();
+        if self.breakpoint_hits.len() > hits {
+            self.queue.request_stop(t);
+        }
+    }
+
+    /// Holds the MCU in reset for `cause`: it stops running, and its
+    /// peripherals are reset, which releases the pins they drive. The
+    /// registers and SRAM keep their values.
+    pub fn hold_reset(&mut self, cause: ResetCause) {
+        let flag = match cause {
+            ResetCause::External => EXTRF,
+            ResetCause::BrownOut => BORF,
+        };
+        self.io.reset(&mut self.queue, flag);
+        self.pc = 0;
+        self.sp = 0;
+        self.sreg = StatusRegister(0);
+        self.rampz = 0;
+        self.eind = 0;
+        self.halted = false;
+        self.sleeping = false;
+        self.in_reset = true;
+    }
+
+    /// Lets the MCU run again from the reset vector.
+    pub fn release_reset(&mut self) {
+        self.in_reset = false;
+    }
+
+    /// Word address in flash of a function or label of the firmware, from
+    /// its [symbols](Mcu::load_symbols).
+    pub fn code_symbol(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .get(name)
+            .filter(|&&address| address < symbols::DATA_OFFSET)
+            .map(|address| address / 2)
+    }
+
+    /// Address in the data space of a variable of the firmware, from its
+    /// [symbols](Mcu::load_symbols).
+    pub fn data_symbol(&self, name: &str) -> Option<u16> {
+        self.symbols
+            .get(name)
+            .and_then(|address| address.checked_sub(symbols::DATA_OFFSET))
+            .and_then(|address| u16::try_from(address).ok())
+    }
+
+    fn check_watch(&mut self, t: Timestamp, pc: u32) {
+        if let Some(watch) = &self.change_watch {
+            let value = self.peek(watch.address).unwrap();
+            if value != watch.value {
+                let change = ValueChange {
+                    t,
+                    pc,
+                    old: watch.value,
+                    new: value,
+                };
+                self.change_watch = Some(ChangeWatch {
+                    address: watch.address,
+                    value,
+                    last_change: Some(change),
+                });
+            }
+        }
+    }
+
     pub fn step(&mut self, max_t: i64) {
         self.queue.update(&mut self.io);
 
@@ -101,7 +332,7 @@ impl Mcu {
             }
         }
 
-        if self.halted || self.sleeping {
+        if self.halted || self.sleeping || self.in_reset {
             self.queue.skip_to_event(max_t);
         } else {
             let opcode: u16 = self.read_at_pc_offset(0);
@@ -335,7 +566,11 @@ impl Module for Mcu {
     }
 
     fn find_mut(&mut self, address: ModuleAddress) -> Option<&mut dyn Module> {
-        self.io.find_mut(address)
+        if address.is_empty() {
+            Some(self)
+        } else {
+            self.io.find_mut(address)
+        }
     }
 
     fn to_wireable_mut(&mut self) -> Option<&mut dyn WireableModule> {
@@ -344,19 +579,72 @@ impl Module for Mcu {
     fn to_wireable(&self) -> Option<&dyn WireableModule> {
         Some(self)
     }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(&(
+            &self.reg_file,
+            &self.sram,
+            &self.flash,
+            self.pc,
+            self.sp,
+            self.sreg.0,
+            self.rampz,
+            self.eind,
+            self.halted,
+            self.sleeping,
+            self.in_reset,
+        ));
+        self.queue.save_state(state);
+        self.io.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        let sreg;
+        (
+            self.reg_file,
+            self.sram,
+            self.flash,
+            self.pc,
+            self.sp,
+            sreg,
+            self.rampz,
+            self.eind,
+            self.halted,
+            self.sleeping,
+            self.in_reset,
+        ) = state.read()?;
+        self.sreg = StatusRegister(sreg);
+        self.queue.load_state(state)?;
+        self.io.load_state(state)
+    }
 }
 
 impl ActiveModule for Mcu {
     fn run_until_time(&mut self, t: Timestamp) -> Timestamp {
+        self.queue.begin_window(t);
         while self.queue.clock.current_time() < t {
+            let (start, pc) = (self.queue.clock.current_time(), self.pc);
+            let running = !self.halted && !self.sleeping && !self.in_reset;
             self.step(t);
-            if self.halted && self.queue.is_empty() {
+            self.check_watch(start, pc);
+            if !self.breakpoints.is_empty() {
+                self.check_breakpoints(pc, running);
+            }
+            if (self.halted && self.queue.is_empty()) || self.queue.stop_requested() {
                 break;
             }
         }
         self.queue.clock.current_time()
     }
 
+    fn next_wakeup(&mut self) -> Option<Timestamp> {
+        if self.halted || self.sleeping || self.in_reset {
+            Some(self.queue.next_event_time().unwrap_or(Timestamp::MAX))
+        } else {
+            None
+        }
+    }
+
     fn module_store(&mut self) -> &mut PassiveModuleStore {
         &mut self.io.module_store
     }
@@ -364,6 +652,10 @@ impl ActiveModule for Mcu {
     fn event_queue(&self) -> &EventQueue {
         &self.queue
     }
+
+    fn event_queue_mut(&mut self) -> &mut EventQueue {
+        &mut self.queue
+    }
 }
 
 impl WireableModule for Mcu {
@@ -376,6 +668,14 @@ impl WireableModule for Mcu {
     fn set_pin(&mut self, queue: &mut EventQueue, id: PinId, data: WireState) {
         self.io.set_pin(queue, id, data)
     }
+
+    fn pin_count(&self) -> usize {
+        self.io.pin_count()
+    }
+
+    fn pin_by_name(&self, name: &str) -> Option<PinId> {
+        self.io.pin_by_name(name)
+    }
 }
 
 #[cfg(test)]
@@ -393,3 +693,140 @@ mod test_helper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        snapshot::{StateReader, StateWriter},
+        test_util::PB7_HIGH,
+        SystemBuilder,
+    };
+
+    use super::*;
+
+    #[test]
+    fn state_round_trip() {
+        let mut mcu = Mcu::default();
+        mcu.reg_file.regs[24] = 0x42;
+        mcu.sram[0x100] = 0x13;
+        mcu.flash[0x10] = 0xBEEF;
+        mcu.pc = 0x1234;
+        mcu.sp = 0x21FF;
+        mcu.sreg = StatusRegister(0b1000_0011);
+        mcu.write(0x24, 0xFF); // DDRB
+
+        let mut state = StateWriter::new();
+        mcu.save_state(&mut state);
+        let data = state.into_inner();
+
+        let mut restored = Mcu::default();
+        let mut reader = StateReader::new(&data);
+        restored.load_state(&mut reader).unwrap();
+        assert!(reader.is_empty());
+
+        assert_eq!(restored.reg_file.regs, mcu.reg_file.regs);
+        assert_eq!(restored.sram, mcu.sram);
+        assert_eq!(restored.flash, mcu.flash);
+        assert_eq!(restored.pc, 0x1234);
+        assert_eq!(restored.sp, 0x21FF);
+        assert_eq!(restored.sreg.0, 0b1000_0011);
+        assert_eq!(restored.read(0x24), 0xFF);
+    }
+
+    #[test]
+    fn watch_changes() {
+        let mut mcu = Mcu::default();
+        mcu.flash[0] = 0x0000; // NOP
+        mcu.flash[1] = 0xE482; // LDI r24, 0x42
+        mcu.flash[2] = 0x0000; // NOP
+
+        assert!(mcu.watch_changes(24));
+        assert!(!mcu.watch_changes(0x60));
+        assert!(mcu.watch_changes(24));
+        mcu.run_until_time(3);
+
+        let change = mcu.take_watched_change().unwrap();
+        assert_eq!(change.t, 1);
+        assert_eq!(change.pc, 1);
+        assert_eq!(change.old, 0);
+        assert_eq!(change.new, 0x42);
+        assert_eq!(mcu.take_watched_change(), None);
+    }
+
+    #[test]
+    fn pin_names() {
+        let mcu = Mcu::default();
+        assert_eq!(mcu.pin_by_name("PA0"), Some(0));
+        assert_eq!(mcu.pin_by_name("pb7"), Some(15));
+        assert_eq!(mcu.pin_by_name("PG5"), Some(53));
+        assert_eq!(mcu.pin_by_name("PG6"), None);
+        assert_eq!(mcu.pin_by_name("PI0"), None);
+        assert_eq!(mcu.pin_by_name("PH0"), Some(54));
+        assert_eq!(mcu.pin_by_name("PL7"), Some(85));
+        assert_eq!(mcu.pin_by_name("D13"), mcu.pin_by_name("PB7"));
+        assert_eq!(mcu.pin_by_name("D4"), mcu.pin_by_name("PG5"));
+        assert_eq!(mcu.pin_by_name("A0"), mcu.pin_by_name("PF0"));
+        assert_eq!(mcu.pin_by_name("A15"), mcu.pin_by_name("PK7"));
+        assert_eq!(mcu.pin_by_name("D70"), None);
+        assert_eq!(mcu.pin_by_name("A16"), None);
+        assert_eq!(mcu.pin_by_name("TXD0"), mcu.pin_by_name("PE1"));
+        assert_eq!(mcu.pin_by_name("XCK1"), mcu.pin_by_name("PD5"));
+        assert_eq!(mcu.pin_by_name("OC1A"), mcu.pin_by_name("PB5"));
+        assert_eq!(mcu.pin_by_name("foo"), None);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .build()
+            .unwrap();
+        let ddrb = sys.add_breakpoint("mcu", Breakpoint::Access(0x24)).unwrap();
+        let portb = sys.add_breakpoint("mcu", Breakpoint::Write(0x25)).unwrap();
+        sys.add_breakpoint("mcu", Breakpoint::Read(0x25)).unwrap();
+        let hit = |id, t, pc, value| BreakpointHit { id, t, pc, value };
+        // The MCU stops after the instruction that accessed the memory.
+        assert_eq!(sys.run_until_change(100), Some(2));
+        assert_eq!(sys.take_breakpoint_hits(), [hit(ddrb, 2, 1, Some(0x80))]);
+        assert_eq!(sys.run_until_change(100), Some(3));
+        assert_eq!(sys.take_breakpoint_hits(), [hit(portb, 3, 2, Some(0x80))]);
+        // It is at the `rjmp .-2` already, it stops when it comes back.
+        let pc = sys.add_breakpoint("mcu", Breakpoint::Pc(3)).unwrap();
+        assert_eq!(sys.run_until_change(100), Some(5));
+        assert_eq!(sys.take_breakpoint_hits(), [hit(pc, 5, 3, None)]);
+        assert_eq!(sys.breakpoint(pc), Some(("mcu", Breakpoint::Pc(3))));
+        assert!(sys.remove_breakpoint(pc));
+        assert!(!sys.remove_breakpoint(pc));
+        assert_eq!(sys.run_until_change(100), None);
+        assert_eq!(sys.take_breakpoint_hits(), []);
+        assert_eq!(
+            sys.add_breakpoint("other", Breakpoint::Pc(0)),
+            Err("unknown MCU `other`".into())
+        );
+    }
+
+    #[test]
+    fn reset_mcu() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .build()
+            .unwrap();
+        sys.run_for(10);
+        assert_eq!(sys.read_pin("mcu:PB7"), Ok(WireState::High));
+        // MCUSR has PORF set from the power-on, then EXTRF as well.
+        assert_eq!(sys.read_memory("mcu", 0x54), Ok(0x01));
+        sys.reset_mcu("mcu", ResetCause::External, Some(50))
+            .unwrap();
+        assert_eq!(sys.read_pin("mcu:PB7"), Ok(WireState::Z));
+        sys.run_for(40);
+        assert_eq!(sys.read_pin("mcu:PB7"), Ok(WireState::Z));
+        assert_eq!(sys.read_memory("mcu", 0x54), Ok(0x03));
+        // The program runs again once the reset is released.
+        sys.run_for(20);
+        assert_eq!(sys.read_pin("mcu:PB7"), Ok(WireState::High));
+        assert_eq!(
+            sys.reset_mcu("other", ResetCause::BrownOut, None),
+            Err("unknown MCU `other`".into())
+        );
+    }
+}
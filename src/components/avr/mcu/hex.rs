@@ -1,6 +1,7 @@
 use std::{
     fs::File,
-    io::{BufRead, BufReader},
+    io::{self, BufRead, BufReader, ErrorKind},
+    path::Path,
     str::FromStr,
 };
 
@@ -13,13 +14,20 @@ struct HexLine {
     data: Vec<u8>,
 }
 
+fn parse_hex(s: &str, index: usize, digits: usize) -> Result<u16, String> {
+    let field = s
+        .get(index..index + digits)
+        .ok_or_else(|| "record is too short".to_string())?;
+    u16::from_str_radix(field, 16).map_err(|err| err.to_string())
+}
+
 impl FromStr for HexLine {
-    type Err = std::num::ParseIntError;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let size = u8::from_str_radix(&s[1..3], 16)?;
-        let addr = u16::from_str_radix(&s[3..7], 16)?;
-        let record_type = u8::from_str_radix(&s[7..9], 16)?;
+        let size = parse_hex(s, 1, 2)? as u8;
+        let addr = parse_hex(s, 3, 4)?;
+        let record_type = parse_hex(s, 7, 2)? as u8;
         if size == 0 && addr == 0 && record_type == 1 {
             Ok(HexLine {
                 size,
@@ -28,18 +36,22 @@ impl FromStr for HexLine {
                 data: Vec::new(),
             })
         } else {
-            assert_eq!(record_type, 0);
+            if record_type != 0 {
+                return Err(format!("unsupported record type {}", record_type));
+            }
             let mut data = vec![0; size as usize];
             let mut index = 9;
             let mut checksum = size as u16 + (addr & 0xFF) + (addr >> 8) + record_type as u16;
             for i in 0..size {
-                let x = u8::from_str_radix(&s[index..index + 2], 16)?;
+                let x = parse_hex(s, index, 2)? as u8;
                 index += 2;
                 data[i as usize] = x;
                 checksum += x as u16;
             }
-            checksum += u16::from_str_radix(&s[index..index + 2], 16)?;
-            assert_eq!(checksum & 0xFF, 0);
+            checksum += parse_hex(s, index, 2)?;
+            if checksum & 0xFF != 0 {
+                return Err("wrong checksum".to_string());
+            }
             Ok(HexLine {
                 size,
                 addr,
@@ -52,13 +64,15 @@ impl FromStr for HexLine {
 
 impl Mcu {
     /// Reads flash from Intel .hex file
-    pub fn load_flash_hex(&mut self, filename: &str) {
-        let file = File::open(filename).unwrap();
+    pub fn load_flash_hex(&mut self, filename: impl AsRef<Path>) -> io::Result<()> {
+        let file = File::open(filename)?;
         let lines = BufReader::new(file).lines();
-        for line in lines {
-            let l = line.unwrap();
+        for (n, line) in lines.enumerate() {
+            let l = line?;
             if l.starts_with(':') {
-                let data: HexLine = l.parse().unwrap();
+                let data: HexLine = l.parse().map_err(|err| {
+                    io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", n + 1, err))
+                })?;
                 match data.record_type {
                     0 => {
                         let mut i = 0;
@@ -68,15 +82,15 @@ impl Mcu {
                             i += 2;
                         }
                     }
-                    1 => break,
-                    _ => panic!("Invalid record type"),
+                    _ => break,
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn with_flash_hex(mut self: Self, filename: &str) -> Self {
-        self.load_flash_hex(filename);
-        self
+    pub fn with_flash_hex(mut self: Self, filename: &str) -> io::Result<Self> {
+        self.load_flash_hex(filename)?;
+        Ok(self)
     }
 }
@@ -11,10 +11,35 @@ impl Mcu {
         self.flash[addr as usize] = val
     }
 
+    /// Size of the flash, in words.
+    pub fn flash_size(&self) -> u32 {
+        FLASH_SIZE as u32
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
     pub fn set_pc(&mut self, val: u32) {
         self.pc = val % FLASH_SIZE as u32;
     }
 
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, val: u16) {
+        self.sp = val;
+    }
+
+    pub fn sreg(&self) -> u8 {
+        self.sreg.0
+    }
+
+    pub fn set_sreg(&mut self, val: u8) {
+        self.sreg = StatusRegister(val);
+    }
+
     pub fn read_register(&self, i: u16) -> u8 {
         assert!(i < 32);
         self.reg_file.regs[i as usize]
@@ -35,7 +60,7 @@ impl Mcu {
         self.reg_file.write_u16(i as usize, val);
     }
     pub fn read_io(&mut self, i: u8) -> u8 {
-        match i {
+        let value = match i {
             0x00..=0x3A => self.io.read_port_internal(&mut self.queue, i.into()),
             0x3B => self.rampz,
             0x3C => self.eind,
@@ -46,10 +71,13 @@ impl Mcu {
                 x
             }
             _ => panic!("Only 64 internal IO registers!"),
-        }
+        };
+        self.note_access(i as u16 + 0x20, value, false);
+        value
     }
 
     pub fn write_io(&mut self, i: u8, val: u8) {
+        self.note_access(i as u16 + 0x20, val, true);
         match i {
             0x00..=0x3A => self.io.write_port_internal(&mut self.queue, i.into(), val),
             0x3B => self.rampz = val & RAMPZ_MASK,
@@ -62,18 +90,50 @@ impl Mcu {
     }
 
     pub fn read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let value = match addr {
             0x0000..=0x001F => self.read_register(addr),
-            0x0020..=0x005F => self.read_io((addr - 0x20) as u8),
+            // The access is noted by read_io.
+            0x0020..=0x005F => return self.read_io((addr - 0x20) as u8),
             0x0060..=0x01FF => self.io.read_port(&mut self.queue, addr.into()),
             0x0200..=SRAM_END => self.sram[addr as usize - 0x200],
             _ => 0,
+        };
+        self.note_access(addr, value, false);
+        value
+    }
+
+    /// Reads the data space without the side effects of reading peripheral
+    /// registers. Returns `None` for addresses owned by the peripherals.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x001F => Some(self.read_register(addr)),
+            0x005B => Some(self.rampz),
+            0x005C => Some(self.eind),
+            0x005D => Some(self.sp as u8),
+            0x005E => Some((self.sp >> 8) as u8),
+            0x005F => Some(self.sreg.0),
+            0x0200..=SRAM_END => Some(self.sram[addr as usize - 0x200]),
+            _ => None,
+        }
+    }
+
+    /// Reads the data space like [Mcu::peek], including the peripheral
+    /// registers, without the side effects of reading them. Returns `None` for
+    /// addresses that aren't mapped to an emulated register or to SRAM.
+    pub fn peek_memory(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0020..=0x005A | 0x0060..=0x01FF => self.io.peek_port(&self.queue, addr.into()),
+            _ => self.peek(addr),
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        if !(0x0020..=0x005F).contains(&addr) {
+            self.note_access(addr, val, true);
+        }
         match addr {
             0x0000..=0x001F => self.write_register(addr, val),
+            // The access is noted by write_io.
             0x0020..=0x005F => self.write_io((addr - 0x20) as u8, val),
             0x0060..=0x01FF => self.io.write_port(&mut self.queue, addr.into(), val),
             0x0200..=SRAM_END => self.sram[addr as usize - 0x200] = val,
@@ -152,6 +212,23 @@ mod tests {
         assert_eq!(mcu.reg_file.regs[0..4], [9, 10, 11, 12]);
     }
 
+    #[test]
+    fn memory_peeks() {
+        let mut mcu = Mcu::default();
+        mcu.write(0x24, 0x80); // DDRB
+        mcu.write(0x200, 5);
+
+        assert_eq!(mcu.peek_memory(0x24), Some(0x80));
+        assert_eq!(mcu.peek_memory(0x5D), Some(mcu.sp as u8));
+        assert_eq!(mcu.peek_memory(0x81), Some(0)); // TCCR1B
+        assert_eq!(mcu.peek_memory(0xC6), Some(0)); // UDR0
+        assert_eq!(mcu.peek_memory(0x200), Some(5));
+        // Registers that aren't emulated and unmapped addresses.
+        for address in [0x35, 0x37, 0x6E, 0x70, 0x86, 0xB0, 0x1FF, SRAM_END + 1] {
+            assert_eq!(mcu.peek_memory(address), None, "{:#x}", address);
+        }
+    }
+
     #[test]
     fn memory_extended() {
         let mut mcu = Mcu::default();
@@ -0,0 +1,73 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use super::Mcu;
+
+/// Offset of the data space in the addresses of the symbols, as avr-gcc
+/// lays them out.
+pub(super) const DATA_OFFSET: u32 = 0x80_0000;
+
+/// Name and address of a symbol, from a line like `00000106 T main`, with an
+/// optional size after the address. Undefined symbols have no address and
+/// give `None`.
+fn parse_symbol(line: &str) -> Result<Option<(&str, u32)>, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (address, name) = match fields[..] {
+        [] | [_, _] => return Ok(None),
+        [address, _, name] | [address, _, _, name] => (address, name),
+        _ => return Err("expected an address, a type and a name".to_string()),
+    };
+    let address =
+        u32::from_str_radix(address, 16).map_err(|_| format!("invalid address `{}`", address))?;
+    Ok(Some((name, address)))
+}
+
+impl Mcu {
+    /// Reads the symbols of the firmware from the output of `avr-nm`, for
+    /// [Mcu::code_symbol] and [Mcu::data_symbol].
+    pub fn load_symbols(&mut self, filename: impl AsRef<Path>) -> io::Result<()> {
+        let text = fs::read_to_string(filename)?;
+        for (n, line) in text.lines().enumerate() {
+            let symbol = parse_symbol(line).map_err(|err| {
+                io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", n + 1, err))
+            })?;
+            if let Some((name, address)) = symbol {
+                self.symbols.insert(name.to_string(), address);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols() {
+        let path = std::env::temp_dir().join("amber-symbols.txt");
+        fs::write(
+            &path,
+            "00000106 T main\n\
+             00800100 00000002 B counter\n\
+             \x20        U __do_copy_data\n",
+        )
+        .unwrap();
+        let mut mcu = Mcu::default();
+        mcu.load_symbols(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mcu.code_symbol("main"), Some(0x83));
+        assert_eq!(mcu.data_symbol("main"), None);
+        assert_eq!(mcu.data_symbol("counter"), Some(0x100));
+        assert_eq!(mcu.code_symbol("counter"), None);
+        assert_eq!(mcu.code_symbol("__do_copy_data"), None);
+        assert_eq!(
+            parse_symbol("main T 106"),
+            Err("invalid address `main`".to_string())
+        );
+    }
+}
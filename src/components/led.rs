@@ -1,6 +1,7 @@
 use std::any::Any;
 
 use kanal::Sender;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     clock::Timestamp,
@@ -8,13 +9,15 @@ use crate::{
     module::{Module, PinId, WireableModule},
     module_id::ModuleAddress,
     pin_state::WireState,
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Led {
     module_id: ModuleAddress,
     // state: bool,
+    #[serde(skip)]
     vcd_sender: Option<Sender<VcdEvent>>,
     vcd_start_id: i32,
 }
@@ -83,6 +86,17 @@ impl Module for Led {
     fn to_wireable_mut(&mut self) -> Option<&mut dyn WireableModule> {
         Some(self)
     }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(self);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        let vcd_sender = self.vcd_sender.take();
+        *self = state.read()?;
+        self.vcd_sender = vcd_sender;
+        Ok(())
+    }
 }
 
 impl WireableModule for Led {
@@ -115,4 +129,8 @@ impl WireableModule for Led {
         // }
         self.send_vcd(queue.clock.current_time(), self.vcd_start_id, &[data]);
     }
+
+    fn pin_count(&self) -> usize {
+        1
+    }
 }
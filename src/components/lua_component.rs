@@ -0,0 +1,543 @@
+//! Passive component whose behavior is a Lua script, for prototyping
+//! peripherals without writing Rust.
+//!
+//! The script is run once when the system is loaded, with the `params` of
+//! the component in a global table of the same name. It can then define:
+//!
+//! - `init()`, called at the start of the simulation;
+//! - `on_pin(pin, value, t)`, called when an input changes, `value` being
+//!   `true`, `false` or `nil` when the line floats.
+//!
+//! While they run, these functions can call:
+//!
+//! - `set_pin(pin, value)` to drive a pin high (`true`), low (`false`) or
+//!   release it (`nil`);
+//! - `get_pin(pin)` for the value of an input;
+//! - `after(cycles, fn)` to call `fn()` later, returning an id for
+//!   `cancel(id)`;
+//! - `now()` for the current time.
+//!
+//! Pins are given by name or by number. The variables of the script aren't
+//! part of snapshots, only the pins and the pending timers are: a restored
+//! timer whose function already ran since does nothing.
+
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+};
+
+use kanal::Sender;
+use mlua::{Function, Lua, Table, Value};
+use serde::{Deserialize, Serialize};
+use yaml_rust2::Yaml;
+
+use crate::{
+    clock::{TimeDiff, Timestamp},
+    events::{EventQueue, InternalEvent},
+    module::{Module, PinId, WireableModule},
+    module_id::ModuleAddress,
+    pin_state::WireState,
+    snapshot::{SnapshotResult, StateReader, StateWriter},
+    vcd::{VcdEvent, VcdSender, VcdSignal},
+};
+
+/// Name of the registry table with the timer callbacks, by id.
+const TIMERS: &str = "amber.timers";
+
+/// Most pins a component can have, as pins are numbered with a byte.
+pub const MAX_PINS: usize = 256;
+
+/// Event port of the timers.
+const TIMER_PORT: u8 = 0;
+/// Event port of the call to `init`.
+const INIT_PORT: u8 = 1;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ScriptState {
+    inputs: Vec<WireState>,
+    outputs: Vec<WireState>,
+    /// Time of the pending timers, by id.
+    timers: BTreeMap<u64, Timestamp>,
+    next_timer: u64,
+}
+
+/// Change requested by the script, applied once it returns.
+enum Action {
+    Drive(usize, WireState),
+    Schedule(u64, Timestamp),
+    Cancel(u64),
+}
+
+pub struct LuaComponent {
+    module_id: ModuleAddress,
+    name: String,
+    pins: Vec<String>,
+    state: ScriptState,
+    lua: Lua,
+
+    vcd_sender: Option<Sender<VcdEvent>>,
+    vcd_start_id: i32,
+}
+
+impl LuaComponent {
+    /// Loads the script `source`, named `chunk_name` in error messages. The
+    /// component is called `name` in the messages of the simulation.
+    pub fn new(
+        module_id: ModuleAddress,
+        name: &str,
+        pins: Vec<String>,
+        source: &str,
+        chunk_name: &str,
+        params: &Yaml,
+    ) -> Result<LuaComponent, String> {
+        if pins.len() > MAX_PINS {
+            return Err(format!(
+                "too many pins ({}), at most {}",
+                pins.len(),
+                MAX_PINS
+            ));
+        }
+        let lua = Lua::new();
+        let setup = || -> mlua::Result<()> {
+            lua.set_named_registry_value(TIMERS, lua.create_table()?)?;
+            lua.globals().set("params", yaml_to_lua(&lua, params)?)?;
+            lua.load(source).set_name(format!("@{}", chunk_name)).exec()
+        };
+        setup().map_err(|err| err.to_string())?;
+        Ok(LuaComponent {
+            module_id,
+            name: name.to_string(),
+            state: ScriptState {
+                inputs: vec![WireState::Z; pins.len()],
+                outputs: vec![WireState::Z; pins.len()],
+                ..Default::default()
+            },
+            pins,
+            lua,
+
+            vcd_sender: None,
+            vcd_start_id: 0,
+        })
+    }
+
+    /// Schedules the call to `init` at the start of the simulation.
+    pub fn start(address: ModuleAddress, queue: &mut EventQueue) {
+        queue.fire_event(
+            InternalEvent {
+                receiver_id: address.with_event_port(INIT_PORT),
+            },
+            0,
+        );
+    }
+
+    /// Calls `f` with the functions of the script available, then applies
+    /// what it asked for. Errors are reported as messages of the simulation.
+    fn call(&mut self, queue: &mut EventQueue, f: impl FnOnce(&Lua) -> mlua::Result<()>) {
+        let t = queue.clock.current_time();
+        let actions = RefCell::new(Vec::new());
+        let next_timer = Cell::new(self.state.next_timer);
+        let (lua, pins, inputs) = (&self.lua, &self.pins, &self.state.inputs);
+        let result = lua.scope(|scope| {
+            let globals = lua.globals();
+            globals.set(
+                "set_pin",
+                scope.create_function(|_, (pin, value): (Value, Option<bool>)| {
+                    let pin = pin_index(pins, &pin)?;
+                    let state = value.map_or(WireState::Z, WireState::from_bool);
+                    actions.borrow_mut().push(Action::Drive(pin, state));
+                    Ok(())
+                })?,
+            )?;
+            globals.set(
+                "get_pin",
+                scope.create_function(|_, pin: Value| {
+                    Ok(wire_value(inputs[pin_index(pins, &pin)?]))
+                })?,
+            )?;
+            globals.set(
+                "after",
+                scope.create_function(|lua, (cycles, callback): (TimeDiff, Function)| {
+                    let id = next_timer.get();
+                    next_timer.set(id + 1);
+                    let timers: Table = lua.named_registry_value(TIMERS)?;
+                    timers.set(id, callback)?;
+                    actions
+                        .borrow_mut()
+                        .push(Action::Schedule(id, t + cycles.max(0)));
+                    Ok(id)
+                })?,
+            )?;
+            globals.set(
+                "cancel",
+                scope.create_function(|lua, id: u64| {
+                    let timers: Table = lua.named_registry_value(TIMERS)?;
+                    timers.set(id, Value::Nil)?;
+                    actions.borrow_mut().push(Action::Cancel(id));
+                    Ok(())
+                })?,
+            )?;
+            globals.set("now", scope.create_function(move |_, ()| Ok(t))?)?;
+            f(lua)
+        });
+        if let Err(err) = result {
+            queue.add_message(format!("{}: {}: {}", t, self.name, err));
+        }
+
+        self.state.next_timer = next_timer.get();
+        for action in actions.into_inner() {
+            match action {
+                Action::Drive(pin, state) => {
+                    self.state.outputs[pin] = state;
+                    queue.set_wire(self.module_id.with_pin(pin as u8), state);
+                    self.send_vcd(t, self.vcd_start_id + pin as i32, &[state]);
+                }
+                Action::Schedule(id, at) => {
+                    self.state.timers.insert(id, at);
+                }
+                Action::Cancel(id) => {
+                    self.state.timers.remove(&id);
+                }
+            }
+        }
+        self.schedule_timers(queue);
+    }
+
+    /// Schedules the event of the earliest pending timer. There is a single
+    /// event for all the timers: firing it again moves it.
+    fn schedule_timers(&self, queue: &mut EventQueue) {
+        if let Some(&at) = self.state.timers.values().min() {
+            queue.fire_event(
+                InternalEvent {
+                    receiver_id: self.module_id.with_event_port(TIMER_PORT),
+                },
+                at,
+            );
+        }
+    }
+
+    /// Calls the global function `name`, if the script defines it.
+    fn call_hook<A: for<'lua> mlua::IntoLuaMulti<'lua>>(
+        &mut self,
+        queue: &mut EventQueue,
+        name: &str,
+        args: A,
+    ) {
+        self.call(queue, |lua| {
+            match lua.globals().get::<_, Option<Function>>(name)? {
+                Some(hook) => hook.call(args),
+                None => Ok(()),
+            }
+        });
+    }
+
+    fn run_timers(&mut self, queue: &mut EventQueue, t: Timestamp) {
+        let mut due: Vec<(Timestamp, u64)> = self
+            .state
+            .timers
+            .iter()
+            .filter(|&(_, &at)| at <= t)
+            .map(|(&id, &at)| (at, id))
+            .collect();
+        due.sort();
+        for (_, id) in due {
+            self.state.timers.remove(&id);
+            self.call(queue, |lua| {
+                let timers: Table = lua.named_registry_value(TIMERS)?;
+                let callback: Option<Function> = timers.get(id)?;
+                timers.set(id, Value::Nil)?;
+                // Timers restored from a snapshot may not exist anymore.
+                match callback {
+                    Some(callback) => callback.call(()),
+                    None => Ok(()),
+                }
+            });
+        }
+        self.schedule_timers(queue);
+    }
+}
+
+/// Index of a pin given by name or number.
+fn pin_index(pins: &[String], pin: &Value) -> mlua::Result<usize> {
+    let (index, pin) = match pin {
+        Value::Integer(i) => (
+            usize::try_from(*i).ok().filter(|&i| i < pins.len()),
+            i.to_string(),
+        ),
+        Value::String(name) => {
+            let name = name.to_str()?;
+            (
+                pins.iter().position(|pin| pin.eq_ignore_ascii_case(name)),
+                name.to_string(),
+            )
+        }
+        other => (None, other.type_name().to_string()),
+    };
+    index.ok_or_else(|| mlua::Error::RuntimeError(format!("unknown pin `{}`", pin)))
+}
+
+/// Value of a line for the script: `nil` when it floats.
+fn wire_value(state: WireState) -> Option<bool> {
+    match state {
+        WireState::High | WireState::WeakHigh => Some(true),
+        WireState::Low | WireState::WeakLow => Some(false),
+        WireState::Z | WireState::Error => None,
+    }
+}
+
+fn yaml_to_lua<'lua>(lua: &'lua Lua, value: &Yaml) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        Yaml::String(s) => Value::String(lua.create_string(s)?),
+        Yaml::Integer(i) => Value::Integer(*i),
+        Yaml::Real(_) => value.as_f64().map_or(Value::Nil, Value::Number),
+        Yaml::Boolean(b) => Value::Boolean(*b),
+        Yaml::Array(items) => {
+            let table = lua.create_table()?;
+            for item in items {
+                table.push(yaml_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        Yaml::Hash(entries) => {
+            let table = lua.create_table()?;
+            for (key, item) in entries {
+                table.set(yaml_to_lua(lua, key)?, yaml_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        _ => Value::Nil,
+    })
+}
+
+impl std::fmt::Debug for LuaComponent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaComponent")
+            .field("module_id", &self.module_id)
+            .field("name", &self.name)
+            .field("pins", &self.pins)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl VcdSender for LuaComponent {
+    fn register_vcd(&mut self, sender: Sender<VcdEvent>, start_id: i32) -> (Vec<VcdSignal>, i32) {
+        let signals = self
+            .pins
+            .iter()
+            .enumerate()
+            .map(|(i, pin)| VcdSignal::Signal {
+                name: pin.clone(),
+                id: start_id + i as i32,
+                size: 1,
+            })
+            .collect();
+        self.vcd_sender = Some(sender);
+        self.vcd_start_id = start_id;
+        (signals, self.pins.len() as i32)
+    }
+
+    fn vcd_sender(&self) -> Option<&Sender<VcdEvent>> {
+        self.vcd_sender.as_ref()
+    }
+}
+
+impl Module for LuaComponent {
+    fn address(&self) -> ModuleAddress {
+        self.module_id
+    }
+
+    #[inline]
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn handle_event(&mut self, event: InternalEvent, queue: &mut EventQueue, t: Timestamp) {
+        match event.receiver_id.event_port_id {
+            TIMER_PORT => self.run_timers(queue, t),
+            INIT_PORT => self.call_hook(queue, "init", ()),
+            port => panic!("Invalid event port {}", port),
+        }
+    }
+
+    fn find(&self, address: ModuleAddress) -> Option<&dyn Module> {
+        if address.is_empty() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn find_mut(&mut self, address: ModuleAddress) -> Option<&mut dyn Module> {
+        if address.is_empty() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn to_wireable(&self) -> Option<&dyn WireableModule> {
+        Some(self)
+    }
+    fn to_wireable_mut(&mut self) -> Option<&mut dyn WireableModule> {
+        Some(self)
+    }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(&self.state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        self.state = state.read()?;
+        Ok(())
+    }
+}
+
+impl WireableModule for LuaComponent {
+    fn get_pin(&self, _queue: &EventQueue, id: PinId) -> WireState {
+        self.state.outputs[id]
+    }
+
+    fn set_pin(&mut self, queue: &mut EventQueue, id: PinId, data: WireState) {
+        if self.state.inputs[id] == data {
+            return;
+        }
+        self.state.inputs[id] = data;
+        let t = queue.clock.current_time();
+        let pin = self.pins[id].clone();
+        self.call_hook(queue, "on_pin", (pin, wire_value(data), t));
+    }
+
+    fn pin_count(&self) -> usize {
+        self.pins.len()
+    }
+
+    fn pin_by_name(&self, name: &str) -> Option<PinId> {
+        self.pins
+            .iter()
+            .position(|pin| pin.eq_ignore_ascii_case(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load, system::System, test_util::TempDir, LoadOptions};
+
+    /// System with a component `mcu.lua`, with pins `in` and `out`, running
+    /// `script`. The MCU halts right away, so that the component sees the
+    /// changes of its pins when they are made.
+    fn system(script: &str) -> System {
+        let dir = TempDir::new();
+        dir.write("script.lua", script);
+        // nop; rjmp .-2
+        dir.write("halt.hex", ":040000000000FFCF2E\n:00000001FF\n");
+        let config = "components:\n  mcu:\n    type: mcu\n    memory: halt.hex\n    \
+                      components:\n      lua:\n        type: lua\n        script: script.lua\n        \
+                      pins: [in, out]\n";
+        let path = dir.write("config.yaml", config);
+        load(path.to_str().unwrap(), &LoadOptions::default()).unwrap()
+    }
+
+    /// The global `log` of the script.
+    fn log(sys: &mut System) -> String {
+        let lua = &sys.component_mut::<LuaComponent>("mcu.lua").unwrap().lua;
+        lua.globals().get("log").unwrap()
+    }
+
+    fn messages(sys: &System) -> Vec<String> {
+        sys.system_tables.messages.read().unwrap().clone()
+    }
+
+    #[test]
+    fn on_pin() {
+        let mut sys = system(
+            "log = ''\n\
+             function on_pin(pin, value, t)\n  \
+               log = log .. string.format('%s=%s@%d ', pin, tostring(value), t)\n  \
+               set_pin('out', value)\n  \
+               if value == false then set_pin('nope', true) end\n\
+             end\n",
+        );
+        sys.run_for(10);
+        sys.write_pin("mcu.lua:in", WireState::High).unwrap();
+        sys.run_for(10);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::High));
+        // The same value again isn't a change.
+        sys.write_pin("mcu.lua:in", WireState::High).unwrap();
+        sys.run_for(10);
+        sys.write_pin("mcu.lua:in", WireState::Low).unwrap();
+        sys.run_for(10);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::Low));
+        sys.write_pin("mcu.lua:in", WireState::Z).unwrap();
+        sys.run_for(10);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::Z));
+
+        assert_eq!(log(&mut sys), "in=true@10 in=false@30 in=nil@40 ");
+        // Errors are reported, what the script did before them still applies.
+        let messages = messages(&sys);
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert!(
+            messages[0].starts_with("30: mcu.lua: ") && messages[0].contains("unknown pin `nope`"),
+            "{}",
+            messages[0]
+        );
+    }
+
+    #[test]
+    fn timers() {
+        let mut sys = system(
+            "log = ''\n\
+             function init()\n  \
+               after(30, function() log = log .. 'a' end)\n  \
+               after(10, function() log = log .. 'b' end)\n  \
+               after(10, function()\n    \
+                 log = log .. 'c'\n    \
+                 after(0, function() log = log .. string.format('d@%d', now()) end)\n  \
+               end)\n  \
+               cancel(after(20, function() log = log .. 'e' end))\n  \
+               after(-5, function() log = log .. 'f' end)\n\
+             end\n",
+        );
+        sys.run_for(5);
+        assert_eq!(log(&mut sys), "f");
+        // Timers due at the same time run in the order they were set.
+        sys.run_for(10);
+        assert_eq!(log(&mut sys), "fbcd@10");
+        sys.run_for(100);
+        assert_eq!(log(&mut sys), "fbcd@10a");
+        assert_eq!(messages(&sys), Vec::<String>::new());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut sys = system(
+            "function init()\n  \
+               after(10, function() set_pin('out', true) end)\n  \
+               after(50, function() set_pin('out', false) end)\n\
+             end\n\
+             function on_pin(pin, value) set_pin('out', value) end\n",
+        );
+        sys.run_for(20);
+        let snapshot = sys.snapshot();
+        sys.write_pin("mcu.lua:in", WireState::Low).unwrap();
+        sys.run_for(10);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::Low));
+
+        // The pins and the pending timer are restored.
+        sys.restore(&snapshot).unwrap();
+        assert_eq!(sys.t, 20);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::High));
+        sys.run_for(25);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::High));
+        sys.run_for(10);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::Low));
+
+        // The function of a timer is gone once it ran: restored again, the
+        // timer does nothing.
+        sys.restore(&snapshot).unwrap();
+        sys.run_for(100);
+        assert_eq!(sys.read_pin("mcu.lua:out"), Ok(WireState::High));
+        assert_eq!(messages(&sys), Vec::<String>::new());
+    }
+}
@@ -5,8 +5,8 @@ use std::{
     thread::JoinHandle,
 };
 
-use getch::Getch;
 use kanal::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     clock::Timestamp,
@@ -14,17 +14,18 @@ use crate::{
     module::{Module, PinId, WireableModule},
     module_id::ModuleAddress,
     pin_state::{InputPinState, WireState},
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ParityMode {
     Disabled,
     Even,
     Odd,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum FrameState {
     Idle,
     Start,
@@ -33,7 +34,7 @@ enum FrameState {
     End(u8),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct UartConfig {
     pub parity: ParityMode,
     pub double_stop_bit: bool,
@@ -41,6 +42,7 @@ pub struct UartConfig {
     pub polarity: bool,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct UartModule {
     module_id: ModuleAddress,
 
@@ -61,9 +63,16 @@ pub struct UartModule {
     parity_error: bool,
     frame_error: bool,
 
+    #[serde(skip)]
     tx_receiver: Option<Receiver<u16>>,
+    #[serde(skip)]
     rx_sender: Option<Sender<u16>>,
+    /// Whether to request a stop of the simulation when a character is
+    /// received, see [UartModule::watch_rx].
+    #[serde(skip)]
+    rx_watch: bool,
 
+    #[serde(skip)]
     vcd_sender: Option<Sender<VcdEvent>>,
 }
 
@@ -95,6 +104,7 @@ impl UartModule {
 
             tx_receiver: None,
             rx_sender: None,
+            rx_watch: false,
         }
     }
 
@@ -133,7 +143,7 @@ impl UartModule {
         }
     }
 
-    fn trigger_receiver(&mut self, _queue: &mut EventQueue) {
+    fn trigger_receiver(&mut self, queue: &mut EventQueue) {
         self.rx_state = self.advance_state(self.rx_state);
 
         if self.rx_state == FrameState::Idle {
@@ -176,6 +186,9 @@ impl UartModule {
                 } else {
                     self.rx_data.push_back(self.rx_buf);
                 }
+                if self.rx_watch {
+                    queue.request_stop(queue.clock.current_time());
+                }
             }
             FrameState::End(_) => {}
         }
@@ -262,26 +275,42 @@ impl UartModule {
         self.rx_data.pop_front().map(|x| x as u8 as char)
     }
 
-    pub fn connect(&mut self) -> (JoinHandle<()>, JoinHandle<()>) {
-        let (tx_sender, tx_receiver) = kanal::bounded(1024);
+    /// Characters received and not read yet.
+    pub fn received(&self) -> &VecDeque<u16> {
+        &self.rx_data
+    }
+
+    /// Drops the first `count` received characters.
+    pub fn discard_received(&mut self, count: usize) {
+        self.rx_data.drain(..count.min(self.rx_data.len()));
+    }
+
+    /// Requests a stop of the simulation whenever a character is received.
+    pub fn watch_rx(&mut self, watch: bool) {
+        self.rx_watch = watch;
+    }
+
+    /// Connects the UART to the console: received characters go to stdout and
+    /// characters from `input` are transmitted. Returns the stdout writer thread.
+    pub fn connect(&mut self, input: Receiver<u16>) -> JoinHandle<()> {
         let (rx_sender, rx_receiver) = kanal::bounded(1024);
-        self.tx_receiver = Some(tx_receiver);
+        self.tx_receiver = Some(input);
         self.rx_sender = Some(rx_sender);
 
-        let t1 = std::thread::spawn(move || {
+        std::thread::spawn(move || {
             let mut f = stdout();
             for x in rx_receiver {
                 f.write_all(&[x as u8]).unwrap();
                 f.flush().unwrap();
             }
-        });
-        let t2 = std::thread::spawn(move || {
-            let g = Getch::new();
-            while let Ok(x) = g.getch() {
-                tx_sender.send(x as u16).unwrap();
-            }
-        });
-        (t1, t2)
+        })
+    }
+
+    /// Detaches the console, so that the writer thread from [UartModule::connect]
+    /// finishes once everything received so far is printed.
+    pub fn disconnect(&mut self) {
+        self.tx_receiver = None;
+        self.rx_sender = None;
     }
 }
 
@@ -353,6 +382,21 @@ impl Module for UartModule {
     fn to_wireable(&self) -> Option<&dyn WireableModule> {
         Some(self)
     }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        state.write(self);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        let vcd_sender = self.vcd_sender.take();
+        let tx_receiver = self.tx_receiver.take();
+        let rx_sender = self.rx_sender.take();
+        *self = state.read()?;
+        self.vcd_sender = vcd_sender;
+        self.tx_receiver = tx_receiver;
+        self.rx_sender = rx_sender;
+        Ok(())
+    }
 }
 
 impl WireableModule for UartModule {
@@ -378,4 +422,18 @@ impl WireableModule for UartModule {
             _ => panic!("Invalid pin {}", id),
         }
     }
+
+    fn pin_count(&self) -> usize {
+        Self::XCK_PIN as usize + 1
+    }
+
+    fn pin_by_name(&self, name: &str) -> Option<PinId> {
+        let pin = match name.to_ascii_uppercase().as_str() {
+            "RX" | "RXD" => Self::RX_PIN,
+            "TX" | "TXD" => Self::TX_PIN,
+            "XCK" => Self::XCK_PIN,
+            _ => return None,
+        };
+        Some(pin as PinId)
+    }
 }
@@ -0,0 +1,74 @@
+use kanal::{Receiver, Sender};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    Pause,
+    Resume,
+    TogglePause,
+    /// Runs the given number of cycles and stays paused afterwards.
+    Step(i64),
+    Stop,
+}
+
+/// Handle for controlling a running [System](crate::system::System) from another thread.
+#[derive(Debug, Clone)]
+pub struct SystemController(Sender<ControlCommand>);
+
+impl SystemController {
+    pub fn send(&self, command: ControlCommand) {
+        let _ = self.0.send(command);
+    }
+
+    pub fn pause(&self) {
+        self.send(ControlCommand::Pause)
+    }
+    pub fn resume(&self) {
+        self.send(ControlCommand::Resume)
+    }
+    pub fn toggle_pause(&self) {
+        self.send(ControlCommand::TogglePause)
+    }
+    pub fn step(&self, cycles: i64) {
+        self.send(ControlCommand::Step(cycles))
+    }
+    pub fn stop(&self) {
+        self.send(ControlCommand::Stop)
+    }
+}
+
+#[derive(Debug)]
+pub struct ControlChannel {
+    sender: Sender<ControlCommand>,
+    receiver: Receiver<ControlCommand>,
+    pub paused: bool,
+}
+
+impl Default for ControlChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlChannel {
+    pub fn new() -> Self {
+        let (sender, receiver) = kanal::unbounded();
+        Self {
+            sender,
+            receiver,
+            paused: false,
+        }
+    }
+
+    pub fn controller(&self) -> SystemController {
+        SystemController(self.sender.clone())
+    }
+
+    /// Returns the next pending command, blocking until one arrives while paused.
+    pub fn next(&self) -> Option<ControlCommand> {
+        if self.paused {
+            self.receiver.recv().ok()
+        } else {
+            self.receiver.try_recv().ok().flatten()
+        }
+    }
+}
@@ -1,29 +1,114 @@
-use std::cmp::Reverse;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+};
 
 use kanal::Receiver;
 use priority_queue::PriorityQueue;
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 
 use crate::{
     clock::{Clock, TickTimestamp, TimeDiff, Timestamp},
     module::{Module, PinId},
-    module_id::{EventPortAddress, ModuleAddress, PinAddress},
+    module_id::{EventPortAddress, ModuleAddress, ModuleIndex, PinAddress},
     multiplexer::MultiplexingTable,
-    pin_state::WireState,
+    pin_state::{Edge, WireState},
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     system_tables::SystemTables,
+    wiring::{NetId, WiringTable},
 };
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+/// Length of the window in which active modules run without synchronizing.
+/// Wire events between active modules only become visible at the start of the
+/// window after the one they were sent in, so the outcome of a run doesn't
+/// depend on how the module threads were scheduled.
+pub const SYNC_WINDOW: TimeDiff = 100;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InternalEvent {
     pub receiver_id: EventPortAddress,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WireChangeEvent {
     pub receiver_id: PinAddress,
     pub state: WireState,
 }
 
+/// Change of the value a pin drives onto its net, sent to every active
+/// module having a pin on that net.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetChangeEvent {
+    pub driver: PinAddress,
+    pub state: WireState,
+}
+
+/// Contributions of the drivers of a net, as seen by one active module.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct NetState {
+    drivers: Vec<(PinAddress, WireState)>,
+    resolved: Option<WireState>,
+}
+
+/// Pin whose changes stop the simulation.
+#[derive(Debug, Clone)]
+struct PinWatch {
+    id: usize,
+    pin: PinAddress,
+    net: Option<NetId>,
+    edge: Edge,
+    value: bool,
+}
+
+/// Change of a watched pin, see [EventQueue::watch_pin].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinChange {
+    /// Id the watch was given.
+    pub watch: usize,
+    pub t: Timestamp,
+    pub value: bool,
+}
+
+/// Fault on the changes a pin drives onto its net, as another pin sees them,
+/// see [EventQueue::add_link_fault].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinkFault {
+    /// The next changes are lost, this many of them or all of them.
+    Drop(Option<u32>),
+    /// The changes arrive this many cycles late.
+    Delay(TimeDiff),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FaultyLink {
+    id: usize,
+    from: PinAddress,
+    to: PinAddress,
+    fault: LinkFault,
+    /// Delayed values for `to`, with the time they arrive at.
+    delayed: VecDeque<(Timestamp, WireState)>,
+}
+
+impl FaultyLink {
+    /// Applies the fault to a change of the net caused by `from`. Returns
+    /// whether it is kept from `to` for now.
+    fn intercept(&mut self, state: WireState, t: Timestamp) -> bool {
+        match &mut self.fault {
+            LinkFault::Drop(None) => true,
+            LinkFault::Drop(Some(0)) => false,
+            LinkFault::Drop(Some(count)) => {
+                *count -= 1;
+                true
+            }
+            LinkFault::Delay(delay) => {
+                self.delayed.push_back((t + *delay, state));
+                true
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct PinRedirect {
     pub main_pin: PinAddress,
@@ -37,9 +122,34 @@ pub struct EventQueue {
     pub clock: Clock,
     internal_events: PriorityQueue<InternalEvent, Reverse<Timestamp>>,
     wire_events: PriorityQueue<WireChangeEvent, Reverse<Timestamp>>,
-    root_prefix: u8,
-    receiver: Receiver<(WireChangeEvent, Timestamp)>,
+    root_prefix: ModuleIndex,
+    receiver: Receiver<(NetChangeEvent, Timestamp)>,
+    /// Events received from other modules that aren't visible yet.
+    pending_inbox: Vec<(NetChangeEvent, Timestamp)>,
+    inbox_horizon: Timestamp,
+    nets: BTreeMap<NetId, NetState>,
+    pin_watches: Vec<PinWatch>,
+    pin_changes: Vec<PinChange>,
+    /// Values of the nets held whatever their drivers, see
+    /// [EventQueue::force_net].
+    forced_nets: BTreeMap<NetId, WireState>,
+    links: Vec<FaultyLink>,
+    /// Time at which the module asked the simulation to stop.
+    stop: Option<Timestamp>,
+
+    multiplexing_table: MultiplexingTable,
+}
 
+#[derive(Serialize, Deserialize)]
+struct EventQueueState {
+    clock: Clock,
+    internal_events: Vec<(InternalEvent, Timestamp)>,
+    wire_events: Vec<(WireChangeEvent, Timestamp)>,
+    pending_inbox: Vec<(NetChangeEvent, Timestamp)>,
+    inbox_horizon: Timestamp,
+    nets: BTreeMap<NetId, NetState>,
+    forced_nets: BTreeMap<NetId, WireState>,
+    links: Vec<FaultyLink>,
     multiplexing_table: MultiplexingTable,
 }
 
@@ -47,8 +157,8 @@ impl EventQueue {
     pub fn new(
         system_tables: SystemTables,
         ticks_per_cycle: TimeDiff,
-        root_prefix: u8,
-        receiver: Receiver<(WireChangeEvent, Timestamp)>,
+        root_prefix: ModuleIndex,
+        receiver: Receiver<(NetChangeEvent, Timestamp)>,
     ) -> Self {
         Self {
             clock: Clock::new(ticks_per_cycle),
@@ -56,6 +166,14 @@ impl EventQueue {
             wire_events: PriorityQueue::new(),
             root_prefix,
             receiver,
+            pending_inbox: Vec::new(),
+            inbox_horizon: 0,
+            nets: BTreeMap::new(),
+            pin_watches: Vec::new(),
+            pin_changes: Vec::new(),
+            forced_nets: BTreeMap::new(),
+            links: Vec::new(),
+            stop: None,
 
             multiplexing_table: MultiplexingTable::new(),
             system_tables,
@@ -88,55 +206,340 @@ impl EventQueue {
         self.fire_event(event, self.clock.ticks_to_time(ticks));
     }
 
-    #[inline]
     pub fn set_wire(&mut self, writer_pin_address: PinAddress, state: WireState) {
-        // println!("{} -> ? to {:?}", writer_pin_address, state);
-        for reader_id in self.multiplexing_table.outgoing_event_listeners(
-            &self.system_tables.wiring.read().unwrap(),
-            writer_pin_address,
-        ) {
-            // println!("{} -> {} to {:?}", writer_pin_address, reader_id, state);
-
-            if reader_id.module_address.current() == self.root_prefix {
-                for r in self.multiplexing_table.incoming_event_listeners(reader_id) {
-                    let mut e = WireChangeEvent {
-                        receiver_id: r,
-                        state,
-                    };
-                    e.receiver_id.module_address.advance();
-                    self.wire_events.push(e, Reverse(self.clock.current_time()));
-                }
-            } else {
-                let e = WireChangeEvent {
-                    receiver_id: reader_id,
+        let Some((external, internal)) = self.multiplexing_table.route_output(writer_pin_address)
+        else {
+            return;
+        };
+        let t = self.clock.current_time();
+        for mut reader in internal {
+            reader.module_address.advance();
+            self.wire_events.push(
+                WireChangeEvent {
+                    receiver_id: reader,
                     state,
-                };
-                self.system_tables
-                    .inbox
-                    .read()
-                    .unwrap()
-                    .send(e, self.clock.current_time());
+                },
+                Reverse(t),
+            );
+        }
+
+        let wiring = self.system_tables.wiring.clone();
+        let wiring = wiring.read().unwrap();
+        let Some(net) = wiring.net_of(external) else {
+            if !self.pin_watches.is_empty() {
+                self.update_pin_watches(|watch| watch.pin == external, state, t);
+            }
+            return;
+        };
+        let e = NetChangeEvent {
+            driver: external,
+            state,
+        };
+        for &module in &wiring.net(net).modules {
+            if module == self.root_prefix {
+                self.drive_net(&wiring, e, t);
+            } else {
+                self.system_tables.inbox.read().unwrap().send(module, e, t);
             }
         }
     }
 
-    pub fn update(&mut self, root: &mut impl Module) {
-        while let Ok(Some((e, t))) = self.receiver.try_recv() {
-            let readers: SmallVec<[PinAddress; 4]> = self
-                .multiplexing_table
-                .incoming_event_listeners(e.receiver_id)
-                .collect();
-            for mut reader in readers {
+    /// Updates the contribution of a driver to its net. If the resolved value
+    /// of the net changes, it is delivered to every pin of this module on it.
+    fn drive_net(&mut self, wiring: &WiringTable, e: NetChangeEvent, t: Timestamp) {
+        let Some(id) = wiring.net_of(e.driver) else {
+            return;
+        };
+        let net = self.nets.entry(id).or_default();
+        match net.drivers.iter_mut().find(|(pin, _)| *pin == e.driver) {
+            Some((_, state)) => *state = e.state,
+            None => net.drivers.push((e.driver, e.state)),
+        }
+        self.resolve_net(wiring, id, Some(e.driver), t);
+    }
+
+    /// Resolves the value of a net from its drivers, unless it is forced, and
+    /// delivers it to the pins of this module on it if it changed. `driver`
+    /// is the pin whose change caused it, if any.
+    fn resolve_net(
+        &mut self,
+        wiring: &WiringTable,
+        id: NetId,
+        driver: Option<PinAddress>,
+        t: Timestamp,
+    ) {
+        let forced = self.forced_nets.get(&id).copied();
+        let net = self.nets.entry(id).or_default();
+        let resolved = forced
+            .unwrap_or_else(|| WireState::resolve(net.drivers.iter().map(|&(_, state)| state)));
+        if net.resolved == Some(resolved) {
+            return;
+        }
+        net.resolved = Some(resolved);
+        if !self.pin_watches.is_empty() {
+            self.update_pin_watches(|watch| watch.net == Some(id), resolved, t);
+        }
+
+        let net = wiring.net(id);
+        // Every module sees the contention, only the first one reports it.
+        if resolved == WireState::Error && net.modules.first() == Some(&self.root_prefix) {
+            let names = self.system_tables.names.read().unwrap();
+            let drivers = self.nets[&id]
+                .drivers
+                .iter()
+                .filter(|(_, state)| *state != WireState::Z)
+                .map(|&(pin, state)| format!("{} = {:?}", names.describe_pin(pin), state))
+                .collect::<Vec<_>>()
+                .join(", ");
+            drop(names);
+            self.add_message(format!(
+                "{}: bus contention on net {} ({})",
+                t, net.name, drivers
+            ));
+        }
+        for &pin in &net.pins {
+            if pin.module_address.current() != self.root_prefix {
+                continue;
+            }
+            let link = self
+                .links
+                .iter_mut()
+                .find(|link| link.to == pin && Some(link.from) == driver);
+            if link.is_some_and(|link| link.intercept(resolved, t)) {
+                continue;
+            }
+            for mut reader in self.multiplexing_table.incoming_event_listeners(pin) {
                 reader.module_address.advance();
                 self.wire_events.push(
                     WireChangeEvent {
                         receiver_id: reader,
-                        state: e.state,
+                        state: resolved,
                     },
                     Reverse(t),
                 );
             }
         }
+    }
+
+    /// Asks the simulation to stop at `t`. The module returns from
+    /// [run_until_time](crate::module::ActiveModule::run_until_time) early.
+    pub fn request_stop(&mut self, t: Timestamp) {
+        self.stop = Some(self.stop.map_or(t, |stop| stop.min(t)));
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.stop.is_some()
+    }
+
+    pub fn take_stop(&mut self) -> Option<Timestamp> {
+        self.stop.take()
+    }
+
+    /// Requests a stop when `pin`, one of this module's, has an `edge`. The
+    /// change is then recorded with the watch `id`. `value` is the current
+    /// value of the pin.
+    pub fn watch_pin(&mut self, id: usize, pin: PinAddress, edge: Edge, value: bool) {
+        let net = self.system_tables.wiring.read().unwrap().net_of(pin);
+        self.pin_watches.push(PinWatch {
+            id,
+            pin,
+            net,
+            edge,
+            value,
+        });
+    }
+
+    /// Removes every watch, and returns the changes they recorded.
+    pub fn unwatch_pins(&mut self) -> Vec<PinChange> {
+        self.pin_watches.clear();
+        std::mem::take(&mut self.pin_changes)
+    }
+
+    fn update_pin_watches(
+        &mut self,
+        affected: impl Fn(&PinWatch) -> bool,
+        state: WireState,
+        t: Timestamp,
+    ) {
+        let value = state.to_bool();
+        let mut hit = false;
+        for watch in self.pin_watches.iter_mut().filter(|watch| affected(watch)) {
+            if watch.edge.matches(watch.value, value) {
+                self.pin_changes.push(PinChange {
+                    watch: watch.id,
+                    t,
+                    value,
+                });
+                hit = true;
+            }
+            watch.value = value;
+        }
+        // Changes sent by other modules are only seen from the window after
+        // the one they were sent in: they are recorded at the time they
+        // happened, but the module can only stop now, or at the start of the
+        // window if its clock is behind, like when it is halted.
+        if hit {
+            let now = self.clock.current_time().max(self.inbox_horizon);
+            self.request_stop(t.max(now));
+        }
+    }
+
+    /// Holds the net `net` at `state` from `t`, whatever its drivers, or lets
+    /// them drive it again with `None`.
+    pub fn force_net(&mut self, net: NetId, state: Option<WireState>, t: Timestamp) {
+        match state {
+            Some(state) => self.forced_nets.insert(net, state),
+            None => self.forced_nets.remove(&net),
+        };
+        let wiring = self.system_tables.wiring.clone();
+        let wiring = wiring.read().unwrap();
+        self.resolve_net(&wiring, net, None, t);
+    }
+
+    /// Applies `fault` to the changes that `from` drives onto its net, as
+    /// `to`, a pin of this module on the same net, sees them. The fault is
+    /// recorded with the id `id`.
+    pub fn add_link_fault(
+        &mut self,
+        id: usize,
+        from: PinAddress,
+        to: PinAddress,
+        fault: LinkFault,
+    ) {
+        self.links.push(FaultyLink {
+            id,
+            from,
+            to,
+            fault,
+            delayed: VecDeque::new(),
+        });
+    }
+
+    /// Removes a link fault. Its pin gets the value of the net at `t`, the
+    /// changes still delayed are dropped.
+    pub fn remove_link_fault(&mut self, id: usize, t: Timestamp) -> bool {
+        let Some(i) = self.links.iter().position(|link| link.id == id) else {
+            return false;
+        };
+        let link = self.links.remove(i);
+        if let Some(state) = self.net_state(link.to) {
+            let e = WireChangeEvent {
+                receiver_id: link.to,
+                state,
+            };
+            self.deliver(e, t);
+        }
+        true
+    }
+
+    /// Link with the earliest delayed change, and the time it arrives at.
+    fn next_delayed(&self) -> Option<(usize, Timestamp)> {
+        self.links
+            .iter()
+            .enumerate()
+            .filter_map(|(i, link)| link.delayed.front().map(|&(t, _)| (i, t)))
+            .min_by_key(|&(_, t)| t)
+    }
+
+    /// Resolved value of the net `pin` is on, as currently seen by this module.
+    pub fn net_state(&self, pin: PinAddress) -> Option<WireState> {
+        let id = self.system_tables.wiring.read().unwrap().net_of(pin)?;
+        self.nets.get(&id)?.resolved
+    }
+
+    /// Starts a synchronization window ending at `goal`. Only the events
+    /// sent before the window started can be received during it.
+    pub fn begin_window(&mut self, goal: Timestamp) {
+        self.inbox_horizon = (goal - 1).div_euclid(SYNC_WINDOW) * SYNC_WINDOW;
+    }
+
+    /// Delivers an event addressed to a pin of this module right away. Used
+    /// for inputs coming from outside of the simulation, while it is stopped.
+    pub fn deliver(&mut self, e: WireChangeEvent, t: Timestamp) {
+        let readers: SmallVec<[PinAddress; 4]> = self
+            .multiplexing_table
+            .incoming_event_listeners(e.receiver_id)
+            .collect();
+        for mut reader in readers {
+            reader.module_address.advance();
+            self.wire_events.push(
+                WireChangeEvent {
+                    receiver_id: reader,
+                    state: e.state,
+                },
+                Reverse(t),
+            );
+        }
+    }
+
+    fn receive_inbox(&mut self) {
+        while let Ok(Some(event)) = self.receiver.try_recv() {
+            self.pending_inbox.push(event);
+        }
+        if self.pending_inbox.is_empty() {
+            return;
+        }
+        let horizon = self.inbox_horizon;
+        let (visible, pending): (Vec<_>, _) = std::mem::take(&mut self.pending_inbox)
+            .into_iter()
+            .partition(|&(_, t)| t < horizon);
+        self.pending_inbox = pending;
+        if visible.is_empty() {
+            return;
+        }
+        let wiring = self.system_tables.wiring.clone();
+        let wiring = wiring.read().unwrap();
+        for (e, t) in visible {
+            self.drive_net(&wiring, e, t);
+        }
+    }
+
+    /// Saves the clock and every pending event, including the ones that other
+    /// modules have already sent to this queue's inbox.
+    pub fn save_state(&mut self, state: &mut StateWriter) {
+        self.receive_inbox();
+        state.write(&EventQueueState {
+            clock: self.clock,
+            internal_events: self
+                .internal_events
+                .iter()
+                .map(|(e, t)| (*e, t.0))
+                .collect(),
+            wire_events: self.wire_events.iter().map(|(e, t)| (*e, t.0)).collect(),
+            pending_inbox: self.pending_inbox.clone(),
+            inbox_horizon: self.inbox_horizon,
+            nets: self.nets.clone(),
+            forced_nets: self.forced_nets.clone(),
+            links: self.links.clone(),
+            multiplexing_table: self.multiplexing_table.clone(),
+        });
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        while let Ok(Some(_)) = self.receiver.try_recv() {}
+        let saved: EventQueueState = state.read()?;
+        self.clock = saved.clock;
+        self.internal_events = saved
+            .internal_events
+            .into_iter()
+            .map(|(e, t)| (e, Reverse(t)))
+            .collect();
+        self.wire_events = saved
+            .wire_events
+            .into_iter()
+            .map(|(e, t)| (e, Reverse(t)))
+            .collect();
+        self.pending_inbox = saved.pending_inbox;
+        self.inbox_horizon = saved.inbox_horizon;
+        self.nets = saved.nets;
+        self.forced_nets = saved.forced_nets;
+        self.links = saved.links;
+        self.multiplexing_table = saved.multiplexing_table;
+        Ok(())
+    }
+
+    pub fn update(&mut self, root: &mut impl Module) {
+        self.receive_inbox();
         loop {
             if let Some((&e, &Reverse(t))) = self.internal_events.peek() {
                 if t <= self.clock.current_time() {
@@ -145,7 +548,10 @@ impl EventQueue {
                     if let Some(m) = m {
                         m.handle_event(e, self, t);
                     } else {
-                        panic!("Module not found: {:?}", e.receiver_id);
+                        panic!(
+                            "Module not found: {}",
+                            self.describe(e.receiver_id.module_address)
+                        );
                     }
                     continue;
                 }
@@ -162,14 +568,32 @@ impl EventQueue {
                             // println!("{}, {}. {}", self.root_prefix, root_addr, m.address());
                             m.set_pin(self, e.receiver_id.pin_id as PinId, e.state);
                         } else {
-                            panic!("Module not wireable: {:?}", e.receiver_id);
+                            panic!(
+                                "Module not wireable: {}",
+                                self.describe(e.receiver_id.module_address)
+                            );
                         }
                     } else {
-                        panic!("Module not found: {:?}", e.receiver_id);
+                        panic!(
+                            "Module not found: {}",
+                            self.describe(e.receiver_id.module_address)
+                        );
                     }
                     continue;
                 }
             }
+            if let Some((i, t)) = self.next_delayed() {
+                if t <= self.clock.current_time() {
+                    let link = &mut self.links[i];
+                    let (_, state) = link.delayed.pop_front().unwrap();
+                    let e = WireChangeEvent {
+                        receiver_id: link.to,
+                        state,
+                    };
+                    self.deliver(e, t);
+                    continue;
+                }
+            }
             break;
         }
     }
@@ -178,6 +602,10 @@ impl EventQueue {
         self.multiplexing_table.register(main_pin, alternatives)
     }
 
+    pub fn is_multiplexed(&self, pin: PinAddress) -> bool {
+        self.multiplexing_table.is_registered(pin)
+    }
+
     pub fn set_multiplexer_flag(&mut self, pin: PinAddress, flag: bool) {
         self.multiplexing_table.set_flag(pin, flag)
     }
@@ -187,32 +615,148 @@ impl EventQueue {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.internal_events.is_empty() && self.wire_events.is_empty()
+        self.internal_events.is_empty()
+            && self.wire_events.is_empty()
+            && self.next_delayed().is_none()
+    }
+
+    fn peek_event_time(&self) -> Option<Timestamp> {
+        let t1 = self.wire_events.peek().map(|(_, &Reverse(t))| t);
+        let t2 = self.internal_events.peek().map(|(_, &Reverse(t))| t);
+        let t3 = self.next_delayed().map(|(_, t)| t);
+        [t1, t2, t3].into_iter().flatten().min()
+    }
+
+    /// Time of the earliest pending event, including the ones still waiting in the inbox.
+    pub fn next_event_time(&mut self) -> Option<Timestamp> {
+        self.receive_inbox();
+        let pending = self.pending_inbox.iter().map(|&(_, t)| t).min();
+        match (self.peek_event_time(), pending) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        }
     }
 
     pub fn skip_to_event(&mut self, max_t: i64) {
-        let t1 = self
-            .wire_events
-            .peek()
-            .map(|(_, &Reverse(t))| t)
-            .filter(|&t| t < max_t);
-        let t2 = self
-            .internal_events
-            .peek()
-            .map(|(_, &Reverse(t))| t)
-            .filter(|&t| t < max_t);
-        let t = match (t1, t2) {
-            (None, None) => max_t,
-            (None, Some(x)) => x,
-            (Some(x), None) => x,
-            (Some(x), Some(y)) => x.min(y),
-        };
+        let t = self
+            .peek_event_time()
+            .filter(|&t| t < max_t)
+            .unwrap_or(max_t);
 
         let ticks = self.clock.time_to_ticks(t) - self.clock.current_tick();
         self.clock.advance(ticks);
     }
 
+    /// Name of a module of this queue, whose address has the root prefix
+    /// already consumed.
+    fn describe(&self, mut address: ModuleAddress) -> String {
+        address.retreat();
+        self.system_tables.names.read().unwrap().describe(address)
+    }
+
     pub fn add_message(&self, msg: String) {
         self.system_tables.messages.write().unwrap().push(msg);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pin_state::Edge,
+        test_util::{PB7_HIGH, PB7_LOW},
+        System, SystemBuilder,
+    };
+
+    #[test]
+    fn bus_contention() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("a", &PB7_HIGH)
+            .mcu_with_flash("b", &PB7_LOW)
+            .led("b", "led")
+            .wire("a:PB7", "b:PB7")
+            .wire("b.led:0", "a:PB7")
+            .build()
+            .unwrap();
+        sys.run_for(300);
+        // Both modules see the contention, only one of them reports it.
+        assert_eq!(sys.read_pin("b.led:0"), Ok(WireState::Error));
+        let messages = sys.system_tables.messages.read().unwrap().clone();
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        assert!(
+            messages[0].ends_with("bus contention on net wires[0] (a:15 = High, b:15 = Low)"),
+            "{}",
+            messages[0]
+        );
+    }
+
+    #[test]
+    fn cross_module_edges() {
+        let build = || {
+            SystemBuilder::new()
+                .mcu_with_flash("a", &PB7_HIGH)
+                .mcu_with_flash("b", &[0x0000, 0xCFFF])
+                .wire("a:PB7", "b:PB0")
+                .build()
+                .unwrap()
+        };
+        let mut sys = build();
+        let pb7 = sys.pin_address("a:PB7").unwrap();
+        let edge = sys.run_until_edge(pb7, Edge::Rising, 1000).unwrap();
+        assert_eq!(sys.t, edge);
+
+        // Seen from the other MCU, the edge is at the same time, but the
+        // simulation stops when it gets there, in the next window.
+        let mut sys = build();
+        let pb0 = sys.pin_address("b:PB0").unwrap();
+        assert_eq!(sys.run_until_edge(pb0, Edge::Rising, 1000), Some(edge));
+        assert!(
+            (SYNC_WINDOW..=SYNC_WINDOW + 4).contains(&sys.t),
+            "{}",
+            sys.t
+        );
+    }
+
+    #[test]
+    fn wire_faults() {
+        let build = || {
+            SystemBuilder::new()
+                .mcu_with_flash("mcu", &PB7_LOW)
+                .wire("mcu:PB7", "mcu:PB0")
+                .build()
+                .unwrap()
+        };
+        // PB0 reads high until it sees PB7 driving it low.
+        let pinb0 = |sys: &mut System| sys.read_memory("mcu", 0x23).unwrap() & 1;
+
+        let mut sys = build();
+        let pb7 = sys.pin_address("mcu:PB7").unwrap();
+        let pb0 = sys.pin_address("mcu:PB0").unwrap();
+        sys.add_link_fault(pb7, pb0, LinkFault::Delay(20)).unwrap();
+        sys.run_for(10);
+        assert_eq!(pinb0(&mut sys), 1);
+        sys.run_for(20);
+        assert_eq!(pinb0(&mut sys), 0);
+
+        // Dropped changes are caught up with when the fault is removed.
+        let mut sys = build();
+        let drop = sys.add_link_fault(pb7, pb0, LinkFault::Drop(None)).unwrap();
+        sys.run_for(100);
+        assert_eq!(pinb0(&mut sys), 1);
+        assert!(sys.remove_fault(drop));
+        assert!(!sys.remove_fault(drop));
+        sys.run_for(10);
+        assert_eq!(pinb0(&mut sys), 0);
+
+        let mut sys = build();
+        sys.stick_pin(pb0, WireState::High, Some(50)).unwrap();
+        sys.run_for(10);
+        assert_eq!(pinb0(&mut sys), 1);
+        assert_eq!(sys.read_pin("mcu:PB7"), Ok(WireState::High));
+        sys.run_for(100);
+        assert_eq!(pinb0(&mut sys), 0);
+        assert_eq!(sys.read_pin("mcu:PB7"), Ok(WireState::Low));
+        let pc0 = sys.pin_address("mcu:PC0").unwrap();
+        assert!(sys.stick_pin(pc0, WireState::High, None).is_err());
+    }
+}
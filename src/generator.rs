@@ -0,0 +1,167 @@
+//! Periodic signals driven on pins from outside of the simulation, like a
+//! clock. Their edges only depend on the time, so the system applies them
+//! at the right cycle during a run, and again the same way when it replays
+//! its history.
+
+use crate::{
+    clock::{TimeDiff, Timestamp},
+    module_id::PinAddress,
+    pin_state::WireState,
+};
+
+/// Pulses on a pin: high for `width` cycles every `period` cycles, from
+/// `start`. Both can be fractional, the edges are rounded to the closest
+/// cycle.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    pin: PinAddress,
+    start: Timestamp,
+    period: f64,
+    width: f64,
+    count: Option<u64>,
+    /// Time from which the generator is stopped.
+    end: Option<Timestamp>,
+    /// Index of the next edge. Rising edges are even, falling ones odd.
+    next: u64,
+}
+
+impl Generator {
+    /// `count` pulses, or pulses forever. The edges must be at least a cycle
+    /// apart.
+    pub fn new(
+        pin: PinAddress,
+        start: Timestamp,
+        width: f64,
+        period: f64,
+        count: Option<u64>,
+    ) -> Result<Self, String> {
+        if width < 1.0 {
+            return Err(format!("pulse width {} is less than a cycle", width));
+        }
+        if count != Some(1) && period - width < 1.0 {
+            return Err(format!(
+                "period {} leaves less than a cycle between pulses of width {}",
+                period, width
+            ));
+        }
+        Ok(Generator {
+            pin,
+            start,
+            period,
+            width,
+            count,
+            end: None,
+            next: 0,
+        })
+    }
+
+    /// Square wave of `period` cycles, rising at `start`.
+    pub fn clock(pin: PinAddress, start: Timestamp, period: f64) -> Result<Self, String> {
+        Self::new(pin, start, period / 2.0, period, None)
+    }
+
+    pub fn pin(&self) -> PinAddress {
+        self.pin
+    }
+
+    fn edge_time(&self, edge: u64) -> Timestamp {
+        let mut offset = (edge / 2) as f64 * self.period;
+        if edge % 2 == 1 {
+            offset += self.width;
+        }
+        self.start + offset.round() as TimeDiff
+    }
+
+    /// Time and value of the next edge, if there is one.
+    pub fn next_edge(&self) -> Option<(Timestamp, WireState)> {
+        if self.count.is_some_and(|count| self.next >= 2 * count) {
+            return None;
+        }
+        let t = self.edge_time(self.next);
+        if self.end.is_some_and(|end| t >= end) {
+            return None;
+        }
+        Some((t, WireState::from_bool(self.next.is_multiple_of(2))))
+    }
+
+    pub fn advance(&mut self) {
+        self.next += 1;
+    }
+
+    /// Moves to the first edge at or after `t`.
+    pub fn seek(&mut self, t: Timestamp) {
+        let pulses = ((t - self.start) as f64 / self.period).floor().max(1.0) as u64;
+        self.next = 2 * (pulses - 1);
+        while self.edge_time(self.next) < t {
+            self.next += 1;
+        }
+    }
+
+    /// Stops the generator at `t`, leaving the pin as it is. The edges
+    /// before are kept, for the history.
+    pub fn stop_at(&mut self, t: Timestamp) {
+        self.end = Some(t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module_id::ModuleAddress;
+
+    fn edges(generator: &mut Generator, n: usize) -> Vec<(Timestamp, WireState)> {
+        let mut edges = Vec::new();
+        while let Some(edge) = generator.next_edge() {
+            if edges.len() == n {
+                break;
+            }
+            edges.push(edge);
+            generator.advance();
+        }
+        edges
+    }
+
+    #[test]
+    fn clock_edges() {
+        let pin = ModuleAddress::root().child_id(0).with_pin(0);
+        // 16 MHz / 38 kHz is about 421.05 cycles.
+        let mut clock = Generator::clock(pin, 10, 16e6 / 38e3).unwrap();
+        assert_eq!(
+            edges(&mut clock, 5),
+            [
+                (10, WireState::High),
+                (221, WireState::Low),
+                (431, WireState::High),
+                (642, WireState::Low),
+                (852, WireState::High),
+            ]
+        );
+        clock.seek(431);
+        assert_eq!(clock.next_edge(), Some((431, WireState::High)));
+        clock.seek(432);
+        assert_eq!(clock.next_edge(), Some((642, WireState::Low)));
+        clock.seek(0);
+        assert_eq!(clock.next_edge(), Some((10, WireState::High)));
+        clock.stop_at(642);
+        clock.seek(500);
+        assert_eq!(clock.next_edge(), None);
+    }
+
+    #[test]
+    fn pulse_count() {
+        let pin = ModuleAddress::root().child_id(0).with_pin(0);
+        let mut pulses = Generator::new(pin, 0, 3.0, 10.0, Some(2)).unwrap();
+        assert_eq!(
+            edges(&mut pulses, 10),
+            [
+                (0, WireState::High),
+                (3, WireState::Low),
+                (10, WireState::High),
+                (13, WireState::Low),
+            ]
+        );
+        assert!(Generator::new(pin, 0, 0.5, 10.0, None).is_err());
+        assert!(Generator::new(pin, 0, 9.5, 10.0, None).is_err());
+        assert!(Generator::new(pin, 0, 9.5, 0.0, Some(1)).is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt::Display;
+
+use crate::{
+    clock::{TimeDiff, Timestamp},
+    components::avr::mcu::ResetCause,
+    events::{LinkFault, WireChangeEvent},
+    module_id::PinAddress,
+    pin_state::WireState,
+    snapshot::{SnapshotError, SystemSnapshot},
+    wiring::NetId,
+};
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Disabled,
+    /// The requested time lies before the first checkpoint.
+    OutOfRange(Timestamp),
+    InvalidTarget(String),
+    Snapshot(SnapshotError),
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryError::Disabled => write!(f, "history recording is not enabled"),
+            HistoryError::OutOfRange(t) => write!(f, "no history recorded for cycle {}", t),
+            HistoryError::InvalidTarget(msg) => write!(f, "{}", msg),
+            HistoryError::Snapshot(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<SnapshotError> for HistoryError {
+    fn from(err: SnapshotError) -> Self {
+        HistoryError::Snapshot(err)
+    }
+}
+
+/// Action on a [System](crate::system::System) from outside of the
+/// simulation, recorded to be replayed. The faults keep the id they were
+/// given, so that scripts can still remove them after a replay.
+#[derive(Debug, Clone)]
+pub enum Input {
+    /// A pin driven, see [System::set_wire](crate::system::System::set_wire).
+    Wire(WireChangeEvent),
+    /// A byte of the data space of an MCU written.
+    Memory {
+        mcu: String,
+        address: u16,
+        value: u8,
+    },
+    /// A CPU register of an MCU written.
+    Register { mcu: String, index: u16, value: u8 },
+    /// A word of the flash of an MCU written.
+    Flash {
+        mcu: String,
+        address: u32,
+        value: u16,
+    },
+    /// The program counter of an MCU moved.
+    Pc { mcu: String, pc: u32 },
+    /// The stack pointer of an MCU set.
+    Sp { mcu: String, sp: u16 },
+    /// The status register of an MCU set.
+    Sreg { mcu: String, sreg: u8 },
+    /// Characters queued on a UART console, to be sent to its RX pin.
+    UartSend { uart: String, chars: Vec<u16> },
+    /// A net held at a value until `end`, if any.
+    StickNet {
+        id: usize,
+        net: NetId,
+        state: WireState,
+        end: Option<Timestamp>,
+    },
+    /// A fault on the changes `from` drives, as `to` sees them.
+    Link {
+        id: usize,
+        from: PinAddress,
+        to: PinAddress,
+        fault: LinkFault,
+    },
+    /// An MCU held in reset until `end`, if any.
+    Reset {
+        id: usize,
+        mcu: String,
+        cause: ResetCause,
+        end: Option<Timestamp>,
+    },
+    /// A fault removed before its end.
+    RemoveFault(usize),
+}
+
+/// Timeline of a [System](crate::system::System): checkpoints taken every
+/// `interval` cycles plus the inputs applied from outside of the simulation.
+/// Since runs are deterministic, any point of the timeline can be reproduced
+/// by restoring the closest earlier checkpoint and replaying the inputs.
+#[derive(Debug)]
+pub struct History {
+    interval: TimeDiff,
+    checkpoints: Vec<SystemSnapshot>,
+    inputs: Vec<(Timestamp, Input)>,
+    /// Index of the first input that hasn't been applied yet.
+    next_input: usize,
+}
+
+impl History {
+    pub fn new(interval: TimeDiff, start: SystemSnapshot) -> Self {
+        assert!(interval > 0, "Checkpoint interval must be positive");
+        Self {
+            interval,
+            checkpoints: vec![start],
+            inputs: Vec::new(),
+            next_input: 0,
+        }
+    }
+
+    pub fn interval(&self) -> TimeDiff {
+        self.interval
+    }
+
+    pub fn start_time(&self) -> Timestamp {
+        self.checkpoints[0].t
+    }
+
+    pub fn checkpoint_times(&self) -> impl DoubleEndedIterator<Item = Timestamp> + '_ {
+        self.checkpoints.iter().map(|c| c.t)
+    }
+
+    /// The latest checkpoint at or before `t`.
+    pub fn checkpoint_before(&self, t: Timestamp) -> Option<&SystemSnapshot> {
+        self.checkpoints.iter().rev().find(|c| c.t <= t)
+    }
+
+    pub fn is_checkpoint_due(&self, t: Timestamp) -> bool {
+        t % self.interval == 0 && self.checkpoints.last().is_none_or(|c| c.t < t)
+    }
+
+    pub fn add_checkpoint(&mut self, snapshot: SystemSnapshot) {
+        self.checkpoints.push(snapshot);
+    }
+
+    /// Time at which a run starting at `t` has to stop, either to take a
+    /// checkpoint or to apply a recorded input.
+    pub fn next_stop(&self, t: Timestamp) -> Timestamp {
+        let checkpoint = (t.div_euclid(self.interval) + 1) * self.interval;
+        match self.inputs.get(self.next_input) {
+            Some(&(input_t, _)) if input_t > t => checkpoint.min(input_t),
+            _ => checkpoint,
+        }
+    }
+
+    /// Returns the recorded inputs due at `t` that haven't been applied yet.
+    pub fn take_due_inputs(&mut self, t: Timestamp) -> Vec<Input> {
+        let mut due = Vec::new();
+        while let Some((input_t, input)) = self.inputs.get(self.next_input) {
+            if *input_t > t {
+                break;
+            }
+            due.push(input.clone());
+            self.next_input += 1;
+        }
+        due
+    }
+
+    /// Rewinds the input replay to the checkpoint the system was restored to.
+    pub fn rewind_inputs(&mut self, t: Timestamp) {
+        self.next_input = self.inputs.partition_point(|&(input_t, _)| input_t < t);
+    }
+
+    /// Records a new input at `t`. The future recorded after `t` doesn't
+    /// happen anymore, so it is dropped.
+    pub fn record_input(&mut self, t: Timestamp, input: Input) {
+        self.inputs.truncate(self.next_input);
+        self.checkpoints.retain(|c| c.t <= t);
+        self.inputs.push((t, input));
+        self.next_input = self.inputs.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{System, SystemBuilder};
+
+    #[test]
+    fn time_travel() {
+        // Reads PINB into r16, forever.
+        const READ_PINB: [u16; 3] = [
+            0x0000, // nop
+            0xB103, // in r16, PINB
+            0xCFFE, // rjmp .-4
+        ];
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &READ_PINB)
+            .build()
+            .unwrap();
+        let pb0 = sys.pin_address("mcu:PB0").unwrap();
+        let r16 = |sys: &mut System| sys.read_memory("mcu", 16).unwrap();
+        assert!(matches!(sys.travel_to(0), Err(HistoryError::Disabled)));
+        sys.enable_history(100);
+        // The inputs are set like Lua's `set_wire` does, between runs.
+        sys.run_for(150);
+        sys.set_wire(pb0, WireState::High);
+        sys.run_for(200);
+        sys.set_wire(pb0, WireState::Low);
+        sys.run_for(200);
+        sys.set_wire(pb0, WireState::High);
+        sys.run_for(100);
+        assert_eq!(r16(&mut sys), 0x01);
+
+        let change = sys.last_change("mcu", 16).unwrap().unwrap();
+        assert!((550..560).contains(&change.t), "{}", change.t);
+        assert_eq!((change.old, change.new), (0x00, 0x01));
+        assert_eq!(sys.t, 650);
+
+        sys.travel_to(400).unwrap();
+        assert_eq!((sys.t, r16(&mut sys)), (400, 0x00));
+        sys.step_back(100).unwrap();
+        assert_eq!((sys.t, r16(&mut sys)), (300, 0x01));
+        // The recorded inputs are replayed when running again.
+        sys.run_for(350);
+        assert_eq!((sys.t, r16(&mut sys)), (650, 0x01));
+        assert_eq!(sys.last_change("mcu", 16).unwrap(), Some(change));
+        sys.travel_to(500).unwrap();
+        assert_eq!(r16(&mut sys), 0x00);
+        assert!(matches!(
+            sys.travel_to(-10),
+            Err(HistoryError::OutOfRange(-10))
+        ));
+    }
+}
@@ -0,0 +1,46 @@
+//! Simulator of systems of AVR microcontrollers and the components wired to
+//! them.
+//!
+//! A [System] is either loaded from a config file with [load], or put
+//! together in Rust code with a [SystemBuilder]:
+//!
+//! ```no_run
+//! use amber::{pin_state::WireState, SystemBuilder};
+//!
+//! let mut sys = SystemBuilder::new()
+//!     .mcu("mcu", "blink.hex")
+//!     .led("mcu", "led")
+//!     .wire("mcu:PB7", "mcu.led:0")
+//!     .build()
+//!     .unwrap();
+//! sys.run_for(16_000);
+//! assert_eq!(sys.read_pin("mcu.led:0"), Ok(WireState::High));
+//! ```
+
+pub mod builder;
+pub mod clock;
+pub mod components;
+pub mod control;
+pub mod events;
+pub mod generator;
+pub mod history;
+pub mod lua;
+pub mod module;
+pub mod module_holder;
+pub mod module_id;
+pub mod multiplexer;
+pub mod parser;
+pub mod pin_state;
+pub mod registry;
+pub mod snapshot;
+pub mod system;
+pub mod system_tables;
+pub mod test_report;
+#[cfg(test)]
+mod test_util;
+pub mod vcd;
+pub mod wiring;
+
+pub use builder::SystemBuilder;
+pub use parser::{load, ConfigError, ConfigErrors, LoadOptions};
+pub use system::System;
@@ -1,45 +1,312 @@
 use std::{
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
-use mlua::Lua;
+use mlua::{FromLua, Function, IntoLua, Lua, Table, UserData, UserDataRef, Value};
 
 use crate::{
-    events::WireChangeEvent,
-    parser::{self},
-    pin_state::WireState,
-    system::System,
+    clock::{TimeDiff, Timestamp},
+    components::{
+        avr::mcu::{Breakpoint, BreakpointHit, Mcu, ResetCause},
+        uart_module::UartModule,
+    },
+    events::LinkFault,
+    generator::Generator,
+    parser::{self, LoadOptions},
+    pin_state::{Edge, WireState},
+    snapshot::SystemSnapshot,
+    system::{self, System},
 };
 
+impl UserData for SystemSnapshot {}
+
+/// Name of the registry table with the `on_change` callbacks, by watch id.
+const CALLBACKS: &str = "amber.on_change";
+
+/// Runs the simulation with `run`, given the cycles left, until it returns a
+/// result or `deadline` is reached. In between, the `on_change` callbacks are
+/// called at the time of the changes they watch.
+fn run_with_callbacks<T>(
+    lua: &Lua,
+    sys: &Mutex<System>,
+    deadline: Timestamp,
+    mut run: impl FnMut(&mut System, TimeDiff) -> mlua::Result<Option<T>>,
+) -> mlua::Result<Option<T>> {
+    loop {
+        // The lock is released for the callbacks, since they use it too.
+        let (result, changes, t) = {
+            let mut sys = sys.lock().unwrap();
+            let left = deadline - sys.t;
+            let result = run(&mut sys, left)?;
+            (result, sys.take_pin_changes(), sys.t)
+        };
+        if !changes.is_empty() {
+            let callbacks: Table = lua.named_registry_value(CALLBACKS)?;
+            for change in changes {
+                if let Some(callback) = callbacks.get::<_, Option<Function>>(change.watch)? {
+                    callback.call::<_, ()>((change.t, change.value))?;
+                }
+            }
+        }
+        if result.is_some() || t >= deadline {
+            return Ok(result);
+        }
+    }
+}
+
+/// Table describing why the simulation stopped at a breakpoint.
+fn breakpoint_reason<'lua>(
+    lua: &'lua Lua,
+    sys: &System,
+    hit: &BreakpointHit,
+) -> mlua::Result<Table<'lua>> {
+    let reason = lua.create_table()?;
+    reason.set("id", hit.id)?;
+    if let Some((mcu, breakpoint)) = sys.breakpoint(hit.id) {
+        reason.set("mcu", mcu)?;
+        let (kind, address) = match breakpoint {
+            Breakpoint::Pc(pc) => ("pc", pc),
+            Breakpoint::Read(address) => ("read", address as u32),
+            Breakpoint::Write(address) => ("write", address as u32),
+            Breakpoint::Access(address) => ("access", address as u32),
+        };
+        reason.set("type", kind)?;
+        reason.set("address", address)?;
+    }
+    reason.set("pc", hit.pc)?;
+    reason.set("value", hit.value)?;
+    reason.set("t", hit.t)?;
+    Ok(reason)
+}
+
+/// Runs the simulation up to `deadline`, calling the `on_change` callbacks.
+/// Stops early at the first breakpoint hit, and returns why.
+fn execute_until<'lua>(
+    lua: &'lua Lua,
+    sys: &Mutex<System>,
+    deadline: Timestamp,
+) -> mlua::Result<Option<Table<'lua>>> {
+    {
+        let mut sys = sys.lock().unwrap();
+        if deadline <= sys.t {
+            return Ok(None);
+        }
+        // Hits of runs that didn't report them, like `wait_for_pc`.
+        sys.take_breakpoint_hits();
+    }
+    run_with_callbacks(lua, sys, deadline, |sys, left| {
+        sys.run_until_change(left);
+        match sys.take_breakpoint_hits().first() {
+            Some(hit) => breakpoint_reason(lua, sys, hit).map(Some),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Number of cycles in `duration` units of `1 / per_second` seconds.
+fn cycles_in(sys: &Mutex<System>, duration: f64, per_second: f64) -> TimeDiff {
+    (duration * sys.lock().unwrap().freq as f64 / per_second).round() as TimeDiff
+}
+
 fn load_execute(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
-    let execute_fn = lua.create_function(move |_, cycles: i64| {
-        sys.lock().unwrap().run_for(cycles);
-        Ok(())
+    let sys_ref = sys.clone();
+    let execute_fn = lua.create_function(move |lua, cycles: i64| {
+        let deadline = sys_ref.lock().unwrap().t + cycles;
+        execute_until(lua, &sys_ref, deadline)
+    })?;
+    lua.globals().set("execute", execute_fn)?;
+
+    let sys_ref = sys.clone();
+    let execute_ms_fn = lua.create_function(move |lua, ms: f64| {
+        let cycles = cycles_in(&sys_ref, ms, 1e3);
+        let deadline = sys_ref.lock().unwrap().t + cycles;
+        execute_until(lua, &sys_ref, deadline)
+    })?;
+    lua.globals().set("execute_ms", execute_ms_fn)?;
+
+    let sys_ref = sys.clone();
+    let execute_us_fn = lua.create_function(move |lua, us: f64| {
+        let cycles = cycles_in(&sys_ref, us, 1e6);
+        let deadline = sys_ref.lock().unwrap().t + cycles;
+        execute_until(lua, &sys_ref, deadline)
+    })?;
+    lua.globals().set("execute_us", execute_us_fn)?;
+
+    // Runs up to the cycle `t`, if it is still ahead.
+    let sys_ref = sys.clone();
+    let execute_until_fn =
+        lua.create_function(move |lua, t: Timestamp| execute_until(lua, &sys_ref, t))?;
+    lua.globals().set("execute_until", execute_until_fn)?;
+
+    // Time of the simulation, in cycles and in seconds.
+    let sys_ref = sys.clone();
+    let now_fn = lua.create_function(move |_, ()| {
+        let sys = sys_ref.lock().unwrap();
+        Ok((sys.t, sys.t as f64 / sys.freq as f64))
+    })?;
+    lua.globals().set("now", now_fn)?;
+
+    // Frequency of the clock from the config, in Hz.
+    let frequency_fn = lua.create_function(move |_, ()| Ok(sys.lock().unwrap().freq))?;
+    lua.globals().set("frequency", frequency_fn)
+}
+
+/// Time at which a timeout given in cycles expires, or the end of time.
+fn deadline(sys: &Mutex<System>, timeout: Option<TimeDiff>) -> Timestamp {
+    let t = sys.lock().unwrap().t;
+    timeout.map_or(Timestamp::MAX, |timeout| t + timeout)
+}
+
+fn parse_edge(edge: Option<String>) -> mlua::Result<Edge> {
+    edge.as_deref()
+        .unwrap_or("any")
+        .parse()
+        .map_err(mlua::Error::RuntimeError)
+}
+
+fn load_waits(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    // Returns the time of the edge. When the pin is driven by another MCU,
+    // the simulation goes on for up to a sync window after it.
+    let sys_ref = sys.clone();
+    let wait_for_edge_fn = lua.create_function(
+        move |lua, (pin, edge, timeout): (String, Option<String>, Option<TimeDiff>)| {
+            let edge = parse_edge(edge)?;
+            let pin = sys_ref
+                .lock()
+                .unwrap()
+                .pin_address(&pin)
+                .map_err(mlua::Error::RuntimeError)?;
+            let deadline = deadline(&sys_ref, timeout);
+            run_with_callbacks(lua, &sys_ref, deadline, |sys, left| {
+                Ok(sys.run_until_edge(pin, edge, left))
+            })
+        },
+    )?;
+    lua.globals().set("wait_for_edge", wait_for_edge_fn)?;
+
+    let sys_ref = sys.clone();
+    let wait_for_pc_fn = lua.create_function(
+        move |lua, (mcu, location, timeout): (String, Value, Option<TimeDiff>)| {
+            let pc = code_address(lua, &sys_ref, &mcu, location)?;
+            let deadline = deadline(&sys_ref, timeout);
+            run_with_callbacks(lua, &sys_ref, deadline, |sys, left| {
+                sys.run_until_pc(&mcu, pc, left)
+                    .map_err(mlua::Error::RuntimeError)
+            })
+        },
+    )?;
+    lua.globals().set("wait_for_pc", wait_for_pc_fn)?;
+
+    // The predicate is called from Lua, so it is checked every `step` cycles
+    // (1 by default) instead of by the modules. The simulation stops for each
+    // check, which makes it many times slower: long waits should give a
+    // larger step, the time returned is then up to `step - 1` cycles late.
+    let wait_until_fn = lua.create_function(
+        move |lua, (predicate, timeout, step): (Function, Option<TimeDiff>, Option<TimeDiff>)| {
+            let step = step.unwrap_or(1);
+            if step < 1 {
+                return Err(mlua::Error::external(format!(
+                    "invalid step {}, expected at least 1 cycle",
+                    step
+                )));
+            }
+            let deadline = deadline(&sys, timeout);
+            loop {
+                if predicate.call::<_, bool>(())? {
+                    return Ok(Some(sys.lock().unwrap().t));
+                }
+                let t = sys.lock().unwrap().t;
+                if t >= deadline {
+                    return Ok(None);
+                }
+                let next = t.saturating_add(step).min(deadline);
+                run_with_callbacks(lua, &sys, next, |sys, left| {
+                    sys.step(left);
+                    Ok(None::<()>)
+                })?;
+            }
+        },
+    )?;
+    lua.globals().set("wait_until", wait_until_fn)
+}
+
+fn load_stimuli(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    lua.set_named_registry_value(CALLBACKS, lua.create_table()?)?;
+
+    // Calls `callback(t, value)` at every change of the pin, until cancelled.
+    let sys_ref = sys.clone();
+    let on_change_fn = lua.create_function(
+        move |lua, (pin, callback, edge): (String, Function, Option<String>)| {
+            let edge = parse_edge(edge)?;
+            let mut sys = sys_ref.lock().unwrap();
+            let pin = sys.pin_address(&pin).map_err(mlua::Error::RuntimeError)?;
+            let id = sys.watch_pin(pin, edge);
+            let callbacks: Table = lua.named_registry_value(CALLBACKS)?;
+            callbacks.set(id, callback)?;
+            Ok(id)
+        },
+    )?;
+    lua.globals().set("on_change", on_change_fn)?;
+
+    // Square wave of `freq` Hz, rising after `delay` cycles.
+    let sys_ref = sys.clone();
+    let clock_fn = lua.create_function(
+        move |_, (pin, freq, delay): (String, f64, Option<TimeDiff>)| {
+            let mut sys = sys_ref.lock().unwrap();
+            let pin = sys.pin_address(&pin).map_err(mlua::Error::RuntimeError)?;
+            let start = sys.t + delay.unwrap_or(0);
+            let generator = Generator::clock(pin, start, sys.freq as f64 / freq)
+                .map_err(mlua::Error::RuntimeError)?;
+            Ok(sys.add_generator(generator))
+        },
+    )?;
+    lua.globals().set("clock", clock_fn)?;
+
+    // Pulses of `width` cycles, starting now: a single one, or one every
+    // `period` cycles, `count` times or forever.
+    let sys_ref = sys.clone();
+    let pulse_fn = lua.create_function(
+        move |_, (pin, width, period, count): (String, f64, Option<f64>, Option<u64>)| {
+            let mut sys = sys_ref.lock().unwrap();
+            let pin = sys.pin_address(&pin).map_err(mlua::Error::RuntimeError)?;
+            let (period, count) = match period {
+                Some(period) => (period, count),
+                None => (width, Some(1)),
+            };
+            let generator = Generator::new(pin, sys.t, width, period, count)
+                .map_err(mlua::Error::RuntimeError)?;
+            Ok(sys.add_generator(generator))
+        },
+    )?;
+    lua.globals().set("pulse", pulse_fn)?;
+
+    // Stops an `on_change` callback, a generator, a breakpoint or a fault.
+    let cancel_fn = lua.create_function(move |lua, id: usize| {
+        let mut sys = sys.lock().unwrap();
+        let callbacks: Table = lua.named_registry_value(CALLBACKS)?;
+        callbacks.set(id, Value::Nil)?;
+        Ok(sys.unwatch_pin(id)
+            || sys.remove_generator(id)
+            || sys.remove_breakpoint(id)
+            || sys.remove_fault(id))
     })?;
-    lua.globals().set("execute", execute_fn)
+    lua.globals().set("cancel", cancel_fn)
 }
 
 fn load_set_wire(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
     let set_wire_fn = lua.create_function(move |_, (id, value): (String, bool)| {
-        let receiver_id = sys.lock().unwrap().pin_address(&id);
-        sys.lock()
-            .unwrap()
-            .system_tables
-            .inbox
-            .read()
-            .unwrap()
-            .send(
-                WireChangeEvent {
-                    receiver_id,
-                    state: if value {
-                        WireState::High
-                    } else {
-                        WireState::Low
-                    },
-                },
-                sys.lock().unwrap().t,
-            );
+        let mut sys = sys.lock().unwrap();
+        let pin = sys.pin_address(&id).map_err(mlua::Error::RuntimeError)?;
+        sys.set_wire(
+            pin,
+            if value {
+                WireState::High
+            } else {
+                WireState::Low
+            },
+        );
         Ok(())
     })?;
     lua.globals().set("set_wire", set_wire_fn)
@@ -48,28 +315,28 @@ fn load_set_wire(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
 fn load_set_wires(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
     let set_wires_fn =
         lua.create_function(move |_, (comp, msb, lsb, value): (String, u8, u8, i64)| {
-            let sys_ref = sys.lock().unwrap();
-            let module = sys_ref.id_map.get(&comp).unwrap();
+            let mut sys = sys.lock().unwrap();
             let bits = (msb as i32 - lsb as i32 + 1).abs();
-            let inbox = sys_ref.system_tables.inbox.read().unwrap();
-            for i in 0..bits {
-                let pin = if lsb < msb {
-                    lsb + i as u8
-                } else {
-                    msb + i as u8
-                };
-                let receiver_id = module.with_pin(pin);
-
-                inbox.send(
-                    WireChangeEvent {
-                        receiver_id,
-                        state: if (value >> i) & 1 == 1 {
-                            WireState::High
-                        } else {
-                            WireState::Low
-                        },
+            // All the pins are checked before any of them is driven.
+            let pins = (0..bits)
+                .map(|i| {
+                    let pin = if lsb < msb {
+                        lsb + i as u8
+                    } else {
+                        msb + i as u8
+                    };
+                    sys.pin_address(&format!("{}:{}", comp, pin))
+                        .map_err(mlua::Error::RuntimeError)
+                })
+                .collect::<mlua::Result<Vec<_>>>()?;
+            for (i, pin) in pins.into_iter().enumerate() {
+                sys.set_wire(
+                    pin,
+                    if (value >> i) & 1 == 1 {
+                        WireState::High
+                    } else {
+                        WireState::Low
                     },
-                    sys.lock().unwrap().t,
                 );
             }
             Ok(())
@@ -79,7 +346,11 @@ fn load_set_wires(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
 
 fn load_get_wire(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
     let get_wire_fn = lua.create_function(move |_, id: String| {
-        let pin_addr = sys.lock().unwrap().pin_address(&id);
+        let pin_addr = sys
+            .lock()
+            .unwrap()
+            .pin_address(&id)
+            .map_err(mlua::Error::RuntimeError)?;
         let state = sys.lock().unwrap().get_pin(pin_addr);
         Ok(state.to_bool())
     })?;
@@ -89,7 +360,6 @@ fn load_get_wire(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
 fn load_get_wires(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
     let get_wires_fn = lua.create_function(move |_, (comp, msb, lsb): (String, u8, u8)| {
         let sys_ref = sys.lock().unwrap();
-        let module = sys_ref.id_map.get(&comp).unwrap();
         let bits = (msb as i32 - lsb as i32 + 1).abs();
 
         let mut value = 0;
@@ -99,8 +369,10 @@ fn load_get_wires(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
             } else {
                 msb + i as u8
             };
-            let pin_addr = module.with_pin(pin);
-            let state = sys.lock().unwrap().get_pin(pin_addr);
+            let pin = sys_ref
+                .pin_address(&format!("{}:{}", comp, pin))
+                .map_err(mlua::Error::RuntimeError)?;
+            let state = sys_ref.get_pin(pin);
             value |= (state.to_bool() as u64) << i;
         }
         Ok(value)
@@ -108,60 +380,1365 @@ fn load_get_wires(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
     lua.globals().set("get_wires", get_wires_fn)
 }
 
+fn load_snapshots(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    let sys_ref = sys.clone();
+    let snapshot_fn = lua.create_function(move |_, ()| Ok(sys_ref.lock().unwrap().snapshot()))?;
+    lua.globals().set("snapshot", snapshot_fn)?;
+
+    let sys_ref = sys.clone();
+    let restore_fn = lua.create_function(move |_, snapshot: UserDataRef<SystemSnapshot>| {
+        sys_ref
+            .lock()
+            .unwrap()
+            .restore(&snapshot)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("restore", restore_fn)?;
+
+    let sys_ref = sys.clone();
+    let save_snapshot_fn = lua.create_function(move |_, path: String| {
+        let mut sys = sys_ref.lock().unwrap();
+        let path = sys.resolve_path(&path).map_err(mlua::Error::external)?;
+        sys.save_snapshot(path).map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("save_snapshot", save_snapshot_fn)?;
+
+    let load_snapshot_fn = lua.create_function(move |_, path: String| {
+        let mut sys = sys.lock().unwrap();
+        let path = sys.resolve_path(&path).map_err(mlua::Error::external)?;
+        sys.load_snapshot(path).map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("load_snapshot", load_snapshot_fn)
+}
+
+fn load_history(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    let sys_ref = sys.clone();
+    let enable_history_fn = lua.create_function(move |_, interval: i64| {
+        sys_ref.lock().unwrap().enable_history(interval);
+        Ok(())
+    })?;
+    lua.globals().set("enable_history", enable_history_fn)?;
+
+    let sys_ref = sys.clone();
+    let travel_to_fn = lua.create_function(move |_, t: i64| {
+        sys_ref
+            .lock()
+            .unwrap()
+            .travel_to(t)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("travel_to", travel_to_fn)?;
+
+    let sys_ref = sys.clone();
+    let step_back_fn = lua.create_function(move |_, cycles: i64| {
+        sys_ref
+            .lock()
+            .unwrap()
+            .step_back(cycles)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("step_back", step_back_fn)?;
+
+    let last_change_fn = lua.create_function(move |lua, (mcu, address): (String, u16)| {
+        let change = sys
+            .lock()
+            .unwrap()
+            .last_change(&mcu, address)
+            .map_err(mlua::Error::external)?;
+        match change {
+            Some(change) => {
+                let table = lua.create_table()?;
+                table.set("t", change.t)?;
+                table.set("pc", change.pc)?;
+                table.set("old", change.old)?;
+                table.set("new", change.new)?;
+                Ok(Some(table))
+            }
+            None => Ok(None),
+        }
+    })?;
+    lua.globals().set("last_change", last_change_fn)
+}
+
+/// Runs `f` on the MCU named `name`.
+fn with_mcu<T>(
+    sys: &Mutex<System>,
+    name: &str,
+    f: impl FnOnce(&mut Mcu) -> mlua::Result<T>,
+) -> mlua::Result<T> {
+    let mut sys = sys.lock().unwrap();
+    let mcu = sys
+        .mcu_mut(name)
+        .ok_or_else(|| mlua::Error::external(format!("unknown MCU `{}`", name)))?;
+    f(mcu)
+}
+
+fn check_range(what: &str, value: u32, end: u32) -> mlua::Result<()> {
+    system::check_range(what, value, end).map_err(mlua::Error::external)
+}
+
+fn load_mcu_access(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    // Data space, with the registers, I/O registers and SRAM. Reads have no side
+    // effects, writes act like the ones of the CPU.
+    let sys_ref = sys.clone();
+    let read_memory_fn = lua.create_function(
+        move |lua, (mcu, address, count): (String, u16, Option<u16>)| {
+            with_mcu(&sys_ref, &mcu, |mcu| match count {
+                None => peek(mcu, address)?.into_lua(lua),
+                Some(count) => {
+                    let bytes = (0..count)
+                        .map(|i| peek(mcu, address.wrapping_add(i)))
+                        .collect::<mlua::Result<Vec<_>>>()?;
+                    lua.create_sequence_from(bytes)?.into_lua(lua)
+                }
+            })
+        },
+    )?;
+    lua.globals().set("read_memory", read_memory_fn)?;
+
+    let sys_ref = sys.clone();
+    let write_memory_fn =
+        lua.create_function(move |lua, (mcu, address, data): (String, u16, Value)| {
+            let bytes: Vec<u8> = match data {
+                Value::Table(bytes) => bytes.sequence_values().collect::<mlua::Result<_>>()?,
+                value => vec![u8::from_lua(value, lua)?],
+            };
+            let mut sys = sys_ref.lock().unwrap();
+            for (i, byte) in bytes.into_iter().enumerate() {
+                sys.write_memory(&mcu, address.wrapping_add(i as u16), byte)
+                    .map_err(mlua::Error::external)?;
+            }
+            Ok(())
+        })?;
+    lua.globals().set("write_memory", write_memory_fn)?;
+
+    let sys_ref = sys.clone();
+    let read_flash_fn = lua.create_function(move |_, (mcu, address): (String, u32)| {
+        with_mcu(&sys_ref, &mcu, |mcu| {
+            check_range("flash address", address, mcu.flash_size())?;
+            Ok(mcu.read_flash(address))
+        })
+    })?;
+    lua.globals().set("read_flash", read_flash_fn)?;
+
+    let sys_ref = sys.clone();
+    let write_flash_fn =
+        lua.create_function(move |_, (mcu, address, value): (String, u32, u16)| {
+            sys_ref
+                .lock()
+                .unwrap()
+                .write_flash(&mcu, address, value)
+                .map_err(mlua::Error::external)
+        })?;
+    lua.globals().set("write_flash", write_flash_fn)?;
+
+    let sys_ref = sys.clone();
+    let get_register_fn = lua.create_function(move |_, (mcu, i): (String, u16)| {
+        with_mcu(&sys_ref, &mcu, |mcu| {
+            check_range("register", i as u32, 32)?;
+            Ok(mcu.read_register(i))
+        })
+    })?;
+    lua.globals().set("get_register", get_register_fn)?;
+
+    let sys_ref = sys.clone();
+    let set_register_fn = lua.create_function(move |_, (mcu, i, value): (String, u16, u8)| {
+        sys_ref
+            .lock()
+            .unwrap()
+            .set_register(&mcu, i, value)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("set_register", set_register_fn)?;
+
+    // I/O addresses below 0x40 are the ones of IN and OUT, the extended I/O
+    // registers are given by their data space address.
+    let sys_ref = sys.clone();
+    let read_io_fn = lua.create_function(move |_, (mcu, address): (String, u16)| {
+        with_mcu(&sys_ref, &mcu, |mcu| match address {
+            0x00..=0x3F => peek(mcu, address + 0x20),
+            0x60..=0x1FF => peek(mcu, address),
+            _ => Err(invalid_io_address(address)),
+        })
+    })?;
+    lua.globals().set("read_io", read_io_fn)?;
+
+    let sys_ref = sys.clone();
+    let write_io_fn = lua.create_function(move |_, (mcu, address, value): (String, u16, u8)| {
+        let address = match address {
+            0x00..=0x3F => address + 0x20,
+            0x60..=0x1FF => address,
+            _ => return Err(invalid_io_address(address)),
+        };
+        sys_ref
+            .lock()
+            .unwrap()
+            .write_memory(&mcu, address, value)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("write_io", write_io_fn)?;
+
+    let sys_ref = sys.clone();
+    let get_pc_fn =
+        lua.create_function(move |_, mcu: String| with_mcu(&sys_ref, &mcu, |mcu| Ok(mcu.pc())))?;
+    lua.globals().set("get_pc", get_pc_fn)?;
+
+    let sys_ref = sys.clone();
+    let set_pc_fn = lua.create_function(move |_, (mcu, pc): (String, u32)| {
+        sys_ref
+            .lock()
+            .unwrap()
+            .set_pc(&mcu, pc)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("set_pc", set_pc_fn)?;
+
+    let sys_ref = sys.clone();
+    let get_sp_fn =
+        lua.create_function(move |_, mcu: String| with_mcu(&sys_ref, &mcu, |mcu| Ok(mcu.sp())))?;
+    lua.globals().set("get_sp", get_sp_fn)?;
+
+    let sys_ref = sys.clone();
+    let set_sp_fn = lua.create_function(move |_, (mcu, sp): (String, u16)| {
+        sys_ref
+            .lock()
+            .unwrap()
+            .set_sp(&mcu, sp)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("set_sp", set_sp_fn)?;
+
+    let sys_ref = sys.clone();
+    let get_sreg_fn =
+        lua.create_function(move |_, mcu: String| with_mcu(&sys_ref, &mcu, |mcu| Ok(mcu.sreg())))?;
+    lua.globals().set("get_sreg", get_sreg_fn)?;
+
+    let set_sreg_fn = lua.create_function(move |_, (mcu, sreg): (String, u8)| {
+        sys.lock()
+            .unwrap()
+            .set_sreg(&mcu, sreg)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("set_sreg", set_sreg_fn)
+}
+
+/// Reads the data space without side effects, so that scripts can inspect
+/// peripherals without changing their state.
+fn peek(mcu: &Mcu, address: u16) -> mlua::Result<u8> {
+    mcu.peek_memory(address).ok_or_else(|| {
+        mlua::Error::external(format!("no emulated register or SRAM at {:#x}", address))
+    })
+}
+
+fn invalid_io_address(address: u16) -> mlua::Error {
+    mlua::Error::external(format!(
+        "invalid I/O address {:#x}, expected 0x00-0x3F or 0x60-0x1FF",
+        address
+    ))
+}
+
+fn unknown_symbol(name: &str, mcu: &str) -> mlua::Error {
+    mlua::Error::external(format!("unknown symbol `{}` in `{}`", name, mcu))
+}
+
+/// Word address in flash given as a number, or as the name of a function
+/// or label.
+fn code_address(lua: &Lua, sys: &Mutex<System>, mcu: &str, location: Value) -> mlua::Result<u32> {
+    match location {
+        Value::String(name) => {
+            let name = name.to_str()?;
+            with_mcu(sys, mcu, |m| {
+                m.code_symbol(name).ok_or_else(|| unknown_symbol(name, mcu))
+            })
+        }
+        location => u32::from_lua(location, lua),
+    }
+}
+
+/// Address in the data space given as a number, or as the name of a
+/// variable.
+fn data_address(lua: &Lua, sys: &Mutex<System>, mcu: &str, location: Value) -> mlua::Result<u16> {
+    match location {
+        Value::String(name) => {
+            let name = name.to_str()?;
+            with_mcu(sys, mcu, |m| {
+                m.data_symbol(name).ok_or_else(|| unknown_symbol(name, mcu))
+            })
+        }
+        location => u16::from_lua(location, lua),
+    }
+}
+
+/// Breakpoints make `execute` and its variants stop early, returning why.
+/// They are removed with `cancel`.
+fn load_breakpoints(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    let sys_ref = sys.clone();
+    let break_at_fn = lua.create_function(move |lua, (mcu, location): (String, Value)| {
+        let pc = code_address(lua, &sys_ref, &mcu, location)?;
+        let mut sys = sys_ref.lock().unwrap();
+        sys.add_breakpoint(&mcu, Breakpoint::Pc(pc))
+            .map_err(mlua::Error::RuntimeError)
+    })?;
+    lua.globals().set("break_at", break_at_fn)?;
+
+    load_watchpoint(lua, sys.clone(), "break_on_read", Breakpoint::Read)?;
+    load_watchpoint(lua, sys.clone(), "break_on_write", Breakpoint::Write)?;
+    load_watchpoint(lua, sys, "break_on_access", Breakpoint::Access)
+}
+
+/// Sets the global `name` to a function adding a `breakpoint` on an address
+/// of the data space.
+fn load_watchpoint(
+    lua: &mut Lua,
+    sys: Arc<Mutex<System>>,
+    name: &str,
+    breakpoint: fn(u16) -> Breakpoint,
+) -> mlua::Result<()> {
+    let break_fn = lua.create_function(move |lua, (mcu, location): (String, Value)| {
+        let address = data_address(lua, &sys, &mcu, location)?;
+        let mut sys = sys.lock().unwrap();
+        sys.add_breakpoint(&mcu, breakpoint(address))
+            .map_err(mlua::Error::RuntimeError)
+    })?;
+    lua.globals().set(name, break_fn)
+}
+
+fn add_link_fault(
+    sys: &Mutex<System>,
+    from: &str,
+    to: &str,
+    fault: LinkFault,
+) -> mlua::Result<usize> {
+    let mut sys = sys.lock().unwrap();
+    let from = sys.pin_address(from).map_err(mlua::Error::RuntimeError)?;
+    let to = sys.pin_address(to).map_err(mlua::Error::RuntimeError)?;
+    sys.add_link_fault(from, to, fault)
+        .map_err(mlua::Error::RuntimeError)
+}
+
+/// Faults last until cancelled, unless they are given a number of cycles.
+fn load_faults(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    // Inverts a bit of a register or of SRAM, given by its address in the
+    // data space or by the name of a variable. Returns the new value.
+    let sys_ref = sys.clone();
+    let flip_bit_fn =
+        lua.create_function(move |lua, (mcu, location, bit): (String, Value, u8)| {
+            let address = data_address(lua, &sys_ref, &mcu, location)?;
+            check_range("bit", bit as u32, 8)?;
+            let mut sys = sys_ref.lock().unwrap();
+            sys.flip_bit(&mcu, address, bit)
+                .map_err(mlua::Error::external)
+        })?;
+    lua.globals().set("flip_bit", flip_bit_fn)?;
+
+    // Holds the wire of a pin at a value, whatever drives it.
+    let sys_ref = sys.clone();
+    let stick_pin_fn = lua.create_function(
+        move |_, (pin, value, cycles): (String, bool, Option<TimeDiff>)| {
+            let mut sys = sys_ref.lock().unwrap();
+            let pin = sys.pin_address(&pin).map_err(mlua::Error::RuntimeError)?;
+            sys.stick_pin(pin, WireState::from_bool(value), cycles)
+                .map_err(mlua::Error::RuntimeError)
+        },
+    )?;
+    lua.globals().set("stick_pin", stick_pin_fn)?;
+
+    // The next `count` changes that `from` drives are lost for `to`, or all
+    // of them.
+    let sys_ref = sys.clone();
+    let drop_events_fn =
+        lua.create_function(move |_, (from, to, count): (String, String, Option<u32>)| {
+            add_link_fault(&sys_ref, &from, &to, LinkFault::Drop(count))
+        })?;
+    lua.globals().set("drop_events", drop_events_fn)?;
+
+    // The changes that `from` drives reach `to` `cycles` late.
+    let sys_ref = sys.clone();
+    let delay_events_fn =
+        lua.create_function(move |_, (from, to, cycles): (String, String, TimeDiff)| {
+            add_link_fault(&sys_ref, &from, &to, LinkFault::Delay(cycles))
+        })?;
+    lua.globals().set("delay_events", delay_events_fn)?;
+
+    // Waits for the start bit of a UART frame on the line of `pin`, then
+    // inverts the bit `bit` of the frame, bits lasting `cycles`. Bit 0 is the
+    // start bit, the data bits follow, LSB first. Returns the time of the
+    // start bit, or nil on timeout.
+    let sys_ref = sys.clone();
+    let corrupt_frame_fn = lua.create_function(
+        move |lua, (pin, bit, cycles, timeout): (String, TimeDiff, TimeDiff, Option<TimeDiff>)| {
+            let pin = sys_ref
+                .lock()
+                .unwrap()
+                .pin_address(&pin)
+                .map_err(mlua::Error::RuntimeError)?;
+            let deadline = deadline(&sys_ref, timeout);
+            let start = run_with_callbacks(lua, &sys_ref, deadline, |sys, left| {
+                Ok(sys.run_until_edge(pin, Edge::Falling, left))
+            })?;
+            let Some(start) = start else {
+                return Ok(None);
+            };
+            // The bit is read a quarter into it, then the line is held at the
+            // opposite over its middle, where receivers sample it. Releasing
+            // it before the next bit leaves the edges of the sender alone.
+            let bit_start = start + bit * cycles;
+            run_with_callbacks(lua, &sys_ref, bit_start + cycles / 4, |sys, left| {
+                sys.run_until_change(left);
+                Ok(None::<()>)
+            })?;
+            let mut sys = sys_ref.lock().unwrap();
+            let value = sys.get_pin(pin).to_bool();
+            let left = bit_start + cycles * 3 / 4 - sys.t;
+            sys.stick_pin(pin, WireState::from_bool(!value), Some(left))
+                .map_err(mlua::Error::RuntimeError)?;
+            Ok(Some(start))
+        },
+    )?;
+    lua.globals().set("corrupt_frame", corrupt_frame_fn)?;
+
+    // Resets an MCU, holding it in reset for `cycles`, none by default. A
+    // brown-out is told apart from a reset by the flags of MCUSR.
+    for (name, cause) in [
+        ("reset", ResetCause::External),
+        ("brown_out", ResetCause::BrownOut),
+    ] {
+        let sys = sys.clone();
+        let reset_fn =
+            lua.create_function(move |_, (mcu, cycles): (String, Option<TimeDiff>)| {
+                let mut sys = sys.lock().unwrap();
+                sys.reset_mcu(&mcu, cause, Some(cycles.unwrap_or(0)))
+                    .map_err(mlua::Error::RuntimeError)
+            })?;
+        lua.globals().set(name, reset_fn)?;
+    }
+    Ok(())
+}
+
+/// Runs `f` on the UART console named `name`.
+fn with_uart<T>(
+    sys: &Mutex<System>,
+    name: &str,
+    f: impl FnOnce(&mut UartModule) -> mlua::Result<T>,
+) -> mlua::Result<T> {
+    let mut sys = sys.lock().unwrap();
+    f(find_uart(&mut sys, name)?)
+}
+
+fn find_uart<'a>(sys: &'a mut System, name: &str) -> mlua::Result<&'a mut UartModule> {
+    sys.component_mut(name)
+        .ok_or_else(|| mlua::Error::external(format!("unknown UART `{}`", name)))
+}
+
+/// Characters as a Lua string, keeping the low 8 bits of each.
+fn chars_to_string<'lua>(
+    lua: &'lua Lua,
+    chars: impl IntoIterator<Item = u16>,
+) -> mlua::Result<mlua::String<'lua>> {
+    let bytes: Vec<u8> = chars.into_iter().map(|c| c as u8).collect();
+    lua.create_string(bytes)
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn load_uart(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    // Characters are given as a string, or as a table of numbers for frames
+    // wider than 8 bits.
+    let sys_ref = sys.clone();
+    let uart_send_fn = lua.create_function(move |lua, (uart, data): (String, Value)| {
+        let chars: Vec<u16> = match data {
+            Value::Table(chars) => chars.sequence_values().collect::<mlua::Result<_>>()?,
+            value => mlua::String::from_lua(value, lua)?
+                .as_bytes()
+                .iter()
+                .map(|&b| b as u16)
+                .collect(),
+        };
+        sys_ref
+            .lock()
+            .unwrap()
+            .uart_send(&uart, chars)
+            .map_err(mlua::Error::external)
+    })?;
+    lua.globals().set("uart_send", uart_send_fn)?;
+
+    let sys_ref = sys.clone();
+    let uart_read_fn =
+        lua.create_function(move |lua, (uart, count): (String, Option<usize>)| {
+            let chars: Vec<u16> = with_uart(&sys_ref, &uart, |uart| {
+                let count = count.unwrap_or(usize::MAX);
+                Ok(std::iter::from_fn(|| uart.read_u16()).take(count).collect())
+            })?;
+            chars_to_string(lua, chars)
+        })?;
+    lua.globals().set("uart_read", uart_read_fn)?;
+
+    // Waits for `text` and returns what was received before it. What follows
+    // is left for the next reads. On timeout, returns nil and everything
+    // received so far.
+    let expect_fn = lua.create_function(
+        move |lua, (uart, text, timeout): (String, mlua::String, Option<TimeDiff>)| {
+            let text = text.as_bytes();
+            let take_before = |sys: &mut System| -> mlua::Result<Option<Vec<u8>>> {
+                let received = find_uart(sys, &uart)?;
+                let bytes: Vec<u8> = received.received().iter().map(|&c| c as u8).collect();
+                Ok(find_bytes(&bytes, text).map(|pos| {
+                    received.discard_received(pos + text.len());
+                    bytes[..pos].to_vec()
+                }))
+            };
+            let deadline = deadline(&sys, timeout);
+            let before = run_with_callbacks(lua, &sys, deadline, |sys, left| {
+                if let Some(before) = take_before(sys)? {
+                    return Ok(Some(before));
+                }
+                sys.run_until_received(&uart, left)
+                    .map_err(mlua::Error::external)?;
+                take_before(sys)
+            })?;
+            match before {
+                Some(before) => Ok((Some(lua.create_string(before)?), None)),
+                None => {
+                    let mut sys = sys.lock().unwrap();
+                    let received = find_uart(&mut sys, &uart)?.received().clone();
+                    Ok((None, Some(chars_to_string(lua, received)?)))
+                }
+            }
+        },
+    )?;
+    lua.globals().set("expect", expect_fn)
+}
+
+/// Name of the registry table with the cases defined by `test`, in order.
+const TESTS: &str = "amber.tests";
+/// Name of the registry table with the failed expectations of the case being
+/// run.
+const FAILURES: &str = "amber.failures";
+const SETUP: &str = "amber.setup";
+const TEARDOWN: &str = "amber.teardown";
+
+/// Value as it is shown in the failures.
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Nil => "nil".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => format!("{:?}", s.to_string_lossy()),
+        other => other.type_name().to_string(),
+    }
+}
+
+/// Records a failed expectation, with the time of the simulation and where
+/// it was called from in the script.
+fn record_failure(
+    lua: &Lua,
+    sys: &Mutex<System>,
+    failure: String,
+    message: Option<String>,
+) -> mlua::Result<()> {
+    let mut text = match lua.inspect_stack(1) {
+        Some(caller) => format!(
+            "{}:{}: ",
+            caller.source().short_src.unwrap_or_default(),
+            caller.curr_line()
+        ),
+        None => String::new(),
+    };
+    text += &format!("t={}: {}", sys.lock().unwrap().t, failure);
+    if let Some(message) = message {
+        text += &format!(" ({})", message);
+    }
+    let failures: Table = lua.named_registry_value(FAILURES)?;
+    failures.push(text)
+}
+
+fn load_tests(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
+    lua.set_named_registry_value(TESTS, lua.create_table()?)?;
+    lua.set_named_registry_value(FAILURES, lua.create_table()?)?;
+
+    let test_fn = lua.create_function(|lua, (name, case): (String, Function)| {
+        let tests: Table = lua.named_registry_value(TESTS)?;
+        let entry = lua.create_table()?;
+        entry.set("name", name)?;
+        entry.set("case", case)?;
+        tests.push(entry)
+    })?;
+    lua.globals().set("test", test_fn)?;
+    let setup_fn = lua
+        .create_function(|lua, hook: Option<Function>| lua.set_named_registry_value(SETUP, hook))?;
+    lua.globals().set("setup", setup_fn)?;
+    let teardown_fn = lua.create_function(|lua, hook: Option<Function>| {
+        lua.set_named_registry_value(TEARDOWN, hook)
+    })?;
+    lua.globals().set("teardown", teardown_fn)?;
+
+    // The expectations record a failure and let the case go on. They return
+    // whether they were met.
+    let sys_ref = sys.clone();
+    let expect_eq_fn = lua.create_function(
+        move |lua, (actual, expected, message): (Value, Value, Option<String>)| {
+            let met = actual.equals(&expected)?;
+            if !met {
+                let failure = format!(
+                    "expected {}, got {}",
+                    describe(&expected),
+                    describe(&actual)
+                );
+                record_failure(lua, &sys_ref, failure, message)?;
+            }
+            Ok(met)
+        },
+    )?;
+    lua.globals().set("expect_eq", expect_eq_fn)?;
+
+    let sys_ref = sys.clone();
+    let expect_ne_fn = lua.create_function(
+        move |lua, (actual, unexpected, message): (Value, Value, Option<String>)| {
+            let met = !actual.equals(&unexpected)?;
+            if !met {
+                let failure = format!("expected anything but {}", describe(&unexpected));
+                record_failure(lua, &sys_ref, failure, message)?;
+            }
+            Ok(met)
+        },
+    )?;
+    lua.globals().set("expect_ne", expect_ne_fn)?;
+
+    let sys_ref = sys.clone();
+    let expect_true_fn =
+        lua.create_function(move |lua, (value, message): (Value, Option<String>)| {
+            let met = !matches!(value, Value::Nil | Value::Boolean(false));
+            if !met {
+                let failure = format!("expected a true value, got {}", describe(&value));
+                record_failure(lua, &sys_ref, failure, message)?;
+            }
+            Ok(met)
+        })?;
+    lua.globals().set("expect_true", expect_true_fn)?;
+
+    let expect_near_fn = lua.create_function(
+        move |lua, (actual, expected, tolerance, message): (Value, f64, f64, Option<String>)| {
+            let met = match actual {
+                Value::Integer(i) => (i as f64 - expected).abs() <= tolerance,
+                Value::Number(n) => (n - expected).abs() <= tolerance,
+                _ => false,
+            };
+            if !met {
+                let failure = format!(
+                    "expected {} ± {}, got {}",
+                    expected,
+                    tolerance,
+                    describe(&actual)
+                );
+                record_failure(lua, &sys, failure, message)?;
+            }
+            Ok(met)
+        },
+    )?;
+    lua.globals().set("expect_near", expect_near_fn)
+}
+
 fn load_support_lib(lua: &mut Lua, sys: Arc<Mutex<System>>) -> mlua::Result<()> {
     load_execute(lua, sys.clone())?;
+    load_waits(lua, sys.clone())?;
     load_set_wire(lua, sys.clone())?;
     load_get_wire(lua, sys.clone())?;
     load_set_wires(lua, sys.clone())?;
     load_get_wires(lua, sys.clone())?;
+    load_snapshots(lua, sys.clone())?;
+    load_history(lua, sys.clone())?;
+    load_mcu_access(lua, sys.clone())?;
+    load_breakpoints(lua, sys.clone())?;
+    load_faults(lua, sys.clone())?;
+    load_uart(lua, sys.clone())?;
+    load_stimuli(lua, sys.clone())?;
+    load_tests(lua, sys.clone())?;
     Ok(())
 }
 
 pub enum TestResult {
-    Success(Duration),
-    #[allow(dead_code)]
+    Success,
+    /// The expectations that weren't met.
     Failure(Vec<String>),
-    Error(mlua::Error, Vec<String>),
+    Error(mlua::Error),
+}
+
+/// Outcome of a case defined with `test`, or of a whole file without cases.
+pub struct TestCase {
+    /// Name given to `test`, `None` for a whole file.
+    pub name: Option<String>,
+    pub result: TestResult,
+    /// Time taken to run the case.
+    pub duration: Duration,
+    /// Messages of the simulation.
+    pub messages: Vec<String>,
 }
 
+impl TestCase {
+    pub fn passed(&self) -> bool {
+        matches!(self.result, TestResult::Success)
+    }
+}
+
+fn load_system(
+    sys_filename: &str,
+    options: &LoadOptions,
+    snapshot: Option<&SystemSnapshot>,
+) -> Result<System, String> {
+    let mut sys = parser::load(sys_filename, options).map_err(|err| err.to_string())?;
+    if let Some(snapshot) = snapshot {
+        sys.restore(snapshot).map_err(|err| err.to_string())?;
+    }
+    Ok(sys)
+}
+
+/// VCD output of a test file, next to it: `test.vcd` for the whole file, and
+/// `test.name.vcd` for its case `name`, with the characters that don't belong
+/// in a file name replaced.
+fn vcd_file(test_filename: &str, case: Option<&str>, compressed: bool) -> PathBuf {
+    let path = Path::new(test_filename);
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    if let Some(case) = case {
+        let case: String = case
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        name.push(".");
+        name.push(case);
+    }
+    name.push(if compressed { ".vcd.gz" } else { ".vcd" });
+    path.with_file_name(name)
+}
+
+/// Runs `f` with the VCD output of `sys` written while it runs.
+fn with_vcd<T>(sys: &Mutex<System>, f: impl FnOnce() -> T) -> T {
+    let vcd = sys.lock().unwrap().vcd.take().map(|vcd| vcd.deploy());
+    let result = f();
+    drop(vcd);
+    result
+}
+
+/// Result of a case that ran to the end or stopped on `result`.
+fn case_result(lua: &Lua, result: mlua::Result<()>) -> TestResult {
+    let failures = lua
+        .named_registry_value::<Table>(FAILURES)
+        .and_then(|failures| {
+            failures
+                .sequence_values()
+                .collect::<mlua::Result<Vec<String>>>()
+        });
+    match (result, failures) {
+        (Err(err), _) | (Ok(()), Err(err)) => TestResult::Error(err),
+        (Ok(()), Ok(failures)) if failures.is_empty() => TestResult::Success,
+        (Ok(()), Ok(failures)) => TestResult::Failure(failures),
+    }
+}
+
+/// Runs a test file. Without calls to `test`, the whole file is a single
+/// case. Otherwise the file only defines the cases, which are then run in
+/// order, each on a freshly loaded system and between the `setup` and
+/// `teardown` functions, if any. Each of them writes its own VCD output, if
+/// it is enabled, so that test files can run in parallel.
 pub fn run_test(
     sys_filename: &str,
     test_filename: &str,
-    vcd_enabled: bool,
-    vcd_compressed: bool,
-) -> TestResult {
-    let sys: Arc<Mutex<System>> = Arc::new(Mutex::new(parser::load(
-        sys_filename,
-        vcd_enabled,
-        vcd_compressed,
-    )));
-
-    let vcd = Arc::new(Mutex::new(Some(
-        sys.lock().unwrap().vcd.take().unwrap().deploy(),
-    )));
-
-    let mut lua = Lua::new();
-    load_support_lib(&mut lua, sys.clone()).unwrap();
+    options: &LoadOptions,
+    snapshot: Option<&SystemSnapshot>,
+) -> Vec<TestCase> {
+    let start = Instant::now();
+    let whole_file = |result, messages| {
+        vec![TestCase {
+            name: None,
+            result,
+            duration: start.elapsed(),
+            messages,
+        }]
+    };
     let test_src = match std::fs::read_to_string(test_filename) {
         Ok(src) => src,
-        Err(err) => return TestResult::Error(err.into(), Vec::new()),
+        Err(err) => return whole_file(TestResult::Error(err.into()), Vec::new()),
+    };
+    let file_options = LoadOptions {
+        vcd_file: Some(vcd_file(test_filename, None, options.vcd_compressed)),
+        ..options.clone()
+    };
+    let sys = match load_system(sys_filename, &file_options, snapshot) {
+        Ok(sys) => Arc::new(Mutex::new(sys)),
+        Err(err) => return whole_file(TestResult::Error(mlua::Error::external(err)), Vec::new()),
+    };
+    let messages = |sys: &Mutex<System>| {
+        let sys = sys.lock().unwrap();
+        let messages = sys.system_tables.messages.read().unwrap();
+        messages.clone()
     };
-    let start = Instant::now();
 
-    let result = lua.load(test_src).exec();
-    let simulation_time = start.elapsed();
-    drop(vcd.lock().unwrap().take());
-    match result {
-        Ok(()) => TestResult::Success(simulation_time),
-        Err(err) => TestResult::Error(
-            err,
-            sys.lock()
-                .unwrap()
-                .system_tables
-                .messages
-                .read()
-                .unwrap()
-                .clone(),
-        ),
+    let mut lua = Lua::new();
+    load_support_lib(&mut lua, sys.clone()).unwrap();
+    let result = with_vcd(&sys, || {
+        lua.load(test_src)
+            .set_name(format!("@{}", test_filename))
+            .exec()
+    });
+    let cases: Vec<(String, Function)> =
+        match lua.named_registry_value::<Table>(TESTS).and_then(|tests| {
+            tests
+                .sequence_values::<Table>()
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok((entry.get("name")?, entry.get("case")?))
+                })
+                .collect()
+        }) {
+            Ok(cases) => cases,
+            Err(err) => return whole_file(TestResult::Error(err), messages(&sys)),
+        };
+    if result.is_err() || cases.is_empty() {
+        return whole_file(case_result(&lua, result), messages(&sys));
+    }
+    if options.vcd_enabled {
+        // The file only defines the cases, which have their own VCD output.
+        let _ = std::fs::remove_file(file_options.vcd_file.unwrap());
+    }
+
+    let mut results = Vec::new();
+    for (name, case) in cases {
+        let start = Instant::now();
+        let case_options = LoadOptions {
+            vcd_file: Some(vcd_file(test_filename, Some(&name), options.vcd_compressed)),
+            ..options.clone()
+        };
+        let result = load_system(sys_filename, &case_options, snapshot)
+            .map_err(mlua::Error::external)
+            .and_then(|new_sys| {
+                *sys.lock().unwrap() = new_sys;
+                lua.set_named_registry_value(CALLBACKS, lua.create_table()?)?;
+                lua.set_named_registry_value(FAILURES, lua.create_table()?)
+            })
+            .and_then(|()| {
+                with_vcd(&sys, || {
+                    let hook = |name| match lua.named_registry_value::<Option<Function>>(name)? {
+                        Some(hook) => hook.call::<_, ()>(()),
+                        None => Ok(()),
+                    };
+                    hook(SETUP)?;
+                    let result = case.call::<_, ()>(());
+                    // The teardown runs even when the case fails.
+                    let teardown = hook(TEARDOWN);
+                    result.and(teardown)
+                })
+            });
+        results.push(TestCase {
+            name: Some(name),
+            result: case_result(&lua, result),
+            duration: start.elapsed(),
+            messages: messages(&sys),
+        });
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::PB7_HIGH, SystemBuilder};
+
+    /// A Lua with the support library bound to `sys`.
+    fn bind(sys: System) -> (Lua, Arc<Mutex<System>>) {
+        let sys = Arc::new(Mutex::new(sys));
+        let mut lua = Lua::new();
+        load_support_lib(&mut lua, sys.clone()).unwrap();
+        (lua, sys)
+    }
+
+    /// A Lua bound to an MCU running [PB7_HIGH], with an LED on PB7.
+    fn bind_led() -> Lua {
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .led("mcu", "led")
+            .wire("mcu:PB7", "mcu.led:0")
+            .build()
+            .unwrap();
+        bind(sys).0
+    }
+
+    /// Message of the error raised by `script`.
+    fn error(lua: &Lua, script: &str) -> String {
+        lua.load(script).exec().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn waits() {
+        let lua = bind_led();
+        let times: Vec<Option<Timestamp>> = lua
+            .load(
+                r#"
+                local led = "mcu.led:0"
+                return {
+                    wait_for_pc("mcu", 1, 100),
+                    wait_for_edge(led, "rising", 100),
+                    -- The LED stays on: the wait times out.
+                    wait_for_edge(led, "any", 100) or false,
+                    now(),
+                    -- The MCU halted on its last instruction.
+                    wait_for_pc("mcu", 0, 50) or false,
+                    now(),
+                    wait_until(function() return now() >= 160 end, 100),
+                    -- Checked every 7 cycles, the stop is up to 6 cycles late.
+                    wait_until(function() return now() >= 170 end, 100, 7),
+                    wait_until(function() return false end, 10) or false,
+                    (now()),
+                }
+                "#,
+            )
+            .eval::<Vec<Value>>()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.as_i64())
+            .collect();
+        assert_eq!(
+            times,
+            [
+                Some(1),
+                Some(2),
+                None,
+                Some(102),
+                None,
+                Some(152),
+                Some(160),
+                Some(174),
+                None,
+                Some(184)
+            ]
+        );
+
+        let err = error(&lua, r#"wait_for_edge("mcu.led:0", "up", 10)"#);
+        assert!(err.contains("invalid edge 'up'"), "{}", err);
+        let err = error(&lua, "wait_until(function() return true end, 10, 0)");
+        assert!(err.contains("invalid step 0"), "{}", err);
+        let err = error(&lua, r#"wait_for_pc("other", 0, 10)"#);
+        assert!(err.contains("unknown MCU `other`"), "{}", err);
+        let err = error(&lua, r#"wait_for_pc("mcu", "main", 10)"#);
+        assert!(err.contains("unknown symbol `main` in `mcu`"), "{}", err);
+    }
+
+    /// Toggles PB7 every 3 cycles.
+    const TOGGLE_PB7: [u16; 4] = [
+        0xE800, // ldi r16, 0x80
+        0xB904, // out DDRB, r16
+        0xB903, // out PINB, r16
+        0xCFFE, // rjmp .-4
+    ];
+
+    #[test]
+    fn generators() {
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &[0xCFFF])
+            .build()
+            .unwrap();
+        let (lua, _) = bind(sys);
+        let pinb = |script: &str| -> String {
+            let sample = r#"
+                local samples = {}
+                for _, t in ipairs(times) do
+                    execute_until(t)
+                    table.insert(samples, read_io("mcu", 0x03) & 1)
+                end
+                return table.concat(samples)
+            "#;
+            lua.load(format!("{} {}", script, sample)).eval().unwrap()
+        };
+        // 20 cycles per period, rising 5 cycles from now. PINB follows 2
+        // cycles late, once the GPIO bank latched its input.
+        let wave = r#"
+            wave = clock("mcu:PB0", frequency() / 20, 5)
+            times = {6, 7, 16, 17, 26, 27, 36, 37}
+        "#;
+        assert_eq!(pinb(wave), "01100110");
+        let stopped = r#"
+            cancel(wave)
+            times = {47, 57, 67}
+        "#;
+        assert_eq!(pinb(stopped), "000");
+        // A single pulse of 3 cycles from now, then 2 of 4 cycles every 10.
+        let pulses = r#"
+            pulse("mcu:PB0", 3)
+            times = {68, 69, 71, 72}
+        "#;
+        assert_eq!(pinb(pulses), "0110");
+        let pulses = r#"
+            pulse("mcu:PB0", 4, 10, 2)
+            times = {73, 74, 77, 78, 83, 84, 87, 88, 93, 94}
+        "#;
+        assert_eq!(pinb(pulses), "0110011000");
+
+        let err = error(&lua, r#"clock("mcu:PB0", frequency())"#);
+        assert!(
+            err.contains("pulse width 0.5 is less than a cycle"),
+            "{}",
+            err
+        );
+        let err = error(&lua, r#"pulse("mcu:PB0", 4, 4)"#);
+        assert!(err.contains("period 4 leaves less than a cycle"), "{}", err);
+        assert!(!lua.load("return cancel(1000)").eval::<bool>().unwrap());
+    }
+
+    #[test]
+    fn on_change() {
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &TOGGLE_PB7)
+            .led("mcu", "led")
+            .wire("mcu:PB7", "mcu.led:0")
+            .build()
+            .unwrap();
+        let (lua, _) = bind(sys);
+        let changes: String = lua
+            .load(
+                r#"
+                local changes = {}
+                local function log(name)
+                    return function(t, value)
+                        -- Called at the time of the change.
+                        assert(now() == t)
+                        table.insert(changes, name .. "@" .. t .. "=" .. tostring(value))
+                    end
+                end
+                local any = on_change("mcu.led:0", log("any"))
+                on_change("mcu.led:0", log("rising"), "rising")
+                execute(8)
+                cancel(any)
+                execute(8)
+                return table.concat(changes, " ")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        // The LED turns off with DDRB, then toggles every 3 cycles.
+        assert_eq!(
+            changes,
+            "any@1=false any@2=true rising@2=true any@5=false rising@8=true rising@14=true"
+        );
+
+        let err = error(&lua, r#"on_change("mcu:PB0", print, "up")"#);
+        assert!(err.contains("invalid edge 'up'"), "{}", err);
+        let err = error(&lua, r#"on_change("mcu:PB99", print)"#);
+        assert!(err.contains("PB99"), "{}", err);
+    }
+
+    #[test]
+    fn breakpoints() {
+        // Drives PB7 high, then reads DDRB into r17 forever.
+        const READ_DDRB: [u16; 5] = [
+            0xE800, // ldi r16, 0x80
+            0xB904, // out DDRB, r16
+            0xB905, // out PORTB, r16
+            0xB114, // in r17, DDRB
+            0xCFFE, // rjmp .-4
+        ];
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &READ_DDRB)
+            .build()
+            .unwrap();
+        let (lua, _) = bind(sys);
+        let reasons: String = lua
+            .load(
+                r#"
+                local reasons = {}
+                local function run(cycles)
+                    local hit = execute(cycles)
+                    table.insert(reasons, hit and string.format(
+                        "%s %s@%#x pc=%d value=%s t=%d",
+                        hit.mcu, hit.type, hit.address, hit.pc, hit.value, hit.t
+                    ) or "nil@" .. now())
+                end
+                local ddrb = break_on_write("mcu", 0x24)
+                run(100)
+                cancel(ddrb)
+                local loop = break_at("mcu", 3)
+                run(100)
+                -- Running on hits it again, once around the loop.
+                run(100)
+                cancel(loop)
+                local read = break_on_read("mcu", 0x24)
+                run(100)
+                cancel(read)
+                local access = break_on_access("mcu", 0x24)
+                run(100)
+                cancel(access)
+                -- The PORTB write is past, the run goes to its end.
+                break_on_access("mcu", 0x25)
+                run(100)
+                return table.concat(reasons, "; ")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        // PC breakpoints stop before the instruction, watchpoints after it.
+        assert_eq!(
+            reasons,
+            "mcu write@0x24 pc=1 value=128 t=2; \
+             mcu pc@0x3 pc=3 value=nil t=3; \
+             mcu pc@0x3 pc=3 value=nil t=6; \
+             mcu read@0x24 pc=3 value=128 t=7; \
+             mcu access@0x24 pc=3 value=128 t=10; \
+             nil@110"
+        );
+
+        let err = error(&lua, r#"break_at("other", 0)"#);
+        assert!(err.contains("unknown MCU `other`"), "{}", err);
+        let err = error(&lua, r#"break_on_write("mcu", "counter")"#);
+        assert!(err.contains("unknown symbol `counter` in `mcu`"), "{}", err);
+    }
+
+    #[test]
+    fn faults() {
+        // PB7 of `a` drives PB0 of `b` high.
+        let run = |script: &str| -> Vec<i64> {
+            let sys = SystemBuilder::new()
+                .mcu_with_flash("a", &PB7_HIGH)
+                .mcu_with_flash("b", &[0xCFFF])
+                .wire("a:PB7", "b:PB0")
+                .build()
+                .unwrap();
+            let (lua, _) = bind(sys);
+            let pinb0 = r#"
+                local function pinb0() return read_io("b", 0x03) & 1 end
+            "#;
+            lua.load(format!("{} {}", pinb0, script)).eval().unwrap()
+        };
+        let rising = r#"return {wait_for_edge("b:PB0", "rising", 1000)}"#;
+        assert_eq!(run(rising), [2]);
+        let stuck = format!(r#"stick_pin("b:PB0", false, 50) {}"#, rising);
+        assert_eq!(run(&stuck), [50]);
+        // `b` sees the changes of `a` once their window is over.
+        assert_eq!(run("execute(250) return {pinb0()}"), [1]);
+        let delayed = r#"
+            delay_events("a:PB7", "b:PB0", 300)
+            execute(250)
+            local before = pinb0()
+            execute(200)
+            return {before, pinb0()}
+        "#;
+        assert_eq!(run(delayed), [0, 1]);
+        // Dropped changes are caught up with when the fault is cancelled.
+        let dropped = r#"
+            local drop = drop_events("a:PB7", "b:PB0")
+            execute(250)
+            local before = pinb0()
+            local cancelled = cancel(drop) and 1 or 0
+            execute(200)
+            return {before, cancelled, pinb0()}
+        "#;
+        assert_eq!(run(dropped), [0, 1, 1]);
+        let flipped = r#"return {flip_bit("b", 20, 3), flip_bit("b", 20, 3)}"#;
+        assert_eq!(run(flipped), [0x08, 0x00]);
+        // The MCU starts over once out of reset, with the flag of the cause
+        // added to MCUSR.
+        let reset = r#"
+            reset("a", 50)
+            local external = read_io("a", 0x34)
+            local restart = wait_for_pc("a", 2, 100)
+            brown_out("a")
+            return {external, restart, read_io("a", 0x34)}
+        "#;
+        assert_eq!(run(reset), [0x03, 52, 0x07]);
+        // Without a start bit on the line, the wait times out.
+        let corrupt = r#"
+            return {corrupt_frame("b:PB1", 1, 10, 50) or -1, (now())}
+        "#;
+        assert_eq!(run(corrupt), [-1, 50]);
+
+        let lua = bind_led();
+        let err = error(&lua, r#"flip_bit("mcu", 20, 8)"#);
+        assert!(err.contains("bit 0x8 out of range"), "{}", err);
+        let err = error(&lua, r#"reset("other")"#);
+        assert!(err.contains("unknown MCU `other`"), "{}", err);
+    }
+
+    #[test]
+    fn time() {
+        let sys = SystemBuilder::new()
+            .frequency(8_000_000)
+            .mcu_with_flash("mcu", &[0xCFFF])
+            .build()
+            .unwrap();
+        let (lua, _) = bind(sys);
+        let times: Vec<f64> = lua
+            .load(
+                r#"
+                local times = {frequency()}
+                local function log()
+                    local cycles, seconds = now()
+                    table.insert(times, cycles)
+                    table.insert(times, seconds)
+                end
+                assert(execute_us(1.5) == nil)
+                log()
+                execute_ms(0.25)
+                log()
+                execute_until(5000)
+                log()
+                -- Times already past are no-ops.
+                execute_until(100)
+                execute(-10)
+                log()
+                return times
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(
+            times,
+            [8e6, 12.0, 1.5e-6, 2012.0, 2.515e-4, 5000.0, 6.25e-4, 5000.0, 6.25e-4]
+        );
+    }
+
+    #[test]
+    fn writes_replay() {
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &[0xCFFF])
+            .build()
+            .unwrap();
+        let (lua, _) = bind(sys);
+        lua.load(
+            r#"
+            enable_history(100)
+            execute(150)
+            write_memory("mcu", 0x100, {0x12, 0x34})
+            set_register("mcu", 20, 0x56)
+            write_io("mcu", 0x04, 0x80)
+            write_flash("mcu", 1, 0xCFFF)
+            set_pc("mcu", 1)
+            set_sp("mcu", 0x1000)
+            set_sreg("mcu", 0x02)
+            execute(100)
+            travel_to(100)
+            "#,
+        )
+        .exec()
+        .unwrap();
+        let state = r#"{
+            read_memory("mcu", 0x100, 2)[2], get_register("mcu", 20),
+            read_io("mcu", 0x04), read_flash("mcu", 1), get_pc("mcu"),
+            get_sp("mcu"), get_sreg("mcu"),
+        }"#;
+        let before: Vec<u32> = lua.load(state).eval().unwrap();
+        assert_eq!(before[..5], [0, 0, 0, 0, 0]);
+        // Running again past the writes replays them.
+        let after: Vec<u32> = lua
+            .load(format!("execute(150) return {}", state))
+            .eval()
+            .unwrap();
+        assert_eq!(after, [0x34, 0x56, 0x80, 0xCFFF, 1, 0x1000, 0x02]);
+
+        let err = lua.load(r#"set_pc("mcu", 0x100000)"#).exec().unwrap_err();
+        assert!(
+            err.to_string().contains("PC 0x100000 out of range"),
+            "{}",
+            err
+        );
+        let err = lua.load(r#"set_sp("nope", 0)"#).exec().unwrap_err();
+        assert!(err.to_string().contains("unknown MCU `nope`"), "{}", err);
+    }
+
+    #[test]
+    fn uart_expect() {
+        let input = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/examples/uart_test2/input.yaml"
+        );
+        let (lua, _) = bind(load_system(input, &LoadOptions::default(), None).unwrap());
+        let results: (
+            String,
+            String,
+            String,
+            String,
+            String,
+            Option<String>,
+            String,
+            i64,
+        ) = lua
+            .load(
+                r#"
+                local uart, second = "mcu.uart", frequency()
+                enable_history(10000)
+                local prompt = expect(uart, "> ", second)
+                local sent = now()
+                uart_send(uart, "hello world\n")
+                -- The firmware echoes the line, then counts its words.
+                local echo = expect(uart, "\n", second)
+                local words = expect(uart, "\n> ", second)
+                -- The send is replayed after travelling back before it, to
+                -- the end of the prompt.
+                travel_to(sent - 1)
+                uart_read(uart)
+                local replayed = expect(uart, "\n> ", second)
+                local left = uart_read(uart)
+                -- Nothing is sent without input: the wait times out.
+                local start = now()
+                local missing, received = expect(uart, "> ", 10000)
+                return prompt, echo, words, replayed, left, missing, received, now() - start
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(
+            results,
+            (
+                String::new(),
+                "hello world".to_string(),
+                "2".to_string(),
+                " hello world\n2".to_string(),
+                String::new(),
+                None,
+                String::new(),
+                10000
+            )
+        );
+
+        let err = lua
+            .load(r#"expect("mcu.clk", "x", 100)"#)
+            .exec()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown UART `mcu.clk`"),
+            "{}",
+            err
+        );
+        let err = lua.load(r#"uart_send("mcu.clk", "x")"#).exec().unwrap_err();
+        assert!(
+            err.to_string().contains("unknown UART `mcu.clk`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn vcd_files() {
+        assert_eq!(vcd_file("test.lua", None, false), PathBuf::from("test.vcd"));
+        assert_eq!(
+            vcd_file("test.lua", None, true),
+            PathBuf::from("test.vcd.gz")
+        );
+        assert_eq!(
+            vcd_file("tests/uart/echo.lua", Some("echo"), false),
+            PathBuf::from("tests/uart/echo.echo.vcd")
+        );
+        assert_eq!(
+            vcd_file("test.lua", Some("send 2 bytes/s: ok?"), true),
+            PathBuf::from("test.send_2_bytes_s__ok_.vcd.gz")
+        );
+        assert_eq!(
+            vcd_file("../test.lua", Some("a-b_c"), false),
+            PathBuf::from("../test.a-b_c.vcd")
+        );
     }
 }
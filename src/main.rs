@@ -1,32 +1,29 @@
 use std::{
+    collections::HashSet,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     time::{Duration, Instant},
 };
 
+use amber::{
+    components::uart_module::UartModule,
+    control::SystemController,
+    load,
+    lua::{run_test, TestCase, TestResult},
+    snapshot::SystemSnapshot,
+    system::{LagPolicy, RealtimeConfig, RealtimeSpeed},
+    test_report::{self, TestFile},
+    LoadOptions,
+};
 use clap::{Parser, Subcommand};
-use components::uart_module::UartModule;
-use lua::{run_test, TestResult};
-use parser::load;
-
-pub mod clock;
-pub mod components;
-pub mod events;
-mod lua;
-pub mod module;
-pub mod module_holder;
-pub mod module_id;
-pub mod multiplexer;
-mod parser;
-pub mod pin_state;
-pub mod system;
-mod system_tables;
-mod vcd;
-pub mod wiring;
+use getch::Getch;
+use kanal::Sender;
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// Path to the config YAML file
+    /// Path to the config file (YAML, JSON or TOML)
     #[arg(short, long)]
     config: Option<String>,
 
@@ -42,6 +39,14 @@ struct Args {
     #[arg(long)]
     gz: bool,
 
+    /// Start from a snapshot saved earlier with the same config
+    #[arg(long)]
+    snapshot: Option<String>,
+
+    /// Load this firmware into an MCU instead of its configured memory, as NAME=PATH
+    #[arg(long, value_name = "NAME=PATH", value_parser = parse_firmware)]
+    firmware: Vec<(String, PathBuf)>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -49,7 +54,21 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Run Lua tests
-    Test { tests: Vec<String> },
+    Test {
+        tests: Vec<String>,
+
+        /// Write a JUnit XML report to this file
+        #[arg(long)]
+        junit: Option<String>,
+
+        /// Print the results in TAP format
+        #[arg(long)]
+        tap: bool,
+
+        /// Number of test files to run at the same time
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+    },
     /// Run a simulation
     Run {
         /// Simulation duration in seconds. No value means running forever in realtime.
@@ -58,97 +77,405 @@ enum Commands {
         /// UART console to connect to
         #[arg(long)]
         uart: Option<String>,
+
+        /// Realtime speed factor (for example 0.1, 10 or max)
+        #[arg(long, default_value = "1")]
+        speed: RealtimeSpeed,
+
+        /// What to do when the host is too slow: catch-up or drop
+        #[arg(long, default_value = "catch-up")]
+        lag_policy: LagPolicy,
+
+        /// Print the achieved speed every N seconds
+        #[arg(long)]
+        status: Option<f64>,
+
+        /// Number of cycles to run on each Ctrl-N while paused
+        #[arg(long, default_value_t = 16_000)]
+        step_cycles: i64,
+
+        /// Save a snapshot of the system when the simulation ends
+        #[arg(long)]
+        save_snapshot: Option<String>,
     },
+    /// Check config files (YAML, JSON or TOML) without running them
+    Validate {
+        /// Files to check, the config file by default
+        files: Vec<String>,
+    },
+}
+
+fn parse_firmware(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("invalid firmware '{}', expected NAME=PATH", s)),
+    }
+}
+
+/// Reads the keyboard, handling the control shortcuts and forwarding
+/// everything else to the connected UART console. The terminal stays in raw
+/// mode until the returned [Getch] is dropped.
+fn spawn_keyboard(
+    controller: SystemController,
+    uart_input: Option<Sender<u16>>,
+    step_cycles: i64,
+) -> Getch {
+    const CTRL_N: u8 = 0x0E;
+    const CTRL_P: u8 = 0x10;
+    const CTRL_X: u8 = 0x18;
+
+    // The reader thread is never joined, so the original terminal settings
+    // are restored by this outer instance instead.
+    let terminal = Getch::new();
+    std::thread::spawn(move || {
+        let g = Getch::new();
+        // getch returns 0 once stdin is closed
+        while let Ok(x) = g.getch() {
+            match x {
+                0 => break,
+                CTRL_P => controller.toggle_pause(),
+                CTRL_N => controller.step(step_cycles),
+                CTRL_X => controller.stop(),
+                _ => {
+                    if let Some(input) = &uart_input {
+                        let _ = input.send(x as u16);
+                    }
+                }
+            }
+        }
+    });
+    terminal
+}
+
+fn print_results(file: &TestFile, verbose: bool) {
+    for case in &file.cases {
+        let label = file.case_label(case);
+        match &case.result {
+            TestResult::Success => {
+                println!("Test {} passed in {} ms", label, case.duration.as_millis());
+            }
+            TestResult::Failure(failures) => {
+                println!("Test {} failed", label);
+                for failure in failures {
+                    println!("{}", failure);
+                }
+            }
+            TestResult::Error(err) => {
+                println!("Test {} failed", label);
+                println!("Error: {}", err);
+            }
+        }
+        if verbose && !case.passed() {
+            for message in case.messages.iter() {
+                println!("{}", message);
+            }
+        }
+    }
+}
+
+/// Runs the test files on `jobs` threads, calling `done` as each of them
+/// finishes. The results are in the order of `tests`.
+fn run_tests(
+    config: &str,
+    tests: Vec<String>,
+    options: &LoadOptions,
+    snapshot: Option<&SystemSnapshot>,
+    jobs: usize,
+    mut done: impl FnMut(&TestFile),
+) -> Vec<TestFile> {
+    let next = AtomicUsize::new(0);
+    let mut files: Vec<Option<TestFile>> = tests.iter().map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for _ in 0..jobs.clamp(1, tests.len()) {
+            let sender = sender.clone();
+            let (next, tests) = (&next, &tests);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(test) = tests.get(i) else {
+                    break;
+                };
+                let start = Instant::now();
+                let cases = catch_unwind(AssertUnwindSafe(|| {
+                    run_test(config, test, options, snapshot)
+                }))
+                .unwrap_or_else(|payload| {
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    vec![TestCase {
+                        name: None,
+                        result: TestResult::Error(mlua::Error::external(format!(
+                            "panicked: {}",
+                            message
+                        ))),
+                        duration: start.elapsed(),
+                        messages: vec![],
+                    }]
+                });
+                let file = TestFile {
+                    path: test.clone(),
+                    cases,
+                };
+                if sender.send((i, file)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+        for (i, file) in receiver {
+            done(&file);
+            files[i] = Some(file);
+        }
+    });
+    files.into_iter().flatten().collect()
+}
+
+/// Drops the repeated test files, which would otherwise write the same
+/// VCD files from several threads.
+fn unique_tests(tests: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    tests
+        .into_iter()
+        .filter(|test| {
+            let path = Path::new(test);
+            seen.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+        })
+        .collect()
+}
+
+/// Prints the time taken by every case, when there is more than one.
+fn print_summary(files: &[TestFile], elapsed: Duration) {
+    let cases: Vec<_> = files
+        .iter()
+        .flat_map(|file| file.cases.iter().map(move |case| (file, case)))
+        .collect();
+    if cases.len() < 2 {
+        return;
+    }
+    let failed = cases.iter().filter(|(_, case)| !case.passed()).count();
+    println!();
+    println!(
+        "{} passed, {} failed in {} ms",
+        cases.len() - failed,
+        failed,
+        elapsed.as_millis()
+    );
+    for (file, case) in cases {
+        let status = if case.passed() { "ok" } else { "FAILED" };
+        println!(
+            "{:>8} ms  {:<6}  {}",
+            case.duration.as_millis(),
+            status,
+            file.case_label(case)
+        );
+    }
 }
 
 fn main() {
     let args: Args = Args::parse();
     let config = args.config.unwrap_or("input.yaml".to_string());
+    let options = LoadOptions {
+        vcd_enabled: args.vcd,
+        vcd_compressed: args.gz,
+        firmware: args.firmware.into_iter().collect(),
+        ..LoadOptions::default()
+    };
     match args.command {
-        Commands::Test { tests } => {
+        Commands::Test {
+            tests,
+            junit,
+            tap,
+            jobs,
+        } => {
             if tests.len() == 0 {
                 println!("No tests specified");
                 return;
             }
 
-            let mut any_failed = false;
-            for test in tests {
-                match run_test(&config, &test, args.vcd, args.gz) {
-                    TestResult::Success(simulation_time) => {
-                        println!("Test {} passed in {} ms", test, simulation_time.as_millis());
-                    }
-                    TestResult::Error(err, messages) => {
-                        println!("Test {} failed", test);
-                        println!("Error: {}", err);
-                        if args.verbose {
-                            for message in messages.iter() {
-                                println!("{}", message);
-                            }
-                        }
-                        any_failed = true;
-                    }
-                    TestResult::Failure(messages) => {
-                        println!("Test {} failed", test);
-                        if args.verbose {
-                            for message in messages.iter() {
-                                println!("{}", message);
-                            }
-                        }
-                        any_failed = true;
+            let snapshot = args.snapshot.as_ref().map(|path| {
+                SystemSnapshot::load(path).unwrap_or_else(|err| {
+                    eprintln!("Couldn't load snapshot {}: {}", path, err);
+                    exit(1);
+                })
+            });
+
+            let tests = unique_tests(tests);
+            let start = Instant::now();
+            let files = run_tests(&config, tests, &options, snapshot.as_ref(), jobs, |file| {
+                if !tap {
+                    print_results(file, args.verbose);
+                }
+            });
+            if !tap {
+                print_summary(&files, start.elapsed());
+            }
+            if tap {
+                print!("{}", test_report::tap(&files));
+            }
+            if let Some(path) = &junit {
+                if let Err(err) = std::fs::write(path, test_report::junit(&files)) {
+                    eprintln!("Couldn't write {}: {}", path, err);
+                    exit(1);
+                }
+            }
+            if files
+                .iter()
+                .flat_map(|file| &file.cases)
+                .any(|case| !case.passed())
+            {
+                exit(1);
+            }
+        }
+        Commands::Validate { files } => {
+            let files = if files.is_empty() {
+                vec![config]
+            } else {
+                files
+            };
+            let mut any_invalid = false;
+            for file in files {
+                let options = LoadOptions {
+                    vcd_enabled: false,
+                    ..options.clone()
+                };
+                match load(&file, &options) {
+                    Ok(_) => println!("{} is valid", file),
+                    Err(err) => {
+                        println!("Invalid config {}:\n{}", file, err);
+                        any_invalid = true;
                     }
                 }
             }
-            if any_failed {
+            if any_invalid {
                 exit(1);
             }
         }
-        Commands::Run { duration, uart } => {
-            let mut sys = load(&config, args.vcd, args.gz);
-            let uart_module: Option<&mut UartModule> =
-                uart.and_then(|id| sys.find_module_mut(&id).as_any_mut().downcast_mut());
-            if let Some(u) = uart_module {
-                u.connect();
+        Commands::Run {
+            duration,
+            uart,
+            speed,
+            lag_policy,
+            status,
+            step_cycles,
+            save_snapshot,
+        } => {
+            let mut sys = load(&config, &options).unwrap_or_else(|err| {
+                eprintln!("Invalid config {}:\n{}", config, err);
+                exit(1);
+            });
+            if let Some(path) = &args.snapshot {
+                if let Err(err) = sys.load_snapshot(path) {
+                    eprintln!("Couldn't load snapshot {}: {}", path, err);
+                    exit(1);
+                }
             }
+            let controller = sys.controller();
 
-            const FREQ: i64 = 16_000_000;
+            let (uart_input, uart_output) = match &uart {
+                Some(id) => {
+                    let (input_sender, input_receiver) = kanal::bounded(1024);
+                    let u: &mut UartModule = sys.component_mut(id).unwrap_or_else(|| {
+                        eprintln!("Unknown UART `{}`", id);
+                        exit(1);
+                    });
+                    (Some(input_sender), Some(u.connect(input_receiver)))
+                }
+                None => (None, None),
+            };
 
-            let vcd = Arc::new(Mutex::new(Some(sys.vcd.take().unwrap().deploy())));
-            let vcd_clone = vcd.clone();
+            let vcd = sys.vcd.take().unwrap().deploy();
 
+            let ctrlc_controller = controller.clone();
+            let stopping = AtomicBool::new(false);
             ctrlc::set_handler(move || {
+                if stopping.swap(true, Ordering::SeqCst) {
+                    exit(130);
+                }
                 println!("Terminating simulation...");
-                drop(vcd_clone.lock().unwrap().take());
-                exit(0);
+                ctrlc_controller.stop();
             })
             .unwrap();
 
+            let terminal = if duration.is_none() || uart_input.is_some() {
+                eprintln!(
+                    "Ctrl-P: pause/resume, Ctrl-N: step {} cycles, Ctrl-X: stop",
+                    step_cycles
+                );
+                Some(spawn_keyboard(controller, uart_input, step_cycles))
+            } else {
+                None
+            };
+
+            let start = Instant::now();
+            let start_t = sys.t;
             if let Some(duration) = duration {
-                let start = Instant::now();
-                let model_time = duration * FREQ;
-                sys.run_for(model_time);
+                sys.run_for_controlled(duration * sys.freq, sys.freq / 60);
+            } else {
+                sys.run_realtime(RealtimeConfig {
+                    freq: sys.freq,
+                    speed,
+                    lag_policy,
+                    status_interval: status.map(Duration::from_secs_f64),
+                });
+            }
+            let simulation_time = start.elapsed();
 
-                let simulation_time = start.elapsed();
-                let model_time =
-                    Duration::from_micros((model_time as f64 / FREQ as f64 * 1e6) as u64);
+            if let Some(id) = &uart {
+                if let Some(u) = sys.component_mut::<UartModule>(id) {
+                    u.disconnect();
+                }
+            }
+            if let Some(output) = uart_output {
+                output.join().unwrap();
+            }
+            drop(terminal);
 
-                if args.verbose {
-                    let messages = sys.system_tables.messages.read().unwrap();
-                    for message in messages.iter() {
-                        println!("{}", message);
-                    }
+            if let Some(path) = &save_snapshot {
+                if let Err(err) = sys.save_snapshot(path) {
+                    eprintln!("Couldn't save snapshot {}: {}", path, err);
                 }
+            }
 
-                println!(
-                    "Model Time: {} ms, Simulation Time: {} ms, Speed: {:.2}%",
-                    model_time.as_millis(),
-                    simulation_time.as_millis(),
-                    model_time.as_nanos() as f64 / simulation_time.as_nanos() as f64 * 100.0
-                );
-            } else {
-                sys.run_realtime(FREQ);
+            if args.verbose {
+                let messages = sys.system_tables.messages.read().unwrap();
+                for message in messages.iter() {
+                    println!("{}", message);
+                }
             }
 
-            drop(vcd.lock().unwrap().take());
+            let model_time =
+                Duration::from_micros(((sys.t - start_t) as f64 / sys.freq as f64 * 1e6) as u64);
+            println!(
+                "Model Time: {} ms, Simulation Time: {} ms, Speed: {:.2}%",
+                model_time.as_millis(),
+                simulation_time.as_millis(),
+                model_time.as_nanos() as f64 / simulation_time.as_nanos() as f64 * 100.0
+            );
+
+            drop(vcd);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_tests() {
+        let tests = [
+            "Cargo.toml",
+            "missing.lua",
+            "./Cargo.toml",
+            "src/../Cargo.toml",
+            "missing.lua",
+        ];
+        let tests = unique_tests(tests.iter().map(|s| s.to_string()).collect());
+        assert_eq!(tests, ["Cargo.toml", "missing.lua"]);
+    }
+}
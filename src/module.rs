@@ -6,6 +6,7 @@ use crate::{
     module_holder::PassiveModuleStore,
     module_id::ModuleAddress,
     pin_state::WireState,
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::VcdSender,
 };
 
@@ -22,6 +23,9 @@ pub trait Module: Debug + VcdSender + Send {
     fn to_wireable_mut(&mut self) -> Option<&mut dyn WireableModule>;
 
     fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn save_state(&mut self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()>;
 }
 
 pub trait DataModule: Module {
@@ -33,10 +37,22 @@ pub trait DataModule: Module {
 pub trait WireableModule: Module {
     fn get_pin(&self, queue: &EventQueue, id: PinId) -> WireState;
     fn set_pin(&mut self, queue: &mut EventQueue, id: PinId, data: WireState);
+
+    /// Number of pins, numbered from 0.
+    fn pin_count(&self) -> usize;
+
+    /// Resolves a symbolic pin name, such as `PB5` or `RX`, to its id.
+    fn pin_by_name(&self, _name: &str) -> Option<PinId> {
+        None
+    }
 }
 
 pub trait ActiveModule: Module {
     fn run_until_time(&mut self, t: Timestamp) -> Timestamp;
+    /// Returns `None` while the module is busy, otherwise the time of its next
+    /// pending event (`Timestamp::MAX` if it has nothing left to wait for).
+    fn next_wakeup(&mut self) -> Option<Timestamp>;
     fn module_store(&mut self) -> &mut PassiveModuleStore;
     fn event_queue(&self) -> &EventQueue;
+    fn event_queue_mut(&mut self) -> &mut EventQueue;
 }
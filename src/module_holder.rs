@@ -6,7 +6,8 @@ use crate::{
     clock::Timestamp,
     events::{EventQueue, InternalEvent},
     module::{Module, WireableModule},
-    module_id::ModuleAddress,
+    module_id::{ModuleAddress, ModuleIndex},
+    snapshot::{SnapshotResult, StateReader, StateWriter},
     vcd::{VcdEvent, VcdSender, VcdSignal},
 };
 
@@ -24,15 +25,36 @@ impl PassiveModuleStore {
         }
     }
 
-    pub fn add_module<M, F>(&mut self, f: F) -> &mut dyn WireableModule
+    /// Address of the next module added, if there is room for it.
+    fn next_address(&self) -> Result<ModuleAddress, String> {
+        let index = ModuleIndex::try_from(self.modules.len())
+            .map_err(|_| "too many components".to_string())?;
+        self.module_id
+            .try_child_id(index)
+            .ok_or_else(|| "components are nested too deeply".to_string())
+    }
+
+    pub fn add_module<M, F>(&mut self, f: F) -> Result<&mut dyn WireableModule, String>
     where
         M: WireableModule + 'static,
         F: FnOnce(ModuleAddress) -> M,
     {
-        let module_id = self.module_id.child_id(self.modules.len() as u8);
-        let module = Box::new(f(module_id));
+        let module = Box::new(f(self.next_address()?));
+        self.modules.push(module);
+        Ok(self.modules.last_mut().unwrap().deref_mut())
+    }
+
+    /// Adds a module created by a fallible factory, such as the ones of a
+    /// [ComponentRegistry](crate::registry::ComponentRegistry). The module
+    /// must use the address it is given. Nothing is added if `f` fails.
+    pub fn try_add_boxed<E, F>(&mut self, f: F) -> Result<&mut dyn WireableModule, E>
+    where
+        E: From<String>,
+        F: FnOnce(ModuleAddress) -> Result<Box<dyn WireableModule>, E>,
+    {
+        let module = f(self.next_address()?)?;
         self.modules.push(module);
-        self.modules.last_mut().unwrap().deref_mut()
+        Ok(self.modules.last_mut().unwrap().deref_mut())
     }
 }
 
@@ -92,4 +114,49 @@ impl Module for PassiveModuleStore {
     fn to_wireable_mut(&mut self) -> Option<&mut dyn WireableModule> {
         None
     }
+
+    fn save_state(&mut self, state: &mut StateWriter) {
+        for m in &mut self.modules {
+            m.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> SnapshotResult<()> {
+        for m in &mut self.modules {
+            m.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::led::Led;
+
+    #[test]
+    fn no_room_left() {
+        let mut address = ModuleAddress::root();
+        for _ in 0..7 {
+            address = address.child_id(0);
+        }
+        let mut store = PassiveModuleStore::new(address);
+        assert!(store.add_module(Led::new).is_ok());
+        let mut store = PassiveModuleStore::new(address.child_id(0));
+        assert_eq!(
+            store.add_module(Led::new).err(),
+            Some("components are nested too deeply".to_string())
+        );
+
+        let mut store = PassiveModuleStore::new(ModuleAddress::root().child_id(0));
+        for _ in 0..=ModuleIndex::MAX {
+            store.add_module(Led::new).unwrap();
+        }
+        assert_eq!(
+            store
+                .try_add_boxed(|id| Ok::<_, String>(Box::new(Led::new(id))))
+                .err(),
+            Some("too many components".to_string())
+        );
+    }
 }
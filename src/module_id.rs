@@ -1,13 +1,19 @@
-use std::fmt::Display;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt::Display};
 
 use crate::module::Module;
 
-const MODULE_ID_MAX_LENGTH: usize = 6;
+const MODULE_ID_MAX_LENGTH: usize = 8;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Index of a module among the children of its parent.
+pub type ModuleIndex = u16;
+
+/// Path from the root of the system to a module. The index of the module
+/// itself comes first, so `current()` is the outermost remaining level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ModuleAddress {
     pub depth: u8,
-    pub address: [u8; MODULE_ID_MAX_LENGTH],
+    pub address: [ModuleIndex; MODULE_ID_MAX_LENGTH],
 }
 
 impl Default for ModuleAddress {
@@ -26,7 +32,7 @@ impl ModuleAddress {
     pub const fn is_empty(&self) -> bool {
         self.depth == 0
     }
-    pub const fn current(&self) -> u8 {
+    pub const fn current(&self) -> ModuleIndex {
         self.address[self.depth as usize - 1]
     }
     pub fn advance(&mut self) {
@@ -34,20 +40,46 @@ impl ModuleAddress {
             self.depth -= 1;
         }
     }
-    pub const fn child_id(self: Self, id: u8) -> ModuleAddress {
-        // Unelegant, but the only way I managed to make it work at compile-time
-        let address = [
-            id,
-            self.address[0],
-            self.address[1],
-            self.address[2],
-            self.address[3],
-            self.address[4],
-        ];
-        ModuleAddress {
+    /// Undoes [advance](Self::advance), going back to the outer level.
+    pub fn retreat(&mut self) {
+        assert!((self.depth as usize) < MODULE_ID_MAX_LENGTH);
+        self.depth += 1;
+    }
+    pub const fn child_id(self: Self, id: ModuleIndex) -> ModuleAddress {
+        match self.try_child_id(id) {
+            Some(address) => address,
+            None => panic!("Module hierarchy is too deep"),
+        }
+    }
+    /// Address of the child `id`, or `None` if the hierarchy would be
+    /// deeper than addresses can be.
+    pub const fn try_child_id(self, id: ModuleIndex) -> Option<ModuleAddress> {
+        if self.depth as usize >= MODULE_ID_MAX_LENGTH {
+            return None;
+        }
+        let mut address = [0; MODULE_ID_MAX_LENGTH];
+        address[0] = id;
+        let mut i = 1;
+        while i < MODULE_ID_MAX_LENGTH {
+            address[i] = self.address[i - 1];
+            i += 1;
+        }
+        Some(ModuleAddress {
             depth: self.depth + 1,
             address,
+        })
+    }
+    /// Address of the module containing this one.
+    pub fn parent(&self) -> Option<ModuleAddress> {
+        if self.depth == 0 {
+            return None;
         }
+        let mut address = [0; MODULE_ID_MAX_LENGTH];
+        address[..MODULE_ID_MAX_LENGTH - 1].copy_from_slice(&self.address[1..]);
+        Some(ModuleAddress {
+            depth: self.depth - 1,
+            address,
+        })
     }
     pub const fn with_event_port(self: Self, event_port_id: u8) -> EventPortAddress {
         EventPortAddress {
@@ -76,13 +108,13 @@ impl Display for ModuleAddress {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct EventPortAddress {
     pub module_address: ModuleAddress,
     pub event_port_id: u8,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PinAddress {
     pub module_address: ModuleAddress,
     pub pin_id: u8,
@@ -112,3 +144,48 @@ impl Display for PinAddress {
         write!(f, "{}!{}", self.module_address, self.pin_id)
     }
 }
+
+/// Names given to the modules in the config file, used to show addresses in
+/// diagnostics.
+#[derive(Debug, Default)]
+pub struct NameTable(HashMap<ModuleAddress, String>);
+
+impl NameTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, address: ModuleAddress, name: String) {
+        self.0.insert(address, name);
+    }
+
+    pub fn get(&self, address: ModuleAddress) -> Option<&str> {
+        self.0.get(&address).map(String::as_str)
+    }
+
+    /// Name of a module, or of the closest named module containing it
+    /// followed by the rest of the address, like `pinger/05`.
+    pub fn describe(&self, address: ModuleAddress) -> String {
+        let mut ancestor = address;
+        while let Some(parent) = ancestor.parent() {
+            if let Some(name) = self.get(ancestor) {
+                return if ancestor == address {
+                    name.to_string()
+                } else {
+                    let rest = (0..address.depth - ancestor.depth)
+                        .rev()
+                        .map(|i| format!("{:02x}", address.address[i as usize]))
+                        .collect::<Vec<_>>()
+                        .join(".");
+                    format!("{}/{}", name, rest)
+                };
+            }
+            ancestor = parent;
+        }
+        address.to_string()
+    }
+
+    pub fn describe_pin(&self, pin: PinAddress) -> String {
+        format!("{}:{}", self.describe(pin.module_address), pin.pin_id)
+    }
+}
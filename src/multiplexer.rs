@@ -1,10 +1,13 @@
 use std::collections::HashMap;
 
 use itertools::Either;
+use serde::{Deserialize, Serialize};
 
-use crate::{module_id::PinAddress, wiring::WiringTable};
+use smallvec::SmallVec;
 
-#[derive(Debug, Clone)]
+use crate::module_id::PinAddress;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Multiplexer {
     wireable_pin: PinAddress,
     connections: Vec<PinAddress>,
@@ -12,7 +15,7 @@ struct Multiplexer {
     active_position: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MultiplexingTable {
     incoming_event_table: HashMap<PinAddress, Vec<PinAddress>>,
     multiplexer_table: HashMap<PinAddress, usize>,
@@ -72,36 +75,35 @@ impl MultiplexingTable {
         }
     }
 
-    pub fn outgoing_event_listeners<'b>(
-        &'b self,
-        wt: &'b WiringTable,
+    /// Routes a value written to `addr`. Returns the pin of the module that
+    /// carries it to the outside, along with the other internal pins that are
+    /// connected to it, or `None` if `addr` isn't currently connected.
+    pub fn route_output(
+        &self,
         addr: PinAddress,
-    ) -> impl Iterator<Item = PinAddress> + 'b {
+    ) -> Option<(PinAddress, SmallVec<[PinAddress; 2]>)> {
         if let Some(&multiplexer_id) = self.multiplexer_table.get(&addr) {
             let m = &self.multiplexers[multiplexer_id];
             let position = m.connections.iter().position(|&a| a == addr).unwrap();
-            if m.active_position == position {
-                Either::Left(Either::Left(
-                    m.connections
-                        .iter()
-                        .copied()
-                        .filter(move |&a| a != addr)
-                        .chain(wt.get_connected(m.wireable_pin).map_or_else(
-                            || Either::Right(std::iter::empty()),
-                            |v| Either::Left(v.iter().copied()),
-                        )),
-                ))
-            } else {
-                Either::Left(Either::Right(std::iter::empty()))
+            if m.active_position != position {
+                return None;
             }
+            let internal = m
+                .connections
+                .iter()
+                .copied()
+                .filter(|&a| a != addr)
+                .collect();
+            Some((m.wireable_pin, internal))
         } else {
-            Either::Right(wt.get_connected(addr).map_or_else(
-                || Either::Right(std::iter::empty()),
-                |v| Either::Left(v.iter().copied()),
-            ))
+            Some((addr, SmallVec::new()))
         }
     }
 
+    pub fn is_registered(&self, addr: PinAddress) -> bool {
+        self.multiplexer_table.contains_key(&addr)
+    }
+
     pub fn read_pin_addr(&self, addr: PinAddress) -> PinAddress {
         if let Some(&multiplexer_id) = self.multiplexer_table.get(&addr) {
             let m = &self.multiplexers[multiplexer_id];
@@ -1,105 +1,665 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
-use yaml_rust2::{Yaml, YamlLoader};
+use yaml_rust2::Yaml;
 
 use crate::{
+    builder::{connect_buses, connect_net, new_mcu},
+    clock::FREQ,
     components::{
         avr::mcu,
         led::Led,
+        lua_component::{self, LuaComponent},
         uart_module::{ParityMode, UartConfig, UartModule},
     },
-    events::EventQueue,
-    module::ActiveModule,
-    module_id::ModuleAddress,
+    module::{ActiveModule, WireableModule},
+    module_id::{ModuleAddress, ModuleIndex, PinAddress},
+    registry::{ComponentRegistry, BUILTIN_TYPES},
     system::{find_pin_addr, System},
     system_tables::SystemTables,
     vcd::VcdReceiver,
 };
 
+mod templates;
+mod toml;
+
+/// Version of the config format described by `schema/config.schema.json`.
+/// Files without a `version` key are assumed to use this one.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Settings of [load] that don't come from the config file.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    pub vcd_enabled: bool,
+    pub vcd_compressed: bool,
+    /// File of the VCD output, instead of `out.vcd` or `out.vcd.gz` next to
+    /// the config file.
+    pub vcd_file: Option<PathBuf>,
+    /// Firmware to load into the named MCUs instead of their `memory`.
+    pub firmware: HashMap<String, PathBuf>,
+    /// Component types available besides the built-in ones.
+    pub components: ComponentRegistry,
+}
+
+/// Problem found in the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Location of the problem, for example `components.pinger.components.uart.parity`.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Every problem found in a config file.
+#[derive(Debug, Clone)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Collects the problems found while loading, so that all of them can be
+/// reported at once.
+#[derive(Debug, Default)]
+struct Diagnostics {
+    errors: Vec<ConfigError>,
+    /// Components that failed to load. Wires to them aren't reported again.
+    invalid: HashSet<String>,
+}
+
+impl Diagnostics {
+    fn error(&mut self, path: &str, message: impl Into<String>) {
+        self.errors.push(ConfigError {
+            path: path.to_string(),
+            message: message.into(),
+        });
+    }
+
+    /// Reports the keys of a mapping that aren't in `allowed`. Returns `false`
+    /// if the node isn't a mapping at all.
+    fn check_keys(&mut self, node: &Yaml, path: &str, allowed: &[&str]) -> bool {
+        let Some(hash) = node.as_hash() else {
+            self.error(path, "expected a mapping");
+            return false;
+        };
+        for key in hash.keys() {
+            match key.as_str() {
+                Some(key) if allowed.contains(&key) => {}
+                Some(key) => self.error(
+                    &child_path(path, key),
+                    format!("unknown key, expected one of: {}", allowed.join(", ")),
+                ),
+                None => self.error(path, format!("invalid key {:?}", key)),
+            }
+        }
+        true
+    }
+
+    fn required_str<'y>(&mut self, node: &'y Yaml, path: &str, key: &str) -> Option<&'y str> {
+        match &node[key] {
+            Yaml::BadValue => {
+                self.error(path, format!("missing required key `{}`", key));
+                None
+            }
+            value => self.optional_str(value, &child_path(path, key)),
+        }
+    }
+
+    fn optional_str<'y>(&mut self, value: &'y Yaml, path: &str) -> Option<&'y str> {
+        match value {
+            Yaml::BadValue => None,
+            Yaml::String(s) => Some(s),
+            _ => {
+                self.error(path, "expected a string");
+                None
+            }
+        }
+    }
+
+    fn optional_bool(&mut self, value: &Yaml, path: &str, default: bool) -> bool {
+        match value {
+            Yaml::BadValue => default,
+            Yaml::Boolean(b) => *b,
+            _ => {
+                self.error(path, "expected true or false");
+                default
+            }
+        }
+    }
+
+    fn optional_int(&mut self, value: &Yaml, path: &str, default: i64) -> i64 {
+        match value {
+            Yaml::BadValue => default,
+            Yaml::Integer(i) => *i,
+            _ => {
+                self.error(path, "expected an integer");
+                default
+            }
+        }
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Replaces `$NAME` and `${NAME}` with the value of the environment variable
+/// `NAME`. `$$` stands for a single `$`.
+fn expand_env(s: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let name = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable in `{}`", s))?;
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+            continue;
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        if name.is_empty() {
+            return Err(format!("missing variable name after `$` in `{}`", s));
+        }
+        let value = std::env::var(name)
+            .map_err(|_| format!("environment variable `{}` isn't set", name))?;
+        result.push_str(&value);
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Expands the environment variables in a path of the config file and makes
+/// it relative to `base_dir`, the directory of the file.
+pub(crate) fn resolve_path(base_dir: &Path, path: &str) -> Result<PathBuf, String> {
+    Ok(base_dir.join(expand_env(path)?))
+}
+
+fn parse_uart_config(component: &Yaml, path: &str, diag: &mut Diagnostics) -> UartConfig {
+    let parity_path = child_path(path, "parity");
+    let parity = match diag.optional_str(&component["parity"], &parity_path) {
+        Some("even") | None => ParityMode::Even,
+        Some("odd") => ParityMode::Odd,
+        Some("none") => ParityMode::Disabled,
+        Some(other) => {
+            diag.error(
+                &parity_path,
+                format!("invalid parity `{}`, expected even, odd or none", other),
+            );
+            ParityMode::Even
+        }
+    };
+
+    let char_size_path = child_path(path, "char_size");
+    let mut char_size = diag.optional_int(&component["char_size"], &char_size_path, 8);
+    if !(5..=9).contains(&char_size) {
+        diag.error(&char_size_path, "character size must be between 5 and 9");
+        char_size = 8;
+    }
+
+    UartConfig {
+        parity,
+        double_stop_bit: diag.optional_bool(
+            &component["double_stop_bit"],
+            &child_path(path, "double_stop_bit"),
+            false,
+        ),
+        char_size: char_size as u8,
+        polarity: diag.optional_bool(
+            &component["invert_polarity"],
+            &child_path(path, "invert_polarity"),
+            false,
+        ),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn parse_passive_component(
     parent: &mut dyn ActiveModule,
     component: &Yaml,
+    path: &str,
     parent_name: &str,
     id: &str,
     id_map: &mut HashMap<String, ModuleAddress>,
     vcd: &mut VcdReceiver,
-    vcd_enabled: bool,
+    options: &LoadOptions,
+    base_dir: &Path,
+    diag: &mut Diagnostics,
 ) {
-    let module = match component["type"].as_str().unwrap() {
-        "led" => parent.module_store().add_module(|id| Led::new(id)),
+    let name = format!("{}.{}", parent_name, id);
+    if component.as_hash().is_none() {
+        diag.error(path, "expected a mapping");
+        diag.invalid.insert(name);
+        return;
+    }
+    let Some(component_type) = diag.required_str(component, path, "type") else {
+        diag.invalid.insert(name);
+        return;
+    };
+    let module = match component_type {
+        "led" => {
+            diag.check_keys(component, path, &["type", "vcd"]);
+            parent
+                .module_store()
+                .add_module(|id| Led::new(id))
+                .map_err(|err| (path.to_string(), err))
+        }
         "uart" => {
-            let config = UartConfig {
-                parity: match component["parity"].as_str() {
-                    Some("even") => ParityMode::Even,
-                    Some("odd") => ParityMode::Odd,
-                    Some("none") => ParityMode::Disabled,
-                    Some(_) => panic!("Invalid parity!"),
-                    None => ParityMode::Even,
-                },
-                double_stop_bit: component["double_stop_bit"].as_bool().unwrap_or(false),
-                char_size: component["char_size"].as_i64().unwrap_or(8) as u8,
-                polarity: component["invert_polarity"].as_bool().unwrap_or(false),
-            };
+            diag.check_keys(
+                component,
+                path,
+                &[
+                    "type",
+                    "vcd",
+                    "parity",
+                    "double_stop_bit",
+                    "char_size",
+                    "invert_polarity",
+                ],
+            );
+            let config = parse_uart_config(component, path, diag);
             parent
                 .module_store()
                 .add_module(|id| UartModule::new(id, config))
+                .map_err(|err| (path.to_string(), err))
+        }
+        "lua" => {
+            diag.check_keys(
+                component,
+                path,
+                &["type", "vcd", "script", "pins", "params"],
+            );
+            let pins = parse_pin_names(component, path, diag);
+            let script_path = child_path(path, "script");
+            let Some((script, source)) = read_script(component, path, base_dir, diag) else {
+                diag.invalid.insert(name);
+                return;
+            };
+            let script = script.display().to_string();
+            parent
+                .module_store()
+                .try_add_boxed(|id| {
+                    let module =
+                        LuaComponent::new(id, &name, pins, &source, &script, &component["params"])?;
+                    Ok::<Box<dyn WireableModule>, String>(Box::new(module))
+                })
+                .map_err(|err| (script_path, err))
+        }
+        other => {
+            let Some(factory) = options.components.get(other) else {
+                let types = [
+                    BUILTIN_TYPES.as_slice(),
+                    options.components.types().as_slice(),
+                ]
+                .concat();
+                diag.error(
+                    &child_path(path, "type"),
+                    format!(
+                        "unknown component type `{}`, expected one of: {}",
+                        other,
+                        types.join(", ")
+                    ),
+                );
+                diag.invalid.insert(name);
+                return;
+            };
+            parent
+                .module_store()
+                .try_add_boxed(|id| factory(id, component))
+                .map_err(|err| (path.to_string(), err))
+        }
+    };
+    let module = match module {
+        Ok(module) => module,
+        Err((path, err)) => {
+            diag.error(&path, err);
+            diag.invalid.insert(name);
+            return;
         }
-        _ => unimplemented!(),
     };
-    let name = format!("{}.{}", parent_name, id);
 
-    if vcd_enabled && component["vcd"].as_bool().unwrap_or(false) {
+    if diag.optional_bool(&component["vcd"], &child_path(path, "vcd"), false) && options.vcd_enabled
+    {
         vcd.register(module, &name);
     }
-    id_map.insert(name, module.address());
+    let address = module.address();
+    id_map.insert(name, address);
+    if component_type == "lua" {
+        LuaComponent::start(address, parent.event_queue_mut());
+    }
 }
 
+/// Reads the `script` of a component, relative to `base_dir`. Returns its
+/// path and source.
+fn read_script(
+    component: &Yaml,
+    path: &str,
+    base_dir: &Path,
+    diag: &mut Diagnostics,
+) -> Option<(PathBuf, String)> {
+    let script_path = child_path(path, "script");
+    let script = diag.required_str(component, path, "script")?;
+    let script = match resolve_path(base_dir, script) {
+        Ok(script) => script,
+        Err(err) => {
+            diag.error(&script_path, err);
+            return None;
+        }
+    };
+    match std::fs::read_to_string(&script) {
+        Ok(source) => Some((script, source)),
+        Err(err) => {
+            diag.error(
+                &script_path,
+                format!("couldn't read `{}`: {}", script.display(), err),
+            );
+            None
+        }
+    }
+}
+
+fn parse_pin_names(component: &Yaml, path: &str, diag: &mut Diagnostics) -> Vec<String> {
+    let pins_path = child_path(path, "pins");
+    match &component["pins"] {
+        Yaml::BadValue => {
+            diag.error(path, "missing required key `pins`");
+            Vec::new()
+        }
+        Yaml::Array(pins) if pins.len() > lua_component::MAX_PINS => {
+            diag.error(
+                &pins_path,
+                format!(
+                    "too many pins ({}), at most {}",
+                    pins.len(),
+                    lua_component::MAX_PINS
+                ),
+            );
+            Vec::new()
+        }
+        Yaml::Array(pins) => pins
+            .iter()
+            .enumerate()
+            .filter_map(|(i, pin)| diag.optional_str(pin, &format!("{}[{}]", pins_path, i)))
+            .map(str::to_string)
+            .collect(),
+        _ => {
+            diag.error(&pins_path, "expected a list of pin names");
+            Vec::new()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn parse_active_component<'a>(
-    root_prefix: u8,
+    root_prefix: ModuleIndex,
     component: &Yaml,
+    path: &str,
     id: &str,
     system_tables: SystemTables,
     id_map: &mut HashMap<String, ModuleAddress>,
     vcd: &mut VcdReceiver,
-    vcd_enabled: bool,
-) -> Box<dyn ActiveModule + 'a> {
-    let recv = system_tables
-        .inbox
-        .write()
-        .unwrap()
-        .add_listener(root_prefix);
-    let event_queue = EventQueue::new(system_tables, 1, root_prefix, recv);
+    options: &LoadOptions,
+    base_dir: &Path,
+    diag: &mut Diagnostics,
+) -> Option<Box<dyn ActiveModule + 'a>> {
+    if component.as_hash().is_none() {
+        diag.error(path, "expected a mapping");
+        diag.invalid.insert(id.to_string());
+        return None;
+    }
+    let Some(component_type) = diag.required_str(component, path, "type") else {
+        diag.invalid.insert(id.to_string());
+        return None;
+    };
+    if component_type != "mcu" {
+        diag.error(
+            &child_path(path, "type"),
+            format!("unknown component type `{}`, expected mcu", component_type),
+        );
+        diag.invalid.insert(id.to_string());
+        return None;
+    }
+    diag.check_keys(
+        component,
+        path,
+        &[
+            "type",
+            "device",
+            "frequency",
+            "memory",
+            "symbols",
+            "components",
+            "vcd",
+        ],
+    );
+    let device_path = child_path(path, "device");
+    if let Some(device) = diag.optional_str(&component["device"], &device_path) {
+        if device != mcu::DEVICE {
+            diag.error(
+                &device_path,
+                format!("unsupported device `{}`, expected {}", device, mcu::DEVICE),
+            );
+        }
+    }
+
+    let mut mcu = new_mcu(system_tables, root_prefix);
+    let memory_path = child_path(path, "memory");
+    let memory = match options.firmware.get(id) {
+        // The memory given in the config file is still checked.
+        Some(firmware) => {
+            diag.optional_str(&component["memory"], &memory_path);
+            Some(firmware.clone())
+        }
+        None => diag
+            .required_str(component, path, "memory")
+            .and_then(|memory| match resolve_path(base_dir, memory) {
+                Ok(memory) => Some(memory),
+                Err(err) => {
+                    diag.error(&memory_path, err);
+                    None
+                }
+            }),
+    };
+    if let Some(memory) = memory {
+        if let Err(err) = mcu.load_flash_hex(&memory) {
+            diag.error(
+                &memory_path,
+                format!("couldn't load `{}`: {}", memory.display(), err),
+            );
+        }
+    }
+    let symbols_path = child_path(path, "symbols");
+    if let Some(symbols) = diag.optional_str(&component["symbols"], &symbols_path) {
+        let result = resolve_path(base_dir, symbols).and_then(|symbols| {
+            mcu.load_symbols(&symbols)
+                .map_err(|err| format!("couldn't load `{}`: {}", symbols.display(), err))
+        });
+        if let Err(err) = result {
+            diag.error(&symbols_path, err);
+        }
+    }
 
     id_map.insert(id.to_string(), ModuleAddress::root().child_id(root_prefix));
-    let mut c = match component["type"].as_str().unwrap() {
-        "mcu" => {
-            let memory = component["memory"].as_str().unwrap();
-            let mut mcu = mcu::Mcu::new(event_queue).with_flash_hex(memory);
-            for (name, sub_component) in component["components"].as_hash().unwrap() {
+    let components_path = child_path(path, "components");
+    match &component["components"] {
+        Yaml::BadValue => {}
+        Yaml::Hash(components) => {
+            for (name, sub_component) in components {
+                let Some(name) = name.as_str() else {
+                    diag.error(&components_path, format!("invalid name {:?}", name));
+                    continue;
+                };
                 parse_passive_component(
                     &mut mcu,
                     sub_component,
+                    &child_path(&components_path, name),
                     id,
-                    name.as_str().unwrap(),
+                    name,
                     id_map,
                     vcd,
-                    vcd_enabled,
+                    options,
+                    base_dir,
+                    diag,
                 );
             }
-            Box::new(mcu)
         }
-        _ => unimplemented!(),
-    };
+        _ => diag.error(&components_path, "expected a mapping"),
+    }
 
-    if vcd_enabled && component["vcd"].as_bool().unwrap_or(false) {
+    let mut c: Box<dyn ActiveModule> = Box::new(mcu);
+    if diag.optional_bool(&component["vcd"], &child_path(path, "vcd"), false) && options.vcd_enabled
+    {
         vcd.register(c.as_mut(), id);
     }
-    c
+    Some(c)
 }
 
-pub fn load(path: &str, vcd_enabled: bool, vcd_compressed: bool) -> System {
-    let yaml = std::fs::read_to_string(path).unwrap();
-    let data = &YamlLoader::load_from_str(&yaml).unwrap()[0];
+/// Expands a range like `PA[0..7]` into the names it stands for, along with
+/// their index. Ranges are inclusive and may go down (`D[7..0]`). A name
+/// without a range stands for itself.
+fn expand_range(name: &str) -> Result<Vec<(String, Option<u32>)>, String> {
+    let Some((prefix, rest)) = name.split_once('[') else {
+        return Ok(vec![(name.to_string(), None)]);
+    };
+    let (range, suffix) = rest
+        .split_once(']')
+        .ok_or_else(|| format!("unterminated range in `{}`", name))?;
+    let invalid = || format!("invalid range `[{}]`, expected `[first..last]`", range);
+    let (first, last) = range.split_once("..").ok_or_else(invalid)?;
+    let first: u32 = first.trim().parse().map_err(|_| invalid())?;
+    let last: u32 = last.trim().parse().map_err(|_| invalid())?;
+    let indices: Vec<u32> = if first <= last {
+        (first..=last).collect()
+    } else {
+        (last..=first).rev().collect()
+    };
+    Ok(indices
+        .into_iter()
+        .map(|i| (format!("{}{}{}", prefix, i, suffix), Some(i)))
+        .collect())
+}
+
+/// Expands a bus like `mcu:PA[0..7]` into the names of its pins. A range in
+/// the component part, like `node[1..4]:TXD0`, takes the pin of every one of
+/// the components. A single pin is a bus of width one.
+pub(crate) fn expand_bus(name: &str) -> Result<Vec<String>, String> {
+    let Some((component, pin)) = name.split_once(':') else {
+        return Ok(vec![name.to_string()]);
+    };
+    let pins = expand_range(pin)?;
+    let mut names = Vec::new();
+    for (component, _) in expand_range(component)? {
+        names.extend(pins.iter().map(|(pin, _)| format!("{}:{}", component, pin)));
+    }
+    Ok(names)
+}
+
+/// Splits a name with a range in its component part into one name per
+/// component: `node[1..2]:TXD0` gives `node1:TXD0` and `node2:TXD0`.
+fn split_components(name: &str) -> Result<Vec<String>, String> {
+    match name.split_once(':') {
+        Some((component, pin)) => Ok(expand_range(component)?
+            .into_iter()
+            .map(|(component, _)| format!("{}:{}", component, pin))
+            .collect()),
+        None => Ok(vec![name.to_string()]),
+    }
+}
+
+/// Resolves a pin or a bus of pins, reporting the problems at `path`.
+fn resolve_bus(
+    name: &str,
+    path: &str,
+    id_map: &HashMap<String, ModuleAddress>,
+    components: &[Box<dyn ActiveModule>],
+    diag: &mut Diagnostics,
+) -> Option<Vec<PinAddress>> {
+    let pins = match expand_bus(name) {
+        Ok(pins) => pins,
+        Err(err) => {
+            diag.error(path, err);
+            return None;
+        }
+    };
+    let mut addrs = Vec::with_capacity(pins.len());
+    for pin in pins {
+        match find_pin_addr(&pin, id_map, components) {
+            Ok(addr) => addrs.push(addr),
+            Err(err) => {
+                let component = pin.split(':').next().unwrap_or_default();
+                let root = component.split('.').next().unwrap_or_default();
+                if !diag.invalid.contains(component) && !diag.invalid.contains(root) {
+                    diag.error(path, err);
+                }
+                return None;
+            }
+        }
+    }
+    Some(addrs)
+}
+
+/// Loads a config file. Paths in it are relative to its directory.
+pub fn load(path: &str, options: &LoadOptions) -> Result<System, ConfigErrors> {
+    let mut diag = Diagnostics::default();
+    let base_dir = Path::new(path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+    let data = templates::load_document(Path::new(path), &mut diag).map_err(|message| {
+        ConfigErrors(vec![ConfigError {
+            path: String::new(),
+            message,
+        }])
+    })?;
+    let data = &data;
+    diag.check_keys(data, "", &["version", "components", "wires", "nets"]);
+    let version = diag.optional_int(&data["version"], "version", CONFIG_VERSION);
+    if version != CONFIG_VERSION {
+        diag.error(
+            "version",
+            format!(
+                "unsupported config version {}, expected {}",
+                version, CONFIG_VERSION
+            ),
+        );
+    }
 
     let system_tables = SystemTables::new();
 
@@ -107,46 +667,517 @@ pub fn load(path: &str, vcd_enabled: bool, vcd_compressed: bool) -> System {
 
     let mut components = vec![];
 
-    let mut vcd = if vcd_enabled {
-        VcdReceiver::new(16_000_000, vcd_compressed)
+    let freq = system_frequency(&data["components"], &mut diag);
+    let mut vcd = if options.vcd_enabled {
+        match &options.vcd_file {
+            Some(file) => VcdReceiver::with_file(file, freq, options.vcd_compressed),
+            None => VcdReceiver::new(&base_dir, freq, options.vcd_compressed),
+        }
     } else {
         VcdReceiver::new_dummy()
     };
 
-    let mut root_prefix = 0;
-    for (id, component) in data["components"].as_hash().unwrap() {
-        let c = parse_active_component(
-            root_prefix,
-            component,
-            id.as_str().unwrap(),
-            system_tables.clone(),
-            &mut id_map,
-            &mut vcd,
-            vcd_enabled,
-        );
-        components.push(c);
-        root_prefix += 1;
+    match &data["components"] {
+        Yaml::Hash(hash) => {
+            for (id, component) in hash {
+                let Some(id) = id.as_str() else {
+                    diag.error("components", format!("invalid name {:?}", id));
+                    continue;
+                };
+                let Ok(root_prefix) = ModuleIndex::try_from(components.len()) else {
+                    diag.error("components", "too many components");
+                    break;
+                };
+                let c = parse_active_component(
+                    root_prefix,
+                    component,
+                    &child_path("components", id),
+                    id,
+                    system_tables.clone(),
+                    &mut id_map,
+                    &mut vcd,
+                    options,
+                    &base_dir,
+                    &mut diag,
+                );
+                if let Some(c) = c {
+                    components.push(c);
+                }
+            }
+        }
+        Yaml::BadValue => diag.error("", "missing required key `components`"),
+        _ => diag.error("components", "expected a mapping"),
+    }
+    let mut overridden: Vec<_> = options.firmware.keys().collect();
+    overridden.sort();
+    for id in overridden {
+        if data["components"][id.as_str()].as_hash().is_none() {
+            diag.error("", format!("firmware given for unknown component `{}`", id));
+        }
     }
 
-    for wire in data["wires"].as_vec().unwrap() {
-        let from_name = wire["from"].as_str().unwrap();
-        let to_name = wire["to"].as_str().unwrap();
+    let mut wiring = system_tables.wiring.write().unwrap();
+    match &data["wires"] {
+        Yaml::Array(wires) => {
+            for (i, wire) in wires.iter().enumerate() {
+                let path = format!("wires[{}]", i);
+                let (from, to, from_path, to_path) = if let Some(wire) = wire.as_str() {
+                    let Some((from, to)) = wire.split_once("->") else {
+                        diag.error(&path, "expected `from -> to`");
+                        continue;
+                    };
+                    (from.trim(), to.trim(), path.clone(), path.clone())
+                } else {
+                    if !diag.check_keys(wire, &path, &["from", "to"]) {
+                        continue;
+                    }
+                    let from = diag.required_str(wire, &path, "from");
+                    let to = diag.required_str(wire, &path, "to");
+                    let (Some(from), Some(to)) = (from, to) else {
+                        continue;
+                    };
+                    (from, to, child_path(&path, "from"), child_path(&path, "to"))
+                };
+                let from = resolve_bus(from, &from_path, &id_map, &components, &mut diag);
+                let to = resolve_bus(to, &to_path, &id_map, &components, &mut diag);
+                let (Some(from), Some(to)) = (from, to) else {
+                    continue;
+                };
+                if let Err(err) = connect_buses(&mut wiring, &path, &from, &to) {
+                    diag.error(&path, err);
+                }
+            }
+        }
+        Yaml::BadValue => {}
+        _ => diag.error("wires", "expected a list"),
+    }
+
+    match &data["nets"] {
+        Yaml::Hash(nets) => {
+            for (name, pins) in nets {
+                let Some(name) = name.as_str() else {
+                    diag.error("nets", format!("invalid name {:?}", name));
+                    continue;
+                };
+                let path = child_path("nets", name);
+                let Some(pins) = pins.as_vec() else {
+                    diag.error(&path, "expected a list of pins");
+                    continue;
+                };
+                let mut buses = Vec::new();
+                for (i, pin) in pins.iter().enumerate() {
+                    let pin_path = format!("{}[{}]", path, i);
+                    let Some(pin) = diag.optional_str(pin, &pin_path) else {
+                        continue;
+                    };
+                    let pins = match split_components(pin) {
+                        Ok(pins) => pins,
+                        Err(err) => {
+                            diag.error(&pin_path, err);
+                            continue;
+                        }
+                    };
+                    for pin in pins {
+                        if let Some(bus) =
+                            resolve_bus(&pin, &pin_path, &id_map, &components, &mut diag)
+                        {
+                            buses.push(bus);
+                        }
+                    }
+                }
+                if let Err(err) = connect_net(&mut wiring, name, &buses) {
+                    diag.error(&path, err);
+                }
+            }
+        }
+        Yaml::BadValue => {}
+        _ => diag.error("nets", "expected a mapping"),
+    }
+    drop(wiring);
 
-        let from = find_pin_addr(from_name, &id_map, &components);
-        let to = find_pin_addr(to_name, &id_map, &components);
-        system_tables
-            .wiring
-            .write()
-            .unwrap()
-            .add_wire(from, vec![to])
+    if !diag.errors.is_empty() {
+        return Err(ConfigErrors(diag.errors));
     }
 
-    System {
+    Ok(System::new(
         system_tables,
-        modules: components,
+        components,
         id_map,
-        vcd_sender: vcd.sender.clone(),
-        vcd: Some(vcd),
-        t: 0,
+        vcd,
+        base_dir,
+        freq,
+    ))
+}
+
+/// Frequency in Hz, given as a number, like `16000000` or `16e6`, or a
+/// string with a unit, like `16MHz`.
+fn parse_frequency(value: &Yaml) -> Result<i64, String> {
+    let freq = match value {
+        Yaml::Integer(freq) => *freq as f64,
+        Yaml::Real(freq) => value
+            .as_f64()
+            .ok_or_else(|| format!("invalid frequency `{}`", freq))?,
+        Yaml::String(freq) => {
+            let freq = freq.trim();
+            // The unit is the trailing letters, so that the `e` of an
+            // exponent stays in the number.
+            let split = freq
+                .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+                .len();
+            let (number, unit) = freq.split_at(split);
+            let scale = match unit.to_ascii_lowercase().as_str() {
+                "" | "hz" => 1.0,
+                "khz" => 1e3,
+                "mhz" => 1e6,
+                _ => return Err(format!("unknown unit `{}`, expected Hz, kHz or MHz", unit)),
+            };
+            let number: f64 = number
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid frequency `{}`", freq))?;
+            number * scale
+        }
+        _ => return Err("expected a frequency, like 16000000 or \"16MHz\"".to_string()),
+    };
+    if !freq.is_finite() {
+        return Err(format!("invalid frequency {}", freq));
+    }
+    if freq <= 0.0 {
+        return Err("the frequency must be positive".to_string());
+    }
+    if freq.fract() != 0.0 {
+        return Err(format!("frequency {} is not a whole number of Hz", freq));
+    }
+    Ok(freq as i64)
+}
+
+/// Frequency of the MCUs, which all run on the same clock. The ones without a
+/// `frequency` run at the default [FREQ].
+///
+/// Time is counted in cycles of that single clock, so MCUs at different
+/// frequencies can't be simulated together: every MCU whose frequency differs
+/// from the first one is reported as an error.
+fn system_frequency(components: &Yaml, diag: &mut Diagnostics) -> i64 {
+    let mut first: Option<(&str, i64)> = None;
+    for (id, component) in components.as_hash().into_iter().flatten() {
+        let Some(id) = id.as_str() else {
+            continue;
+        };
+        let path = child_path(&child_path("components", id), "frequency");
+        let freq = match &component["frequency"] {
+            Yaml::BadValue => FREQ,
+            value => match parse_frequency(value) {
+                Ok(freq) => freq,
+                Err(err) => {
+                    diag.error(&path, err);
+                    continue;
+                }
+            },
+        };
+        match first {
+            None => first = Some((id, freq)),
+            Some((first_id, first_freq)) if first_freq != freq => diag.error(
+                &path,
+                format!(
+                    "all MCUs must run at the same frequency, `{}` runs at {} Hz",
+                    first_id, first_freq
+                ),
+            ),
+            Some(_) => {}
+        }
+    }
+    first.map_or(FREQ, |(_, freq)| freq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, FIRMWARE};
+
+    fn load_errors(config: &str) -> Vec<String> {
+        let dir = TempDir::new();
+        let path = dir.write("config.yaml", config);
+        match load(path.to_str().unwrap(), &LoadOptions::default()) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors.0.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// Config of an MCU with the given components, indented to be under it.
+    fn mcu_with(components: &str) -> String {
+        format!(
+            "components:\n  mcu:\n    type: mcu\n    memory: {}\n    components:\n{}",
+            FIRMWARE, components
+        )
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let led = "      led:\n        type: led\n        color: red\n";
+        assert_eq!(
+            load_errors(&mcu_with(led)),
+            ["components.mcu.components.led.color: unknown key, expected one of: type, vcd"]
+        );
+        let config = mcu_with("      led:\n        type: led\n") + "wire: []\n";
+        assert_eq!(
+            load_errors(&config),
+            ["wire: unknown key, expected one of: version, components, wires, nets"]
+        );
+    }
+
+    #[test]
+    fn missing_keys() {
+        assert_eq!(
+            load_errors("components:\n  mcu:\n    type: mcu\n"),
+            ["components.mcu: missing required key `memory`"]
+        );
+        let config = mcu_with("      led:\n        type: led\n") + "wires:\n  - from: mcu:PB7\n";
+        assert_eq!(
+            load_errors(&config),
+            ["wires[0]: missing required key `to`"]
+        );
+        assert_eq!(
+            load_errors("wires: []\n"),
+            ["missing required key `components`"]
+        );
+
+        let script = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/examples/lua_divider/divider.lua"
+        );
+        let lua = |pins: &str| {
+            mcu_with(&format!(
+                "      div:\n        type: lua\n        script: {}\n{}",
+                script, pins
+            ))
+        };
+        assert_eq!(
+            load_errors(&lua("")),
+            ["components.mcu.components.div: missing required key `pins`"]
+        );
+        let pins: Vec<String> = (0..257).map(|i| format!("p{}", i)).collect();
+        assert_eq!(
+            load_errors(&lua(&format!("        pins: [{}]\n", pins.join(", ")))),
+            ["components.mcu.components.div.pins: too many pins (257), at most 256"]
+        );
+        assert_eq!(
+            load_errors(&lua(&format!(
+                "        pins: [{}]\n",
+                pins[..256].join(", ")
+            ))),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn pin_out_of_range() {
+        let config = mcu_with("      led:\n        type: led\n")
+            + "wires:\n  - from: mcu:86\n    to: mcu.led:0\n";
+        assert_eq!(
+            load_errors(&config),
+            ["wires[0].from: pin 86 out of range, `mcu` has 86 pins"]
+        );
+    }
+
+    #[test]
+    fn wrong_types() {
+        let uart = "      uart:\n        type: uart\n        parity: 1\n        \
+                    char_size: eight\n        vcd: yes\n";
+        assert_eq!(
+            load_errors(&mcu_with(uart)),
+            [
+                "components.mcu.components.uart.parity: expected a string",
+                "components.mcu.components.uart.char_size: expected an integer",
+                "components.mcu.components.uart.vcd: expected true or false",
+            ]
+        );
+        assert_eq!(
+            load_errors(&mcu_with("      []\n")),
+            ["components.mcu.components: expected a mapping"]
+        );
+    }
+
+    #[test]
+    fn bus_names() {
+        assert_eq!(
+            expand_bus("mcu:PA[0..2]"),
+            Ok(vec!["mcu:PA0".into(), "mcu:PA1".into(), "mcu:PA2".into()])
+        );
+        assert_eq!(
+            expand_bus("mcu:D[9..8]x"),
+            Ok(vec!["mcu:D9x".into(), "mcu:D8x".into()])
+        );
+        assert_eq!(
+            expand_bus("node[1..2]:TXD0"),
+            Ok(vec!["node1:TXD0".into(), "node2:TXD0".into()])
+        );
+        assert_eq!(expand_bus("mcu:PB0"), Ok(vec!["mcu:PB0".into()]));
+        assert_eq!(
+            expand_bus("mcu:PA[0..x]"),
+            Err("invalid range `[0..x]`, expected `[first..last]`".into())
+        );
+        assert_eq!(
+            expand_bus("mcu:PA[0-7]"),
+            Err("invalid range `[0-7]`, expected `[first..last]`".into())
+        );
+        assert_eq!(
+            expand_bus("mcu:PA[0..7"),
+            Err("unterminated range in `PA[0..7`".into())
+        );
+    }
+
+    #[test]
+    fn bus_wires() {
+        let dir = TempDir::new();
+        let config = mcu_with("      led:\n        type: led\n")
+            + "wires:\n  - mcu:PB[0..3] -> mcu:PC[3..0]\n  - mcu:PD0 -> mcu:PA[0..1]\n";
+        let path = dir.write("config.yaml", &config);
+        let sys = load(path.to_str().unwrap(), &LoadOptions::default()).unwrap();
+        let wiring = sys.system_tables.wiring.read().unwrap();
+        let net = |pin: &str| wiring.net_of(sys.pin_address(pin).unwrap()).unwrap();
+        assert_eq!(net("mcu:PB0"), net("mcu:PC3"));
+        assert_eq!(net("mcu:PB3"), net("mcu:PC0"));
+        assert_ne!(net("mcu:PB0"), net("mcu:PB1"));
+        assert_eq!(wiring.net(net("mcu:PB1")).name, "wires[0][1]");
+        // A single pin is wired to every pin of the bus.
+        assert_eq!(net("mcu:PD0"), net("mcu:PA0"));
+        assert_eq!(net("mcu:PD0"), net("mcu:PA1"));
+
+        let mismatch = mcu_with("      led:\n        type: led\n")
+            + "wires:\n  - mcu:PB[0..7] -> mcu:PC[0..3]\n  - mcu:PB[0..] -> mcu:PC0\n  \
+               - from: mcu:PB[0..1]\n    to: mcu:PC[0..2]\n  - mcu:PB0 mcu:PC0\n";
+        assert_eq!(
+            load_errors(&mismatch),
+            [
+                "wires[0]: bus widths differ (8 and 4)",
+                "wires[1]: invalid range `[0..]`, expected `[first..last]`",
+                "wires[2]: bus widths differ (2 and 3)",
+                "wires[3]: expected `from -> to`",
+            ]
+        );
+    }
+
+    #[test]
+    fn frequencies() {
+        let parse =
+            |text: &str| parse_frequency(&yaml_rust2::YamlLoader::load_from_str(text).unwrap()[0]);
+        assert_eq!(parse("16000000"), Ok(16_000_000));
+        assert_eq!(parse("16e6"), Ok(16_000_000));
+        assert_eq!(parse("8000000.0"), Ok(8_000_000));
+        assert_eq!(parse("16MHz"), Ok(16_000_000));
+        assert_eq!(parse("\"32.768 kHz\""), Ok(32_768));
+        assert_eq!(parse("1.6e7Hz"), Ok(16_000_000));
+        assert_eq!(parse("0"), Err("the frequency must be positive".into()));
+        assert_eq!(parse(".inf"), Err("invalid frequency inf".into()));
+        assert_eq!(
+            parse("1.5"),
+            Err("frequency 1.5 is not a whole number of Hz".into())
+        );
+        assert_eq!(
+            parse("16GHz"),
+            Err("unknown unit `GHz`, expected Hz, kHz or MHz".into())
+        );
+        assert_eq!(parse("1.2.3"), Err("invalid frequency `1.2.3`".into()));
+        assert_eq!(
+            parse("[16]"),
+            Err("expected a frequency, like 16000000 or \"16MHz\"".into())
+        );
+
+        let dir = TempDir::new();
+        let mcus = |a: &str, b: &str| {
+            format!(
+                "components:\n  a:\n    type: mcu\n    memory: {f}\n{a}  b:\n    type: mcu\n    memory: {f}\n{b}",
+                f = FIRMWARE,
+                a = a,
+                b = b
+            )
+        };
+        let load_freq = |config: &str| {
+            let path = dir.write("config.yaml", config);
+            load(path.to_str().unwrap(), &LoadOptions::default()).map(|sys| sys.freq)
+        };
+        assert_eq!(load_freq(&mcus("", "")).ok(), Some(FREQ));
+        assert_eq!(
+            load_freq(&mcus("    frequency: 8e6\n", "    frequency: 8MHz\n")).ok(),
+            Some(8_000_000)
+        );
+        assert_eq!(
+            load_errors(&mcus("    frequency: 8MHz\n", "")),
+            ["components.b.frequency: all MCUs must run at the same frequency, `a` runs at 8000000 Hz"]
+        );
+    }
+
+    #[test]
+    fn error_display() {
+        let mut diag = Diagnostics::default();
+        diag.error("", "missing required key `components`");
+        diag.error("wires[2].from", "unknown component `x`");
+        assert_eq!(diag.errors[1].path, "wires[2].from");
+        assert_eq!(
+            ConfigErrors(diag.errors).to_string(),
+            "missing required key `components`\nwires[2].from: unknown component `x`"
+        );
+    }
+
+    fn to_json(yaml: &Yaml) -> serde_json::Value {
+        use serde_json::Value;
+        match yaml {
+            Yaml::String(s) => Value::from(s.as_str()),
+            Yaml::Integer(i) => Value::from(*i),
+            Yaml::Real(_) => Value::from(yaml.as_f64().unwrap()),
+            Yaml::Boolean(b) => Value::from(*b),
+            Yaml::Array(items) => items.iter().map(to_json).collect(),
+            Yaml::Hash(hash) => hash
+                .iter()
+                .map(|(key, value)| (key.as_str().unwrap().to_string(), to_json(value)))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            _ => Value::Null,
+        }
+    }
+
+    #[test]
+    fn schema() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let schema = std::fs::read_to_string(root.join("schema/config.schema.json")).unwrap();
+        let schema = serde_json::from_str(&schema).unwrap();
+        let schema = jsonschema::JSONSchema::options()
+            .with_draft(jsonschema::Draft::Draft202012)
+            .compile(&schema)
+            .unwrap();
+        let validate = |text: &str| {
+            let doc = to_json(&yaml_rust2::YamlLoader::load_from_str(text).unwrap()[0]);
+            schema
+                .validate(&doc)
+                .map_err(|errors| errors.map(|err| err.to_string()).collect::<Vec<_>>())
+        };
+
+        let mut examples = 0;
+        for entry in std::fs::read_dir(root.join("examples")).unwrap() {
+            let path = entry.unwrap().path().join("input.yaml");
+            if let Ok(text) = std::fs::read_to_string(&path) {
+                examples += 1;
+                let result = validate(&text);
+                assert!(result.is_ok(), "{}: {:?}", path.display(), result);
+            }
+        }
+        assert!(examples > 0);
+
+        let uart = mcu_with("      uart:\n        type: uart\n        parity: mark\n");
+        assert!(validate(&uart).is_err());
+        assert!(validate(&mcu_with("      led:\n        type: led\n")).is_ok());
+
+        let mcu = |freq: &str| {
+            format!(
+                "components:\n  mcu:\n    type: mcu\n    memory: {}\n    frequency: {}\n",
+                FIRMWARE, freq
+            )
+        };
+        for freq in ["16000000", "16e6", "16MHz", "1.6e7Hz", "32.768 kHz"] {
+            assert!(validate(&mcu(freq)).is_ok(), "{}", freq);
+        }
+        for freq in ["0", "-16e6", "16GHz"] {
+            assert!(validate(&mcu(freq)).is_err(), "{}", freq);
+        }
     }
 }
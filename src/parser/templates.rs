@@ -0,0 +1,609 @@
+//! Expansion of `include:` and `templates:`, done before the components of a
+//! config file are created.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use yaml_rust2::{yaml::Hash, Yaml, YamlLoader};
+
+use super::{child_path, expand_range, resolve_path, toml, Diagnostics, CONFIG_VERSION};
+
+const ROOT_KEYS: [&str; 6] = [
+    "version",
+    "include",
+    "templates",
+    "components",
+    "wires",
+    "nets",
+];
+
+/// Reads a config file and everything it includes, then instantiates the
+/// templates. The result only has `version`, `components`, `wires` and `nets`.
+pub(super) fn load_document(path: &Path, diag: &mut Diagnostics) -> Result<Yaml, String> {
+    let mut stack = vec![canonical(path)];
+    let mut doc = read_document(path)?;
+    if let Some(hash) = doc.as_mut_hash() {
+        resolve_includes(hash, path, "", &mut stack, diag);
+        expand_templates(hash, diag);
+    }
+    Ok(doc)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn read_document(path: &Path) -> Result<Yaml, String> {
+    let yaml = std::fs::read_to_string(path)
+        .map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        return toml::parse(&yaml)
+            .map_err(|err| format!("invalid TOML in {}: {}", path.display(), err));
+    }
+    // JSON documents are valid YAML too.
+    let docs = YamlLoader::load_from_str(&yaml)
+        .map_err(|err| format!("invalid YAML in {}: {}", path.display(), err))?;
+    docs.into_iter()
+        .next()
+        .ok_or_else(|| format!("{} is empty", path.display()))
+}
+
+/// Merges the files listed in `include` into `doc`. Included paths are
+/// relative to the file including them, and so are the firmware paths
+/// written in the included files.
+fn resolve_includes(
+    doc: &mut Hash,
+    path: &Path,
+    yaml_path: &str,
+    stack: &mut Vec<PathBuf>,
+    diag: &mut Diagnostics,
+) {
+    let include_path = child_path(yaml_path, "include");
+    let files = match doc.remove(&Yaml::from_str("include")) {
+        None => return,
+        Some(Yaml::String(file)) => vec![Yaml::String(file)],
+        Some(Yaml::Array(files)) => files,
+        Some(_) => {
+            diag.error(&include_path, "expected a file or a list of files");
+            return;
+        }
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for (i, file) in files.iter().enumerate() {
+        let entry_path = format!("{}[{}]", include_path, i);
+        let Some(file) = diag.optional_str(file, &entry_path) else {
+            continue;
+        };
+        let file = match resolve_path(dir, file) {
+            Ok(file) => file,
+            Err(err) => {
+                diag.error(&entry_path, err);
+                continue;
+            }
+        };
+        let file_id = canonical(&file);
+        if stack.contains(&file_id) {
+            diag.error(&entry_path, format!("{} includes itself", file.display()));
+            continue;
+        }
+        let mut included = match read_document(&file) {
+            Ok(Yaml::Hash(included)) => included,
+            Ok(_) => {
+                diag.error(&entry_path, format!("{} isn't a mapping", file.display()));
+                continue;
+            }
+            Err(err) => {
+                diag.error(&entry_path, err);
+                continue;
+            }
+        };
+        stack.push(file_id.clone());
+        resolve_includes(&mut included, &file, &entry_path, stack, diag);
+        stack.pop();
+        if let Some(dir) = file_id.parent() {
+            rebase_paths(&mut included, dir);
+        }
+        merge(doc, included, &entry_path, diag);
+    }
+}
+
+/// Makes the relative `memory` and `symbols` paths of the components and
+/// templates of an included file, and the `script` paths of their passive
+/// components, start from `dir`, its directory. Paths starting with an
+/// environment variable are left alone, and so are the ones with `${...}`,
+/// which may come from template parameters given in the main config file.
+fn rebase_paths(doc: &mut Hash, dir: &Path) {
+    let rebase = |component: &mut Yaml, key: &str| {
+        let Some(Yaml::String(path)) = component
+            .as_mut_hash()
+            .and_then(|component| component.get_mut(&Yaml::from_str(key)))
+        else {
+            return;
+        };
+        if !path.starts_with('$') && !path.contains("${") && Path::new(path).is_relative() {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
+        }
+    };
+    let rebase_mcu = |mcu: &mut Yaml| {
+        rebase(mcu, "memory");
+        rebase(mcu, "symbols");
+        if let Some(Yaml::Hash(components)) = mcu
+            .as_mut_hash()
+            .and_then(|mcu| mcu.get_mut(&Yaml::from_str("components")))
+        {
+            for component in components.values_mut() {
+                rebase(component, "script");
+            }
+        }
+    };
+    if let Some(Yaml::Hash(components)) = doc.get_mut(&Yaml::from_str("components")) {
+        components.values_mut().for_each(rebase_mcu);
+    }
+    if let Some(Yaml::Hash(templates)) = doc.get_mut(&Yaml::from_str("templates")) {
+        for template in templates.values_mut() {
+            if let Some(component) = template
+                .as_mut_hash()
+                .and_then(|template| template.get_mut(&Yaml::from_str("component")))
+            {
+                rebase_mcu(component);
+            }
+        }
+    }
+}
+
+/// Adds the content of an included file to `doc`.
+fn merge(doc: &mut Hash, included: Hash, path: &str, diag: &mut Diagnostics) {
+    for (key, value) in included {
+        let Some(name) = key.as_str().filter(|name| ROOT_KEYS.contains(name)) else {
+            diag.error(
+                path,
+                format!(
+                    "unknown key {:?}, expected one of: {}",
+                    key,
+                    ROOT_KEYS.join(", ")
+                ),
+            );
+            continue;
+        };
+        let section_path = child_path(path, name);
+        if name == "version" {
+            if value.as_i64() != Some(CONFIG_VERSION) {
+                diag.error(
+                    &section_path,
+                    format!("unsupported config version, expected {}", CONFIG_VERSION),
+                );
+            }
+            continue;
+        }
+        match (doc.get_mut(&key), value) {
+            (None, value) => {
+                doc.insert(key, value);
+            }
+            (Some(Yaml::Array(list)), Yaml::Array(added)) => {
+                // Included wires come first, like included components.
+                let own = std::mem::replace(list, added);
+                list.extend(own);
+            }
+            (Some(Yaml::Hash(map)), Yaml::Hash(added)) => {
+                let own = std::mem::replace(map, Hash::new());
+                for (entry, value) in added.into_iter().chain(own) {
+                    if map.contains_key(&entry) {
+                        diag.error(
+                            &section_path,
+                            format!("{:?} is defined more than once", entry),
+                        );
+                    } else {
+                        map.insert(entry, value);
+                    }
+                }
+            }
+            _ => diag.error(&section_path, "doesn't match the including file"),
+        }
+    }
+}
+
+/// Replaces the instances of templates in `components` by the components
+/// they define, and adds their wires and nets.
+fn expand_templates(doc: &mut Hash, diag: &mut Diagnostics) {
+    let templates = match doc.remove(&Yaml::from_str("templates")) {
+        None => Hash::new(),
+        Some(Yaml::Hash(templates)) => templates,
+        Some(_) => {
+            diag.error("templates", "expected a mapping");
+            Hash::new()
+        }
+    };
+    for (name, template) in &templates {
+        let path = child_path("templates", name.as_str().unwrap_or_default());
+        if diag.check_keys(template, &path, &["params", "component", "wires", "nets"])
+            && template["component"].is_badvalue()
+        {
+            diag.error(&path, "missing required key `component`");
+        }
+    }
+
+    let Some(Yaml::Hash(components)) = doc.get_mut(&Yaml::from_str("components")) else {
+        return;
+    };
+    let components = std::mem::replace(components, Hash::new());
+    let mut expanded = Hash::new();
+    let mut wires = Vec::new();
+    let mut nets = Hash::new();
+    for (id, component) in components {
+        let (Some(name), false) = (id.as_str(), component["template"].is_badvalue()) else {
+            expanded.insert(id, component);
+            continue;
+        };
+        let path = child_path("components", name);
+        let instances = instantiate(name, &component, &path, &templates, diag);
+        for (name, instance) in instances {
+            if expanded.contains_key(&Yaml::String(name.clone())) {
+                diag.error(
+                    &path,
+                    format!("component `{}` is defined more than once", name),
+                );
+                continue;
+            }
+            expanded.insert(Yaml::String(name), instance.component);
+            wires.extend(instance.wires);
+            nets.extend(instance.nets);
+        }
+    }
+    doc.insert(Yaml::from_str("components"), Yaml::Hash(expanded));
+
+    if !wires.is_empty() {
+        match doc
+            .entry(Yaml::from_str("wires"))
+            .or_insert(Yaml::Array(Vec::new()))
+        {
+            Yaml::Array(list) => list.extend(wires),
+            _ => diag.error("wires", "expected a list"),
+        }
+    }
+    if !nets.is_empty() {
+        match doc
+            .entry(Yaml::from_str("nets"))
+            .or_insert(Yaml::Hash(Hash::new()))
+        {
+            Yaml::Hash(map) => map.extend(nets),
+            _ => diag.error("nets", "expected a mapping"),
+        }
+    }
+}
+
+struct Instance {
+    component: Yaml,
+    wires: Vec<Yaml>,
+    nets: Vec<(Yaml, Yaml)>,
+}
+
+/// Instantiates a template once, or once per index for names like
+/// `node[1..12]`. Besides its declared parameters, a template can use
+/// `${name}`, the name of the instance, and `${index}`. These two can also be
+/// used in the parameters given to the instance.
+fn instantiate(
+    id: &str,
+    component: &Yaml,
+    path: &str,
+    templates: &Hash,
+    diag: &mut Diagnostics,
+) -> Vec<(String, Instance)> {
+    let names = match expand_range(id) {
+        Ok(names) => names,
+        Err(err) => {
+            diag.error(path, err);
+            return Vec::new();
+        }
+    };
+    let fail = |diag: &mut Diagnostics| {
+        diag.invalid
+            .extend(names.iter().map(|(name, _)| name.clone()));
+        Vec::new()
+    };
+
+    diag.check_keys(component, path, &["template", "params"]);
+    let Some(template_name) = diag.required_str(component, path, "template") else {
+        return fail(diag);
+    };
+    let Some(template) = templates.get(&Yaml::from_str(template_name)) else {
+        diag.error(
+            &child_path(path, "template"),
+            format!("unknown template `{}`", template_name),
+        );
+        return fail(diag);
+    };
+
+    let params_path = child_path(path, "params");
+    let mut params: HashMap<String, Yaml> = HashMap::new();
+    match &template["params"] {
+        Yaml::BadValue => {}
+        Yaml::Hash(declared) => {
+            for (param, default) in declared {
+                if let Some(param) = param.as_str() {
+                    params.insert(param.to_string(), default.clone());
+                }
+            }
+        }
+        _ => diag.error(
+            &child_path(&child_path("templates", template_name), "params"),
+            "expected a mapping of parameters to their default values",
+        ),
+    }
+    match &component["params"] {
+        Yaml::BadValue => {}
+        Yaml::Hash(values) => {
+            for (param, value) in values {
+                let param = param.as_str().unwrap_or_default();
+                if params.contains_key(param) {
+                    params.insert(param.to_string(), value.clone());
+                } else {
+                    diag.error(
+                        &child_path(&params_path, param),
+                        format!("unknown parameter of template `{}`", template_name),
+                    );
+                }
+            }
+        }
+        _ => diag.error(&params_path, "expected a mapping"),
+    }
+    let mut missing: Vec<_> = params
+        .iter()
+        .filter(|(_, value)| value.is_null())
+        .map(|(param, _)| param.as_str())
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        diag.error(
+            &params_path,
+            format!("missing required parameters: {}", missing.join(", ")),
+        );
+        return fail(diag);
+    }
+
+    let mut instances = Vec::new();
+    for (name, index) in names {
+        let mut builtins = HashMap::new();
+        builtins.insert("name".to_string(), Yaml::String(name.clone()));
+        if let Some(index) = index {
+            builtins.insert("index".to_string(), Yaml::Integer(index as i64));
+        }
+        let result = (|| {
+            // Values given to the instance can refer to its name and index.
+            let mut vars = builtins.clone();
+            for (param, value) in &params {
+                vars.insert(param.clone(), substitute(value, &builtins)?);
+            }
+            let component = substitute(&template["component"], &vars)?;
+            let wires = match &template["wires"] {
+                Yaml::Array(wires) => wires
+                    .iter()
+                    .map(|wire| substitute(wire, &vars))
+                    .collect::<Result<_, _>>()?,
+                _ => Vec::new(),
+            };
+            let nets = match &template["nets"] {
+                Yaml::Hash(nets) => nets
+                    .iter()
+                    .map(|(net, pins)| {
+                        let net = format!("{}.{}", name, net.as_str().unwrap_or_default());
+                        Ok((Yaml::String(net), substitute(pins, &vars)?))
+                    })
+                    .collect::<Result<_, String>>()?,
+                _ => Vec::new(),
+            };
+            Ok::<_, String>(Instance {
+                component,
+                wires,
+                nets,
+            })
+        })();
+        match result {
+            Ok(instance) => instances.push((name, instance)),
+            Err(err) => {
+                diag.error(path, err);
+                diag.invalid.insert(name);
+            }
+        }
+    }
+    instances
+}
+
+/// Replaces `${param}` in every string of `yaml`. A string made of a single
+/// parameter takes the value of the parameter as is, so that it can be a
+/// number or a boolean.
+fn substitute(yaml: &Yaml, vars: &HashMap<String, Yaml>) -> Result<Yaml, String> {
+    Ok(match yaml {
+        Yaml::String(s) => {
+            if let Some(var) = s.strip_prefix("${").and_then(|s| s.strip_suffix('}')) {
+                if !var.contains('}') {
+                    return lookup(var, vars).cloned();
+                }
+            }
+            let mut result = String::new();
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("${") {
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| format!("unterminated parameter in `{}`", s))?;
+                result.push_str(&rest[..start]);
+                let value = lookup(&rest[start + 2..start + end], vars)?;
+                match value {
+                    Yaml::String(value) => result.push_str(value),
+                    Yaml::Integer(value) => result.push_str(&value.to_string()),
+                    Yaml::Real(value) => result.push_str(value),
+                    Yaml::Boolean(value) => result.push_str(&value.to_string()),
+                    _ => return Err(format!("parameter in `{}` isn't a scalar", s)),
+                }
+                rest = &rest[start + end + 1..];
+            }
+            result.push_str(rest);
+            Yaml::String(result)
+        }
+        Yaml::Array(items) => Yaml::Array(
+            items
+                .iter()
+                .map(|item| substitute(item, vars))
+                .collect::<Result<_, _>>()?,
+        ),
+        Yaml::Hash(map) => Yaml::Hash(
+            map.iter()
+                .map(|(key, value)| Ok((substitute(key, vars)?, substitute(value, vars)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+fn lookup<'a>(var: &str, vars: &'a HashMap<String, Yaml>) -> Result<&'a Yaml, String> {
+    vars.get(var)
+        .ok_or_else(|| format!("unknown parameter `{}`", var))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn load(path: &Path) -> (Yaml, Vec<String>) {
+        let mut diag = Diagnostics::default();
+        let doc = load_document(path, &mut diag).unwrap();
+        let errors = diag.errors.iter().map(ToString::to_string).collect();
+        (doc, errors)
+    }
+
+    fn yaml(text: &str) -> Yaml {
+        YamlLoader::load_from_str(text).unwrap().remove(0)
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = TempDir::new();
+        let main = dir.write("main.yaml", "include: lib/a.yaml\ncomponents: {}\n");
+        dir.write("lib/a.yaml", "include: [b.yaml]\n");
+        dir.write("lib/b.yaml", "include: [a.yaml, ../main.yaml]\n");
+        let (_, errors) = load(&main);
+        let lib = dir.path().join("lib");
+        assert_eq!(
+            errors,
+            [
+                format!(
+                    "include[0].include[0].include[0]: {} includes itself",
+                    lib.join("a.yaml").display()
+                ),
+                format!(
+                    "include[0].include[0].include[1]: {} includes itself",
+                    lib.join("../main.yaml").display()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn template_parameters() {
+        let dir = TempDir::new();
+        let main = dir.write(
+            "main.yaml",
+            "templates:\n  \
+               node:\n    \
+                 params: { fw: null, led: true }\n    \
+                 component: { type: mcu, memory: '${fw}', vcd: '${led}' }\n\
+             components:\n  \
+               a: { template: node }\n  \
+               b: { template: node, params: { fw: b.hex, color: red } }\n  \
+               c: { template: other }\n  \
+               d: { template: node, params: { fw: '${nope}.hex' } }\n",
+        );
+        let (doc, errors) = load(&main);
+        assert_eq!(
+            errors,
+            [
+                "components.a.params: missing required parameters: fw",
+                "components.b.params.color: unknown parameter of template `node`",
+                "components.c.template: unknown template `other`",
+                "components.d: unknown parameter `nope`",
+            ]
+        );
+        // A parameter standing alone keeps its type.
+        assert_eq!(
+            doc["components"]["b"],
+            yaml("{ type: mcu, memory: b.hex, vcd: true }")
+        );
+        assert!(doc["components"]["a"].is_badvalue());
+    }
+
+    #[test]
+    fn nested_templates() {
+        let dir = TempDir::new();
+        let main = dir.write(
+            "main.yaml",
+            "include: lib/nodes.yaml\n\
+             components:\n  \
+               node[1..2]: { template: node, params: { peer: 'node${index}:TXD0' } }\n\
+             wires:\n  - node1:PB0 -> node2:PB0\n",
+        );
+        dir.write(
+            "lib/nodes.yaml",
+            "templates:\n  \
+               node:\n    \
+                 params: { peer: null }\n    \
+                 component:\n      \
+                   type: mcu\n      \
+                   memory: node.hex\n      \
+                   components: { probe: { type: lua, script: probe.lua } }\n    \
+                 wires: ['${peer} -> ${name}:RXD0']\n    \
+                 nets: { bus: ['${name}:PA0', '${name}.probe:0'] }\n",
+        );
+        let (doc, errors) = load(&main);
+        assert_eq!(errors, Vec::<String>::new());
+        let lib = dir.path().join("lib");
+        for (name, index) in [("node1", 1), ("node2", 2)] {
+            let node = &doc["components"][name];
+            assert_eq!(
+                node["memory"].as_str(),
+                Some(lib.join("node.hex").to_str().unwrap())
+            );
+            assert_eq!(
+                node["components"]["probe"]["script"].as_str(),
+                Some(lib.join("probe.lua").to_str().unwrap())
+            );
+            let net = format!("{}.bus", name);
+            assert_eq!(
+                doc["nets"][net.as_str()],
+                yaml(&format!("['{0}:PA0', '{0}.probe:0']", name))
+            );
+            assert!(doc["wires"]
+                .as_vec()
+                .unwrap()
+                .contains(&Yaml::String(format!(
+                    "node{}:TXD0 -> {}:RXD0",
+                    index, name
+                ))));
+        }
+        assert_eq!(doc["wires"][0].as_str(), Some("node1:PB0 -> node2:PB0"));
+    }
+
+    #[test]
+    fn rebased_paths() {
+        let mut doc = yaml(
+            "components:\n  \
+               a: { memory: a.hex, symbols: /abs/a.sym, components: { s: { script: s.lua } } }\n  \
+               b: { memory: $FW/b.hex, symbols: '${sym}' }\n\
+             templates:\n  \
+               t: { component: { memory: t.hex } }\n",
+        );
+        rebase_paths(doc.as_mut_hash().unwrap(), Path::new("/lib"));
+        assert_eq!(
+            doc,
+            yaml(
+                "components:\n  \
+                   a: { memory: /lib/a.hex, symbols: /abs/a.sym, \
+                        components: { s: { script: /lib/s.lua } } }\n  \
+                   b: { memory: $FW/b.hex, symbols: '${sym}' }\n\
+                 templates:\n  \
+                   t: { component: { memory: /lib/t.hex } }\n"
+            )
+        );
+    }
+}
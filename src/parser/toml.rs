@@ -0,0 +1,102 @@
+//! Reader for config files written in TOML. The document is converted to the
+//! same tree as YAML ones, so the rest of the loader doesn't depend on the
+//! format. Dates are kept as strings, since no config value needs them.
+
+use ::toml::{Table, Value};
+use yaml_rust2::{yaml::Hash, Yaml};
+
+pub(super) fn parse(src: &str) -> Result<Yaml, String> {
+    let table: Table = src
+        .parse()
+        .map_err(|err: ::toml::de::Error| err.to_string().trim_end().to_string())?;
+    Ok(convert_table(table))
+}
+
+fn convert_table(table: Table) -> Yaml {
+    Yaml::Hash(
+        table
+            .into_iter()
+            .map(|(key, value)| (Yaml::String(key), convert(value)))
+            .collect::<Hash>(),
+    )
+}
+
+fn convert(value: Value) -> Yaml {
+    match value {
+        Value::String(s) => Yaml::String(s),
+        Value::Integer(i) => Yaml::Integer(i),
+        Value::Float(f) => Yaml::Real(f.to_string()),
+        Value::Boolean(b) => Yaml::Boolean(b),
+        Value::Datetime(datetime) => Yaml::String(datetime.to_string()),
+        Value::Array(items) => Yaml::Array(items.into_iter().map(convert).collect()),
+        Value::Table(table) => convert_table(table),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yaml_rust2::YamlLoader;
+
+    use super::*;
+
+    fn yaml(text: &str) -> Yaml {
+        YamlLoader::load_from_str(text).unwrap().remove(0)
+    }
+
+    #[test]
+    fn tables() {
+        let doc = parse(
+            "version = 1\n\
+             [components.mcu]\n\
+             type = \"mcu\"\n\
+             frequency = 16_000_000\n\
+             components = { led = { type = 'led', vcd = true } }\n\
+             [[wires]]\n\
+             from = \"mcu:PB7\"\n\
+             to = \"mcu.led:0\"\n\
+             [[wires]]\n\
+             from = \"a\\tb\\u00e9\\\"\"\n\
+             to = 'C:\\fw'\n\
+             [nets]\n\
+             bus = [\"a:PA0\", \"b:PA0\",]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            doc,
+            yaml(
+                "version: 1\n\
+                 components:\n  \
+                   mcu:\n    \
+                     type: mcu\n    \
+                     frequency: 16000000\n    \
+                     components: { led: { type: led, vcd: true } }\n\
+                 wires:\n  \
+                   - { from: 'mcu:PB7', to: 'mcu.led:0' }\n  \
+                   - { from: \"a\\tb\\u00e9\\\"\", to: 'C:\\fw' }\n\
+                 nets:\n  \
+                   bus: ['a:PA0', 'b:PA0']\n"
+            )
+        );
+        // Floats are kept as YAML reals.
+        let doc = parse("f = 16e6\ng = 0.5\n").unwrap();
+        assert_eq!(doc["f"], Yaml::Real("16000000".into()));
+        assert_eq!(doc["g"].as_f64(), Some(0.5));
+        // The order of the keys is kept, like in YAML.
+        let doc = parse("b = 1\na = 2\n").unwrap();
+        let keys: Vec<_> = doc.as_hash().unwrap().keys().collect();
+        assert_eq!(keys, [&Yaml::from_str("b"), &Yaml::from_str("a")]);
+    }
+
+    #[test]
+    fn errors() {
+        let err = parse("[components]\nmcu = { type = \"mcu\"\n").unwrap_err();
+        assert!(
+            err.starts_with("TOML parse error at line 2, column"),
+            "{}",
+            err
+        );
+        let err = parse("a = 1\na = 2\n").unwrap_err();
+        assert!(err.starts_with("TOML parse error at line 2"), "{}", err);
+        assert!(err.contains("duplicate key"), "{}", err);
+    }
+}
@@ -1,4 +1,8 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum WireState {
     Low,
     High,
@@ -32,6 +36,24 @@ impl WireState {
         }
     }
 
+    /// Value of a wire driven by all of `states`. Strong drivers override weak
+    /// ones, and opposing drivers of the same strength give [WireState::Error].
+    pub fn resolve(states: impl IntoIterator<Item = WireState>) -> WireState {
+        let mut strong = WireState::Z;
+        let mut weak = WireState::Z;
+        for state in states {
+            match state {
+                WireState::WeakLow | WireState::WeakHigh => weak = weak.combine(&state),
+                _ => strong = strong.combine(&state),
+            }
+        }
+        if strong == WireState::Z {
+            weak
+        } else {
+            strong
+        }
+    }
+
     pub fn to_bool(&self) -> bool {
         InputPinState::read_wire_state(*self) == InputPinState::High
     }
@@ -76,7 +98,7 @@ impl WireState {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum InputPinState {
     Low,
     High,
@@ -107,3 +129,54 @@ impl InputPinState {
         }
     }
 }
+
+/// Change of the value of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
+impl Edge {
+    pub fn matches(self, old: bool, new: bool) -> bool {
+        match self {
+            Edge::Rising => !old && new,
+            Edge::Falling => old && !new,
+            Edge::Any => old != new,
+        }
+    }
+}
+
+impl FromStr for Edge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rising" => Ok(Edge::Rising),
+            "falling" => Ok(Edge::Falling),
+            "any" => Ok(Edge::Any),
+            _ => Err(format!(
+                "invalid edge '{}', expected rising, falling or any",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use WireState::*;
+
+    #[test]
+    fn resolve_drivers() {
+        assert_eq!(WireState::resolve([]), Z);
+        assert_eq!(WireState::resolve([Z, WeakHigh]), WeakHigh);
+        assert_eq!(WireState::resolve([WeakHigh, Low, Z]), Low);
+        assert_eq!(WireState::resolve([High, WeakLow, High]), High);
+        assert_eq!(WireState::resolve([High, Low]), Error);
+        assert_eq!(WireState::resolve([WeakHigh, WeakLow]), Error);
+        assert_eq!(WireState::resolve([WeakHigh, WeakLow, Low]), Low);
+    }
+}
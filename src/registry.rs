@@ -0,0 +1,136 @@
+//! Component types defined outside of this crate. A factory registered under
+//! a type name creates the passive components of that type found in config
+//! files.
+
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+pub use yaml_rust2::Yaml;
+
+use crate::{module::WireableModule, module_id::ModuleAddress};
+
+/// Creates a component from its address and its config: the whole mapping,
+/// with `type` and `vcd` too. Config files in JSON or TOML are given as the
+/// same tree as YAML ones.
+pub type ComponentFactory =
+    dyn Fn(ModuleAddress, &Yaml) -> Result<Box<dyn WireableModule>, String> + Send + Sync;
+
+/// Passive component types that are always available.
+pub const BUILTIN_TYPES: [&str; 3] = ["led", "uart", "lua"];
+
+/// Factories of the component types that config files may use, besides the
+/// [built-in ones](BUILTIN_TYPES).
+///
+/// ```
+/// # use amber::{components::led::Led, registry::ComponentRegistry, LoadOptions};
+/// let mut options = LoadOptions::default();
+/// options
+///     .components
+///     .register("indicator", |id, _config| Ok(Box::new(Led::new(id))))
+///     .unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct ComponentRegistry {
+    factories: HashMap<String, Arc<ComponentFactory>>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the factory of a component type, replacing the previous one
+    /// with the same name. The built-in types can't be replaced.
+    pub fn register<F>(&mut self, type_name: &str, factory: F) -> Result<(), String>
+    where
+        F: Fn(ModuleAddress, &Yaml) -> Result<Box<dyn WireableModule>, String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        if BUILTIN_TYPES.contains(&type_name) {
+            return Err(format!("`{}` is a built-in component type", type_name));
+        }
+        self.factories
+            .insert(type_name.to_string(), Arc::new(factory));
+        Ok(())
+    }
+
+    pub fn get(&self, type_name: &str) -> Option<&ComponentFactory> {
+        self.factories.get(type_name).map(Arc::as_ref)
+    }
+
+    /// Names of the registered types, sorted.
+    pub fn types(&self) -> Vec<&str> {
+        let mut types: Vec<&str> = self.factories.keys().map(String::as_str).collect();
+        types.sort_unstable();
+        types
+    }
+}
+
+impl Debug for ComponentRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.types()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::led::Led,
+        load,
+        test_util::{TempDir, FIRMWARE},
+        LoadOptions,
+    };
+
+    fn load_config(components: &str, options: &LoadOptions) -> Result<(), String> {
+        let dir = TempDir::new();
+        let config = format!(
+            "components:\n  mcu:\n    type: mcu\n    memory: {}\n    components:\n{}",
+            FIRMWARE, components
+        );
+        let path = dir.write("config.yaml", &config);
+        let result = load(path.to_str().unwrap(), options);
+        result.map(|_| ()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn custom_components() {
+        let mut options = LoadOptions::default();
+        options
+            .components
+            .register("indicator", |id, config| {
+                config["color"]
+                    .as_str()
+                    .ok_or("missing color")
+                    .map(|_| Box::new(Led::new(id)) as Box<dyn WireableModule>)
+                    .map_err(|err| err.to_string())
+            })
+            .unwrap();
+        assert_eq!(
+            options
+                .components
+                .register("led", |id, _| Ok(Box::new(Led::new(id)))),
+            Err("`led` is a built-in component type".to_string())
+        );
+
+        let ok = "      ind:\n        type: indicator\n        color: red\n";
+        assert_eq!(load_config(ok, &options), Ok(()));
+
+        let failing = "      ind:\n        type: indicator\n";
+        assert_eq!(
+            load_config(failing, &options),
+            Err("components.mcu.components.ind: missing color".to_string())
+        );
+
+        let unknown = "      ind:\n        type: buzzer\n";
+        assert_eq!(
+            load_config(unknown, &options),
+            Err(
+                "components.mcu.components.ind.type: unknown component type `buzzer`, \
+                 expected one of: led, uart, lua, indicator"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::clock::Timestamp;
+
+const SNAPSHOT_VERSION: u32 = 6;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Format(bincode::Error),
+    Mismatch(String),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot IO error: {}", err),
+            SnapshotError::Format(err) => write!(f, "corrupted snapshot: {}", err),
+            SnapshotError::Mismatch(msg) => write!(f, "snapshot doesn't match the system: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        SnapshotError::Format(err)
+    }
+}
+
+pub type SnapshotResult<T> = Result<T, SnapshotError>;
+
+/// Accumulates the state of a module tree, in the order the modules are visited.
+#[derive(Debug, Default)]
+pub struct StateWriter(Vec<u8>);
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn write<T: Serialize + ?Sized>(&mut self, value: &T) {
+        bincode::serialize_into(&mut self.0, value).expect("Couldn't serialize module state");
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Reads back the state written by [StateWriter], visiting the modules in the same order.
+#[derive(Debug)]
+pub struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    pub fn read<T: DeserializeOwned>(&mut self) -> SnapshotResult<T> {
+        Ok(bincode::deserialize_from(&mut self.0)?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Complete state of a [System](crate::system::System). The structure of the
+/// system itself (components and wiring) comes from the config file, so a
+/// snapshot can only be restored into a system loaded from the same config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemSnapshot {
+    version: u32,
+    pub t: Timestamp,
+    pub modules: Vec<(String, Vec<u8>)>,
+    /// State of the system outside of its modules, like the injected faults.
+    pub system: Vec<u8>,
+}
+
+impl SystemSnapshot {
+    pub fn new(t: Timestamp, modules: Vec<(String, Vec<u8>)>, system: Vec<u8>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            t,
+            modules,
+            system,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> SnapshotResult<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> SnapshotResult<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let snapshot: Self = bincode::deserialize_from(reader)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Mismatch(format!(
+                "unsupported snapshot version {}",
+                snapshot.version
+            )));
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::PB7_HIGH, System, SystemBuilder};
+
+    /// Counts in r16 and writes the count to PORTB, forever.
+    const COUNTER: [u16; 5] = [
+        0xEF1F, // ldi r17, 0xFF
+        0xB914, // out DDRB, r17
+        0x9503, // inc r16
+        0xB905, // out PORTB, r16
+        0xCFFD, // rjmp .-6
+    ];
+
+    #[test]
+    fn snapshot_restore() {
+        let build = || {
+            SystemBuilder::new()
+                .mcu_with_flash("mcu", &COUNTER)
+                .led("mcu", "led")
+                .wire("mcu:PB0", "mcu.led:0")
+                .mcu_with_flash("other", &PB7_HIGH)
+                .build()
+                .unwrap()
+        };
+        let state = |sys: &mut System| {
+            (
+                sys.t,
+                sys.read_memory("mcu", 16).unwrap(),
+                sys.read_pin("mcu.led:0").unwrap(),
+            )
+        };
+        let mut uninterrupted = build();
+        uninterrupted.run_for(2001);
+
+        let mut sys = build();
+        sys.run_for(1000);
+        let snapshot = sys.snapshot();
+        sys.run_for(500);
+        sys.restore(&snapshot).unwrap();
+        sys.run_for(1001);
+        assert_eq!(state(&mut sys), state(&mut uninterrupted));
+
+        // A snapshot that doesn't load leaves the system as it was, even
+        // the components loaded before the failing one.
+        let mut corrupted = snapshot.clone();
+        corrupted.modules[1].1.truncate(1);
+        let before = state(&mut sys);
+        assert!(sys.restore(&corrupted).is_err());
+        assert_eq!(state(&mut sys), before);
+        sys.run_for(500);
+        uninterrupted.run_for(500);
+        assert_eq!(state(&mut sys), state(&mut uninterrupted));
+    }
+}
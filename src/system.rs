@@ -1,22 +1,54 @@
 use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicI64, AtomicU16, Ordering},
-    thread::sleep,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        RwLock,
+    },
+    thread::{sleep, yield_now},
     time::{Duration, Instant},
 };
 
 use kanal::Sender;
-use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    clock::Timestamp,
+    clock::{TimeDiff, Timestamp},
+    components::{
+        avr::mcu::{Breakpoint, BreakpointHit, Mcu, ResetCause, ValueChange},
+        uart_module::UartModule,
+    },
+    control::{ControlChannel, ControlCommand, SystemController},
+    events::{LinkFault, PinChange, WireChangeEvent, SYNC_WINDOW},
+    generator::Generator,
+    history::{History, HistoryError, Input},
     module::{ActiveModule, Module, PinId},
     module_id::{ModuleAddress, PinAddress},
-    pin_state::WireState,
+    parser::resolve_path,
+    pin_state::{Edge, WireState},
+    snapshot::{SnapshotError, SnapshotResult, StateReader, StateWriter, SystemSnapshot},
     system_tables::SystemTables,
     vcd::{VcdEvent, VcdReceiver},
+    wiring::{InboxTable, NetId},
 };
 
+const REALTIME_FPS: u32 = 60;
+
+/// Marks a module that still has work to do in the current window.
+const MODULE_BUSY: Timestamp = -1;
+
+/// Raises its flag when dropped during a panic.
+struct AbortOnPanic<'a>(&'a AtomicBool);
+
+impl Drop for AbortOnPanic<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
 pub struct System {
     pub system_tables: SystemTables,
     pub modules: Vec<Box<dyn ActiveModule>>,
@@ -24,105 +56,1303 @@ pub struct System {
     pub vcd: Option<VcdReceiver>,
     pub vcd_sender: Sender<VcdEvent>,
     pub t: Timestamp,
+    pub control: ControlChannel,
+    pub history: Option<History>,
+    /// Directory of the config file. Relative paths given by scripts are
+    /// resolved against it.
+    pub base_dir: PathBuf,
+    /// Frequency of the simulated clock, in Hz. Timestamps count its cycles.
+    pub freq: i64,
+    /// Pins watched for changes, by id, see [System::watch_pin].
+    watches: BTreeMap<usize, (PinAddress, Edge)>,
+    pin_changes: Vec<PinChange>,
+    /// Breakpoints by id, with the name of their MCU, see
+    /// [System::add_breakpoint].
+    breakpoints: BTreeMap<usize, (String, Breakpoint)>,
+    breakpoint_hits: Vec<BreakpointHit>,
+    generators: BTreeMap<usize, Generator>,
+    /// Injected faults by id, with the time they end at if they don't last
+    /// until removed.
+    faults: BTreeMap<usize, (Fault, Option<Timestamp>)>,
+    next_id: usize,
+    /// Sync windows run by the threads so far, the ones skipped while every
+    /// module was idle not counted.
+    windows: u64,
+}
+
+/// Fault injected into a running system, see [System::remove_fault].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Fault {
+    /// A net held at a value, see [System::stick_pin].
+    Stuck(NetId, WireState),
+    /// A [LinkFault] in the event queue of this module.
+    Link(usize),
+    /// An MCU held in reset, see [System::reset_mcu].
+    Reset(String),
+}
+
+/// Result of applying the pending [ControlCommand]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlOutcome {
+    Unchanged,
+    /// The simulation was paused or stepped in between, so wall-clock pacing has to restart.
+    Interrupted,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RealtimeSpeed {
+    /// Model time runs this many times faster than wall-clock time.
+    Factor(f64),
+    /// No pacing at all, the simulation runs as fast as the host allows.
+    Max,
+}
+
+impl FromStr for RealtimeSpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(RealtimeSpeed::Max);
+        }
+        match s.trim_end_matches('x').parse::<f64>() {
+            Ok(f) if f > 0.0 && f.is_finite() => Ok(RealtimeSpeed::Factor(f)),
+            _ => Err(format!(
+                "invalid speed '{}', expected a positive factor like 0.5, 10 or max",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do when the host can't keep up with the requested speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Run the missed model time as fast as possible until back on schedule.
+    CatchUp,
+    /// Skip the missed time, so the simulation just runs slower than requested.
+    Drop,
+}
+
+impl FromStr for LagPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "catch-up" => Ok(LagPolicy::CatchUp),
+            "drop" => Ok(LagPolicy::Drop),
+            _ => Err(format!(
+                "invalid lag policy '{}', expected catch-up or drop",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RealtimeConfig {
+    pub freq: i64,
+    pub speed: RealtimeSpeed,
+    pub lag_policy: LagPolicy,
+    /// How often to print the achieved speed, `None` to only report lagging.
+    pub status_interval: Option<Duration>,
+}
+
+struct RealtimeStatus {
+    last_print: Instant,
+    last_t: Timestamp,
+    last_dropped: i64,
+    dropped: i64,
+}
+
+impl RealtimeStatus {
+    const LAG_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new(t: Timestamp) -> Self {
+        Self {
+            last_print: Instant::now(),
+            last_t: t,
+            last_dropped: 0,
+            dropped: 0,
+        }
+    }
+
+    fn update(
+        &mut self,
+        t: Timestamp,
+        config: &RealtimeConfig,
+        base_t: Timestamp,
+        base_instant: Instant,
+        cycles_per_sec: f64,
+    ) {
+        let elapsed = self.last_print.elapsed();
+        let behind = if config.speed == RealtimeSpeed::Max {
+            0.0
+        } else {
+            base_instant.elapsed().as_secs_f64() - (t - base_t) as f64 / cycles_per_sec
+        };
+        let lagging = behind > 0.1 || self.dropped > self.last_dropped;
+        let print = match config.status_interval {
+            Some(interval) => elapsed >= interval,
+            None => lagging && elapsed >= Self::LAG_WARNING_INTERVAL,
+        };
+        if !print {
+            return;
+        }
+
+        let model_secs = (t - self.last_t) as f64 / config.freq as f64;
+        let ratio = model_secs / elapsed.as_secs_f64();
+        let target = match config.speed {
+            RealtimeSpeed::Factor(f) => format!("{:.2}x", f),
+            RealtimeSpeed::Max => "max".to_string(),
+        };
+        let mut line = format!("Speed: {:.2}x (target {})", ratio, target);
+        if behind > 0.1 {
+            line += &format!(", behind by {} ms", (behind * 1000.0) as i64);
+        }
+        if self.dropped > self.last_dropped {
+            let dropped_ms = (self.dropped - self.last_dropped) * 1000 / config.freq;
+            line += &format!(", dropped {} ms of model time", dropped_ms);
+        }
+        eprintln!("{}", line);
+
+        self.last_print = Instant::now();
+        self.last_t = t;
+        self.last_dropped = self.dropped;
+    }
 }
 
 impl System {
+    /// Puts together a system from its components, once they are wired.
+    pub(crate) fn new(
+        system_tables: SystemTables,
+        modules: Vec<Box<dyn ActiveModule>>,
+        id_map: HashMap<String, ModuleAddress>,
+        vcd: VcdReceiver,
+        base_dir: PathBuf,
+        freq: i64,
+    ) -> Self {
+        let mut names = system_tables.names.write().unwrap();
+        for (name, &address) in &id_map {
+            names.insert(address, name.clone());
+        }
+        drop(names);
+        System {
+            system_tables,
+            modules,
+            id_map,
+            vcd_sender: vcd.sender.clone(),
+            vcd: Some(vcd),
+            t: 0,
+            control: ControlChannel::new(),
+            history: None,
+            base_dir,
+            freq,
+            watches: BTreeMap::new(),
+            pin_changes: Vec::new(),
+            breakpoints: BTreeMap::new(),
+            breakpoint_hits: Vec::new(),
+            generators: BTreeMap::new(),
+            faults: BTreeMap::new(),
+            next_id: 0,
+            windows: 0,
+        }
+    }
+
+    /// Runs for `delta` cycles. [Watched](System::watch_pin) pins and
+    /// [breakpoints](System::add_breakpoint) don't stop the run.
     pub fn run_for(&mut self, delta: i64) {
-        const MAX_DESYNC: i64 = 100;
+        self.run_segments(delta, None);
+    }
+
+    /// Like [System::run_until_change], but the modules run one after the
+    /// other in the calling thread. That is faster for a few cycles, since
+    /// there are no threads to synchronize.
+    pub fn step(&mut self, delta: TimeDiff) -> Option<Timestamp> {
+        self.run_segments(delta, Some(&[]))
+    }
+
+    /// Runs for `delta` cycles, or until a [watched](System::watch_pin) pin
+    /// changes or a [breakpoint](System::add_breakpoint) is hit. Returns the
+    /// time the simulation stopped at, which is later than the change when it
+    /// comes from another module (see [System::run_until_edge]).
+    pub fn run_until_change(&mut self, delta: TimeDiff) -> Option<Timestamp> {
+        if self.watches.is_empty() && self.breakpoints.is_empty() {
+            self.run_for(delta);
+            return None;
+        }
+        self.run_segments(delta, Some(&[]))
+    }
+
+    /// Watches `pin` for an `edge`: the runs that stop at changes, like
+    /// [System::run_until_change], stop there, and record it for
+    /// [System::take_pin_changes]. Returns the id of the watch.
+    pub fn watch_pin(&mut self, pin: PinAddress, edge: Edge) -> usize {
+        let id = self.new_id();
+        self.watches.insert(id, (pin, edge));
+        id
+    }
+
+    pub fn unwatch_pin(&mut self, id: usize) -> bool {
+        self.watches.remove(&id).is_some()
+    }
+
+    /// Changes of the watched pins since the last call, oldest first.
+    pub fn take_pin_changes(&mut self) -> Vec<PinChange> {
+        std::mem::take(&mut self.pin_changes)
+    }
+
+    /// Sets a breakpoint in the MCU `mcu`: the runs that stop at changes,
+    /// like [System::run_until_change], stop when it is hit, and record it
+    /// for [System::take_breakpoint_hits]. Returns its id.
+    pub fn add_breakpoint(&mut self, mcu: &str, breakpoint: Breakpoint) -> Result<usize, String> {
+        if self.mcu_mut(mcu).is_none() {
+            return Err(format!("unknown MCU `{}`", mcu));
+        }
+        let id = self.new_id();
+        self.breakpoints.insert(id, (mcu.to_string(), breakpoint));
+        Ok(id)
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        self.breakpoints.remove(&id).is_some()
+    }
+
+    /// MCU and condition of a breakpoint.
+    pub fn breakpoint(&self, id: usize) -> Option<(&str, Breakpoint)> {
+        let (mcu, breakpoint) = self.breakpoints.get(&id)?;
+        Some((mcu, *breakpoint))
+    }
+
+    /// Breakpoints hit since the last call, oldest first.
+    pub fn take_breakpoint_hits(&mut self) -> Vec<BreakpointHit> {
+        std::mem::take(&mut self.breakpoint_hits)
+    }
+
+    /// Starts driving a pin with a generator. Returns its id.
+    pub fn add_generator(&mut self, mut generator: Generator) -> usize {
+        generator.seek(self.t);
+        let id = self.new_id();
+        self.generators.insert(id, generator);
+        id
+    }
+
+    /// Stops a generator, leaving its pin as it is.
+    pub fn remove_generator(&mut self, id: usize) -> bool {
+        match self.generators.get_mut(&id) {
+            // It is kept, in case the history is replayed.
+            Some(generator) => {
+                generator.stop_at(self.t);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Holds the net `pin` is on at `state`, whatever drives it, for
+    /// `duration` cycles or until the fault is [removed](System::remove_fault).
+    /// Returns the id of the fault.
+    pub fn stick_pin(
+        &mut self,
+        pin: PinAddress,
+        state: WireState,
+        duration: Option<TimeDiff>,
+    ) -> Result<usize, String> {
+        let net = self.system_tables.wiring.read().unwrap().net_of(pin);
+        let Some(net) = net else {
+            let names = self.system_tables.names.read().unwrap();
+            return Err(format!(
+                "{} isn't wired to anything",
+                names.describe_pin(pin)
+            ));
+        };
+        let id = self.new_id();
+        let end = duration.map(|duration| self.t + duration);
+        self.input(Input::StickNet {
+            id,
+            net,
+            state,
+            end,
+        });
+        Ok(id)
+    }
+
+    fn force_net(&mut self, net: NetId, state: Option<WireState>) {
+        let modules = self
+            .system_tables
+            .wiring
+            .read()
+            .unwrap()
+            .net(net)
+            .modules
+            .clone();
+        for module in modules {
+            let queue = self.modules[module as usize].event_queue_mut();
+            queue.force_net(net, state, self.t);
+        }
+    }
+
+    /// Applies `fault` to the changes `from` drives onto its net, as `to`
+    /// sees them, until the fault is [removed](System::remove_fault). Returns
+    /// the id of the fault.
+    pub fn add_link_fault(
+        &mut self,
+        from: PinAddress,
+        to: PinAddress,
+        fault: LinkFault,
+    ) -> Result<usize, String> {
+        let wiring = self.system_tables.wiring.read().unwrap();
+        let net = wiring.net_of(to);
+        if net.is_none() || net != wiring.net_of(from) {
+            drop(wiring);
+            let names = self.system_tables.names.read().unwrap();
+            return Err(format!(
+                "{} and {} aren't wired together",
+                names.describe_pin(from),
+                names.describe_pin(to)
+            ));
+        }
+        drop(wiring);
+        let id = self.new_id();
+        self.input(Input::Link {
+            id,
+            from,
+            to,
+            fault,
+        });
+        Ok(id)
+    }
+
+    /// Resets the MCU `mcu` for `cause`. It is held in reset for `duration`
+    /// cycles, or until the fault is [removed](System::remove_fault), then
+    /// runs from the reset vector. Returns the id of the fault.
+    pub fn reset_mcu(
+        &mut self,
+        mcu: &str,
+        cause: ResetCause,
+        duration: Option<TimeDiff>,
+    ) -> Result<usize, String> {
+        if self.mcu_mut(mcu).is_none() {
+            return Err(format!("unknown MCU `{}`", mcu));
+        }
+        let id = self.new_id();
+        let end = duration.map(|duration| self.t + duration);
+        self.input(Input::Reset {
+            id,
+            mcu: mcu.to_string(),
+            cause,
+            end,
+        });
+        Ok(id)
+    }
+
+    /// Inverts the bit `bit` of a CPU register or of a byte of SRAM of the
+    /// MCU `mcu`. Returns the new value.
+    pub fn flip_bit(&mut self, mcu: &str, address: u16, bit: u8) -> Result<u8, String> {
+        let value = self
+            .mcu_mut(mcu)
+            .ok_or_else(|| format!("unknown MCU `{}`", mcu))?
+            .peek(address)
+            .ok_or_else(|| format!("address {:#x} isn't a CPU register or in SRAM", address))?;
+        let value = value ^ (1 << bit);
+        self.input(Input::Memory {
+            mcu: mcu.to_string(),
+            address,
+            value,
+        });
+        Ok(value)
+    }
+
+    /// Ends a fault. The nets and MCUs that other faults still hold stay
+    /// held.
+    pub fn remove_fault(&mut self, id: usize) -> bool {
+        if !self.faults.contains_key(&id) {
+            return false;
+        }
+        self.input(Input::RemoveFault(id));
+        true
+    }
+
+    fn end_fault(&mut self, id: usize) {
+        let Some((fault, _)) = self.faults.remove(&id) else {
+            return;
+        };
+        match fault {
+            Fault::Stuck(net, _) => {
+                let state = self
+                    .faults
+                    .values()
+                    .rev()
+                    .find_map(|(fault, _)| match fault {
+                        Fault::Stuck(other, state) if *other == net => Some(*state),
+                        _ => None,
+                    });
+                self.force_net(net, state);
+            }
+            Fault::Link(module) => {
+                let queue = self.modules[module].event_queue_mut();
+                queue.remove_link_fault(id, self.t);
+            }
+            Fault::Reset(mcu) => {
+                let held = self
+                    .faults
+                    .values()
+                    .any(|(fault, _)| matches!(fault, Fault::Reset(other) if *other == mcu));
+                if !held {
+                    self.mcu_mut(&mcu).unwrap().release_reset();
+                }
+            }
+        }
+    }
+
+    /// Removes the faults that are over, and returns when the next one ends.
+    fn expire_faults(&mut self) -> Option<Timestamp> {
+        let expired: Vec<usize> = self
+            .faults
+            .iter()
+            .filter(|(_, (_, end))| end.is_some_and(|end| end <= self.t))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.end_fault(id);
+        }
+        self.faults.values().filter_map(|&(_, end)| end).min()
+    }
+
+    /// Applies an input from outside of the simulation at the current time,
+    /// and records it in the history to replay it.
+    fn input(&mut self, input: Input) {
+        if let Some(history) = &mut self.history {
+            history.record_input(self.t, input.clone());
+        }
+        self.apply_input(input);
+    }
+
+    fn apply_input(&mut self, input: Input) {
+        match input {
+            Input::Wire(e) => self.deliver(e),
+            Input::Memory {
+                mcu,
+                address,
+                value,
+            } => self.mcu_mut(&mcu).unwrap().write(address, value),
+            Input::Register { mcu, index, value } => {
+                self.mcu_mut(&mcu).unwrap().write_register(index, value)
+            }
+            Input::Flash {
+                mcu,
+                address,
+                value,
+            } => self.mcu_mut(&mcu).unwrap().write_flash(address, value),
+            Input::Pc { mcu, pc } => self.mcu_mut(&mcu).unwrap().set_pc(pc),
+            Input::Sp { mcu, sp } => self.mcu_mut(&mcu).unwrap().set_sp(sp),
+            Input::Sreg { mcu, sreg } => self.mcu_mut(&mcu).unwrap().set_sreg(sreg),
+            Input::UartSend { uart, chars } => {
+                let uart = self.component_mut::<UartModule>(&uart).unwrap();
+                for c in chars {
+                    uart.write_u16(c);
+                }
+            }
+            Input::StickNet {
+                id,
+                net,
+                state,
+                end,
+            } => {
+                self.force_net(net, Some(state));
+                self.faults.insert(id, (Fault::Stuck(net, state), end));
+            }
+            Input::Link {
+                id,
+                from,
+                to,
+                fault,
+            } => {
+                let module = to.module_address.current() as usize;
+                let queue = self.modules[module].event_queue_mut();
+                queue.add_link_fault(id, from, to, fault);
+                self.faults.insert(id, (Fault::Link(module), None));
+            }
+            Input::Reset {
+                id,
+                mcu,
+                cause,
+                end,
+            } => {
+                self.mcu_mut(&mcu).unwrap().hold_reset(cause);
+                self.faults.insert(id, (Fault::Reset(mcu), end));
+            }
+            Input::RemoveFault(id) => self.end_fault(id),
+        }
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Runs until `pin` has an `edge`, for at most `timeout` cycles. Returns
+    /// the time of the edge. The simulation stops there, unless the edge comes
+    /// from another module: then it is seen, and the simulation stops, up to
+    /// a [SYNC_WINDOW] later, and edges in the last window before the timeout
+    /// are missed. Like [System::run_until_change], it also stops at the
+    /// watched pins.
+    pub fn run_until_edge(
+        &mut self,
+        pin: PinAddress,
+        edge: Edge,
+        timeout: TimeDiff,
+    ) -> Option<Timestamp> {
+        let id = self.watch_pin(pin, edge);
+        self.run_segments(timeout, Some(&[]));
+        self.unwatch_pin(id);
+        let i = self.pin_changes.iter().position(|c| c.watch == id)?;
+        Some(self.pin_changes.remove(i).t)
+    }
+
+    /// Runs until an MCU is about to execute the instruction at word address
+    /// `pc`, for at most `timeout` cycles. Returns the time it stopped at.
+    /// Like [System::run_until_change], it also stops at the watched pins.
+    pub fn run_until_pc(
+        &mut self,
+        mcu: &str,
+        pc: u32,
+        timeout: TimeDiff,
+    ) -> Result<Option<Timestamp>, String> {
+        let id = self.add_breakpoint(mcu, Breakpoint::Pc(pc))?;
+        self.run_segments(timeout, Some(&[]));
+        self.remove_breakpoint(id);
+        let Some(i) = self.breakpoint_hits.iter().position(|hit| hit.id == id) else {
+            return Ok(None);
+        };
+        Ok(Some(self.breakpoint_hits.remove(i).t))
+    }
+
+    /// Queues characters on the UART console `uart`, which sends them in
+    /// turn.
+    pub fn uart_send(&mut self, uart: &str, chars: Vec<u16>) -> Result<(), String> {
+        self.component_mut::<UartModule>(uart)
+            .ok_or_else(|| format!("unknown UART `{}`", uart))?;
+        self.input(Input::UartSend {
+            uart: uart.to_string(),
+            chars,
+        });
+        Ok(())
+    }
+
+    /// Runs until the UART console `uart` receives a character, for at most
+    /// `timeout` cycles. Returns the time it stopped at. Like
+    /// [System::run_until_change], it also stops at the watched pins.
+    pub fn run_until_received(
+        &mut self,
+        uart: &str,
+        timeout: TimeDiff,
+    ) -> Result<Option<Timestamp>, String> {
+        let uart_ref = self
+            .component_mut::<UartModule>(uart)
+            .ok_or_else(|| format!("unknown UART `{}`", uart))?;
+        uart_ref.watch_rx(true);
+        let received = uart_ref.received().len();
+        let module = self.id_map[uart].current() as usize;
+        self.run_segments(timeout, Some(&[module]));
+        let uart = self.component_mut::<UartModule>(uart).unwrap();
+        uart.watch_rx(false);
+        Ok((uart.received().len() > received).then_some(self.t))
+    }
+
+    /// Runs for `delta` cycles, in threads unless `watched` modules are given.
+    /// Then the run ends early when one of them requests a stop, a watched
+    /// pin changes or a breakpoint is hit, and the time of the stop is
+    /// returned.
+    fn run_segments(&mut self, delta: TimeDiff, watched: Option<&[usize]>) -> Option<Timestamp> {
+        let Some(watched) = watched else {
+            // Generators split the run at each of their edges, which is
+            // cheaper without threads to start every time.
+            let watched: Option<&[usize]> = if self.generators.is_empty() {
+                None
+            } else {
+                Some(&[])
+            };
+            return self.run_stimulated(delta, watched);
+        };
+
+        let mut modules = watched.to_vec();
+        for (&id, &(pin, edge)) in &self.watches {
+            let value = self.get_pin(pin).to_bool();
+            let module = pin.module_address.current() as usize;
+            self.modules[module]
+                .event_queue_mut()
+                .watch_pin(id, pin, edge, value);
+            if !modules.contains(&module) {
+                modules.push(module);
+            }
+        }
+        let mut mcus = Vec::new();
+        for (&id, (mcu, breakpoint)) in &self.breakpoints {
+            let module = self.id_map[mcu].current() as usize;
+            let mcu = self.modules[module].as_any_mut().downcast_mut::<Mcu>();
+            mcu.unwrap().add_breakpoint(id, *breakpoint);
+            if !modules.contains(&module) {
+                modules.push(module);
+            }
+            if !mcus.contains(&module) {
+                mcus.push(module);
+            }
+        }
+        let stop = self.run_stimulated(delta, Some(&modules));
+        for &module in &modules {
+            let changes = self.modules[module].event_queue_mut().unwatch_pins();
+            self.pin_changes.extend(changes);
+        }
+        self.pin_changes.sort_by_key(|change| change.t);
+        for module in mcus {
+            let mcu = self.modules[module].as_any_mut().downcast_mut::<Mcu>();
+            let hits = mcu.unwrap().clear_breakpoints();
+            self.breakpoint_hits.extend(hits);
+        }
+        self.breakpoint_hits.sort_by_key(|hit| hit.t);
+        stop
+    }
+
+    /// Runs for `delta` cycles, applying the edges of the generators and the
+    /// inputs recorded in the history on the way.
+    fn run_stimulated(&mut self, delta: TimeDiff, watched: Option<&[usize]>) -> Option<Timestamp> {
+        let target_time = self.t + delta;
+        while self.t < target_time {
+            let mut next = target_time;
+            // The recorded inputs come first, as they were applied while the
+            // system was stopped, once the faults over had been removed.
+            self.expire_faults();
+            if let Some(history) = &mut self.history {
+                let inputs = history.take_due_inputs(self.t);
+                next = next.min(history.next_stop(self.t));
+                for input in inputs {
+                    self.apply_input(input);
+                }
+            }
+            if let Some(end) = self.expire_faults() {
+                next = next.min(end);
+            }
+            for generator in self.generators.values_mut() {
+                while let Some((t, state)) = generator.next_edge() {
+                    if t > self.t {
+                        next = next.min(t);
+                        break;
+                    }
+                    let e = WireChangeEvent {
+                        receiver_id: generator.pin(),
+                        state,
+                    };
+                    let root =
+                        self.modules[e.receiver_id.module_address.current() as usize].as_mut();
+                    root.event_queue_mut().deliver(e, self.t);
+                    generator.advance();
+                }
+            }
+
+            let stop = self.run_span(next - self.t, watched);
+
+            if self
+                .history
+                .as_ref()
+                .is_some_and(|history| history.is_checkpoint_due(self.t))
+            {
+                let snapshot = self.snapshot();
+                self.history.as_mut().unwrap().add_checkpoint(snapshot);
+            }
+            if stop.is_some() {
+                self.expire_faults();
+                return stop;
+            }
+        }
+        // Faults ending with the run are over for what is read after it.
+        self.expire_faults();
+        None
+    }
+
+    fn run_span(&mut self, delta: TimeDiff, watched: Option<&[usize]>) -> Option<Timestamp> {
+        match watched {
+            Some(watched) => self.run_sequential(delta, watched),
+            None => {
+                self.run_windows(delta);
+                None
+            }
+        }
+    }
+
+    /// Runs the modules one after the other in each window, the `watched`
+    /// ones first. Modules don't see each other's events before the next
+    /// window, so when a module requests a stop, the ones after it are run up
+    /// to the time of the stop only. Every module is asked for its stop, the
+    /// earliest one is returned.
+    fn run_sequential(&mut self, delta: TimeDiff, watched: &[usize]) -> Option<Timestamp> {
+        let target_time = self.t + delta;
+        let others: Vec<usize> = (0..self.modules.len())
+            .filter(|i| !watched.contains(i))
+            .collect();
+        while self.t < target_time {
+            let mut end = ((self.t.div_euclid(SYNC_WINDOW) + 1) * SYNC_WINDOW).min(target_time);
+            let mut stop: Option<Timestamp> = None;
+            for &i in watched.iter().chain(&others) {
+                let m = self.modules[i].as_mut();
+                // A stop can be past the end of the window, if it happens
+                // during an instruction that started before.
+                let mut t = self.t;
+                while t < end {
+                    t = ((t.div_euclid(SYNC_WINDOW) + 1) * SYNC_WINDOW).min(end);
+                    m.run_until_time(t);
+                    if let Some(t) = m.event_queue_mut().take_stop() {
+                        end = match stop {
+                            None => t,
+                            Some(_) => end.min(t),
+                        };
+                        stop = Some(stop.map_or(t, |stop| stop.min(t)));
+                        break;
+                    }
+                }
+            }
+            self.t = end;
+            if stop.is_some() {
+                return stop;
+            }
+        }
+        None
+    }
+
+    fn run_windows(&mut self, delta: i64) {
         let start_time = self.t;
         let target_time = start_time + delta;
-        let n = self.modules.len() as u16;
-        let ready_counter = AtomicU16::new(0);
+        let n = self.modules.len();
         let goalpost = AtomicI64::new(start_time);
+        let reached: Vec<AtomicI64> = (0..n).map(|_| AtomicI64::new(start_time)).collect();
+        let wakeups: Vec<AtomicI64> = (0..n).map(|_| AtomicI64::new(MODULE_BUSY)).collect();
+        // Set when a module panics, so that the other threads stop waiting for
+        // it and the panic reaches the caller.
+        let aborted = AtomicBool::new(false);
+        let windows = AtomicU64::new(0);
+        let inbox = self.system_tables.inbox.clone();
 
         std::thread::scope(|s| {
-            for m in &mut self.modules {
-                // let mut bus_rx = goalpost_bus.add_rx();
-                let counter_ref = &ready_counter;
+            for ((m, wakeup), reached) in self.modules.iter_mut().zip(&wakeups).zip(&reached) {
                 let goalpost_ref = &goalpost;
+                let aborted_ref = &aborted;
                 s.spawn(move || {
+                    let _guard = AbortOnPanic(aborted_ref);
                     let mut my_t = start_time;
-                    let mut marked = false;
                     loop {
                         let t = goalpost_ref.load(Ordering::SeqCst);
-                        if t >= target_time {
-                            counter_ref.fetch_add(1, Ordering::SeqCst);
-                            break;
-                        }
                         if my_t < t {
-                            marked = false;
                             m.run_until_time(t);
+                            wakeup.store(m.next_wakeup().unwrap_or(MODULE_BUSY), Ordering::SeqCst);
+                            reached.store(t, Ordering::SeqCst);
                             my_t = t;
-                        } else if !marked {
-                            counter_ref.fetch_add(1, Ordering::SeqCst);
-                            marked = true;
+                        } else if t >= target_time || aborted_ref.load(Ordering::SeqCst) {
+                            break;
+                        } else {
+                            yield_now();
                         }
                     }
                 });
             }
 
             let mut t = self.t;
-            let counter_ref = &ready_counter;
             let goalpost_ref = &goalpost;
+            let reached_ref = &reached;
+            let wakeups_ref = &wakeups;
+            let aborted_ref = &aborted;
+            let windows_ref = &windows;
             s.spawn(move || {
-                while t < target_time {
-                    counter_ref.store(0, Ordering::SeqCst);
-                    let step = MAX_DESYNC.min(target_time - t);
-                    t += step;
+                while t < target_time && !aborted_ref.load(Ordering::SeqCst) {
+                    // Window boundaries stay aligned, so that splitting a run
+                    // into several calls doesn't change its outcome.
+                    let mut next = (t.div_euclid(SYNC_WINDOW) + 1) * SYNC_WINDOW;
+                    // Every module is sleeping or halted, so nothing can happen
+                    // before the earliest pending event: jump straight to it.
+                    if let Some(wakeup) = Self::idle_wakeup(wakeups_ref, &inbox) {
+                        next = next.max(wakeup.div_euclid(SYNC_WINDOW) * SYNC_WINDOW);
+                    }
+                    t = next.min(target_time);
                     goalpost_ref.store(t, Ordering::SeqCst);
+                    windows_ref.fetch_add(1, Ordering::Relaxed);
 
-                    while counter_ref.load(Ordering::SeqCst) < n {}
+                    while reached_ref.iter().any(|r| r.load(Ordering::SeqCst) < t) {
+                        if aborted_ref.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        yield_now();
+                    }
                 }
             });
             self.t = target_time;
         });
+        self.windows += windows.into_inner();
+    }
+
+    fn idle_wakeup(wakeups: &[AtomicI64], inbox: &RwLock<InboxTable>) -> Option<Timestamp> {
+        let mut earliest = Timestamp::MAX;
+        for wakeup in wakeups {
+            match wakeup.load(Ordering::SeqCst) {
+                MODULE_BUSY => return None,
+                t => earliest = earliest.min(t),
+            }
+        }
+        if inbox.read().unwrap().is_empty() {
+            Some(earliest)
+        } else {
+            None
+        }
     }
 
-    pub fn run_realtime(&mut self, freq: i64) -> ! {
-        let fps = 60;
-        let timesteps = freq / fps;
-        let delta = Duration::from_secs(1) / fps as u32;
+    pub fn controller(&self) -> SystemController {
+        self.control.controller()
+    }
+
+    /// Applies pending control commands. While paused, this blocks until the
+    /// simulation is resumed or stopped.
+    pub fn process_control(&mut self) -> ControlOutcome {
+        let mut outcome = ControlOutcome::Unchanged;
+        while let Some(command) = self.control.next() {
+            outcome = ControlOutcome::Interrupted;
+            match command {
+                ControlCommand::Pause => self.control.paused = true,
+                ControlCommand::Resume => self.control.paused = false,
+                ControlCommand::TogglePause => self.control.paused = !self.control.paused,
+                ControlCommand::Step(cycles) => {
+                    self.control.paused = true;
+                    self.run_for(cycles);
+                }
+                ControlCommand::Stop => return ControlOutcome::Stopped,
+            }
+            if self.control.paused {
+                eprintln!("Paused at cycle {}", self.t);
+            } else {
+                eprintln!("Resumed at cycle {}", self.t);
+            }
+        }
+        outcome
+    }
+
+    /// Same as [System::run_for], but split into chunks so that the simulation
+    /// can be paused or stopped in between. Returns `false` if it was stopped early.
+    pub fn run_for_controlled(&mut self, delta: i64, chunk: i64) -> bool {
+        let target_time = self.t + delta;
+        while self.t < target_time {
+            if self.process_control() == ControlOutcome::Stopped {
+                return false;
+            }
+            self.run_for(chunk.min(target_time - self.t));
+        }
+        true
+    }
+
+    /// Runs the simulation paced to wall-clock time until it's stopped through the controller.
+    pub fn run_realtime(&mut self, config: RealtimeConfig) {
+        let cycles_per_sec = match config.speed {
+            RealtimeSpeed::Factor(f) => config.freq as f64 * f,
+            RealtimeSpeed::Max => f64::INFINITY,
+        };
+        // Cycles of a frame at the requested speed. It also bounds how much
+        // is caught up at once, so that commands are handled in between.
+        let frame_cycles = match config.speed {
+            RealtimeSpeed::Factor(_) => (cycles_per_sec / REALTIME_FPS as f64).max(1.0) as i64,
+            RealtimeSpeed::Max => config.freq / REALTIME_FPS as i64,
+        };
+
+        let mut base_instant = Instant::now();
+        let mut base_t = self.t;
+        let mut status = RealtimeStatus::new(self.t);
         loop {
-            let start = Instant::now();
-
-            const MAX_DESYNC: i64 = 100;
-            let target_time = self.t + timesteps;
-            while self.t < target_time {
-                let step = MAX_DESYNC.min(target_time - self.t);
-                self.modules.par_iter_mut().for_each(|m| {
-                    m.run_until_time(self.t + step);
-                });
-                self.t += step;
+            match self.process_control() {
+                ControlOutcome::Unchanged => {}
+                ControlOutcome::Interrupted => {
+                    base_instant = Instant::now();
+                    base_t = self.t;
+                    status = RealtimeStatus::new(self.t);
+                }
+                ControlOutcome::Stopped => return,
+            }
+
+            if config.speed == RealtimeSpeed::Max {
+                self.run_for(frame_cycles);
+            } else {
+                let now = Instant::now();
+                let due = base_t
+                    + (now.duration_since(base_instant).as_secs_f64() * cycles_per_sec) as i64;
+                let mut lag = due - self.t;
+                if lag > frame_cycles && config.lag_policy == LagPolicy::Drop {
+                    // Forget about the missed model time and continue from here
+                    base_instant = now;
+                    base_t = self.t;
+                    status.dropped += lag;
+                    lag = 0;
+                }
+                // Runs up to the time due at the end of this frame, then
+                // sleeps until it is.
+                self.run_for((lag + frame_cycles).clamp(0, frame_cycles));
+
+                let ahead = (self.t - base_t) as f64 / cycles_per_sec
+                    - base_instant.elapsed().as_secs_f64();
+                if ahead > 0.0 {
+                    sleep(Duration::from_secs_f64(ahead));
+                }
+            }
+            status.update(self.t, &config, base_t, base_instant, cycles_per_sec);
+        }
+    }
+
+    fn root_name(&self, module: &dyn ActiveModule) -> String {
+        self.id_map
+            .iter()
+            .find(|(_, addr)| **addr == module.address())
+            .map(|(name, _)| name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Captures the state of every module along with their pending events.
+    /// Must be called between runs, while the module threads are stopped.
+    pub fn snapshot(&mut self) -> SystemSnapshot {
+        let names: Vec<String> = self
+            .modules
+            .iter()
+            .map(|m| self.root_name(m.as_ref()))
+            .collect();
+        let modules = self
+            .modules
+            .iter_mut()
+            .zip(names)
+            .map(|(m, name)| {
+                let mut state = StateWriter::new();
+                m.save_state(&mut state);
+                (name, state.into_inner())
+            })
+            .collect();
+        let mut state = StateWriter::new();
+        state.write(&self.faults);
+        SystemSnapshot::new(self.t, modules, state.into_inner())
+    }
+
+    /// Restores a snapshot. When history is being recorded, it restarts from
+    /// the restored state.
+    pub fn restore(&mut self, snapshot: &SystemSnapshot) -> SnapshotResult<()> {
+        self.restore_modules(snapshot)?;
+        if let Some(history) = &self.history {
+            self.enable_history(history.interval());
+        }
+        Ok(())
+    }
+
+    fn restore_modules(&mut self, snapshot: &SystemSnapshot) -> SnapshotResult<()> {
+        if snapshot.modules.len() != self.modules.len() {
+            return Err(SnapshotError::Mismatch(format!(
+                "snapshot has {} components, system has {}",
+                snapshot.modules.len(),
+                self.modules.len()
+            )));
+        }
+        for (i, (name, _)) in snapshot.modules.iter().enumerate() {
+            let expected = self.root_name(self.modules[i].as_ref());
+            if *name != expected {
+                return Err(SnapshotError::Mismatch(format!(
+                    "expected component {}, found {}",
+                    expected, name
+                )));
             }
-            let elapsed = start.elapsed();
-            if elapsed < delta {
-                sleep(delta - elapsed);
+        }
+
+        let mut state = StateReader::new(&snapshot.system);
+        let faults: BTreeMap<usize, (Fault, Option<Timestamp>)> = state.read()?;
+        if !state.is_empty() {
+            return Err(SnapshotError::Mismatch(
+                "leftover state for the system".to_string(),
+            ));
+        }
+
+        // The state of the system is kept to go back to it if a component
+        // fails to load, so that the snapshot is restored entirely or not
+        // at all.
+        let previous = self.snapshot();
+        if let Err(err) = self.load_modules(snapshot) {
+            self.load_modules(&previous)
+                .expect("the state of the system loads back");
+            return Err(err);
+        }
+        self.t = snapshot.t;
+        // The ids of the restored faults stay taken.
+        self.next_id = faults.keys().fold(self.next_id, |id, &fault| id.max(fault));
+        self.faults = faults;
+        for generator in self.generators.values_mut() {
+            generator.seek(self.t);
+        }
+        Ok(())
+    }
+
+    fn load_modules(&mut self, snapshot: &SystemSnapshot) -> SnapshotResult<()> {
+        for (m, (name, data)) in self.modules.iter_mut().zip(&snapshot.modules) {
+            let mut state = StateReader::new(data);
+            m.load_state(&mut state)?;
+            if !state.is_empty() {
+                return Err(SnapshotError::Mismatch(format!(
+                    "leftover state for component {}",
+                    name
+                )));
             }
         }
+        Ok(())
+    }
+
+    pub fn save_snapshot(&mut self, path: impl AsRef<Path>) -> SnapshotResult<()> {
+        self.snapshot().save(path)
     }
 
-    pub fn pin_address(&self, id: &str) -> PinAddress {
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> SnapshotResult<()> {
+        let snapshot = SystemSnapshot::load(path)?;
+        self.restore(&snapshot)
+    }
+
+    /// Resolves a path given by a script like the paths of the config file:
+    /// environment variables are expanded and relative paths start from the
+    /// directory of the config file.
+    pub fn resolve_path(&self, path: &str) -> Result<PathBuf, String> {
+        resolve_path(&self.base_dir, path)
+    }
+
+    /// Starts recording a checkpoint every `interval` cycles, so that the
+    /// system can travel back in time.
+    pub fn enable_history(&mut self, interval: TimeDiff) {
+        let start = self.snapshot();
+        self.history = Some(History::new(interval, start));
+    }
+
+    /// Moves the system to time `t` of the recorded timeline, by replaying it
+    /// from the closest checkpoint. The inputs recorded at `t` are applied
+    /// once the simulation continues.
+    pub fn travel_to(&mut self, t: Timestamp) -> Result<(), HistoryError> {
+        let history = self.history.as_ref().ok_or(HistoryError::Disabled)?;
+        if t >= self.t {
+            self.run_for(t - self.t);
+            return Ok(());
+        }
+        let checkpoint = history
+            .checkpoint_before(t)
+            .ok_or(HistoryError::OutOfRange(t))?
+            .clone();
+
+        self.restore_modules(&checkpoint)?;
+        self.history.as_mut().unwrap().rewind_inputs(checkpoint.t);
+        self.run_for(t - checkpoint.t);
+        Ok(())
+    }
+
+    pub fn step_back(&mut self, delta: TimeDiff) -> Result<(), HistoryError> {
+        self.travel_to(self.t - delta)
+    }
+
+    /// Finds the last change of a byte in the data space of an MCU (registers
+    /// included) before the current time, searching the recorded history one
+    /// checkpoint interval at a time, newest first.
+    pub fn last_change(
+        &mut self,
+        mcu_id: &str,
+        address: u16,
+    ) -> Result<Option<ValueChange>, HistoryError> {
+        let history = self.history.as_ref().ok_or(HistoryError::Disabled)?;
+        self.find_module(mcu_id)
+            .map_err(HistoryError::InvalidTarget)?;
+        let now = self.t;
+        let mut bounds: Vec<Timestamp> = history.checkpoint_times().filter(|&t| t < now).collect();
+        bounds.push(now);
+
+        let mut found = None;
+        for segment in bounds.windows(2).rev() {
+            self.travel_to(segment[0])?;
+            let mcu: &mut Mcu = self
+                .find_module_mut(mcu_id)
+                .map_err(HistoryError::InvalidTarget)?
+                .as_any_mut()
+                .downcast_mut()
+                .ok_or_else(|| HistoryError::InvalidTarget(format!("{} is not an MCU", mcu_id)))?;
+            if !mcu.watch_changes(address) {
+                return Err(HistoryError::InvalidTarget(format!(
+                    "address {:#06x} can't be watched",
+                    address
+                )));
+            }
+            self.run_for(segment[1] - segment[0]);
+            let mcu: &mut Mcu = self.mcu_mut(mcu_id).unwrap();
+            found = mcu.take_watched_change();
+            if found.is_some() {
+                break;
+            }
+        }
+        self.travel_to(now)?;
+        Ok(found)
+    }
+
+    /// Drives a pin from outside of the simulation, at the current time.
+    pub fn set_wire(&mut self, pin: PinAddress, state: WireState) {
+        self.input(Input::Wire(WireChangeEvent {
+            receiver_id: pin,
+            state,
+        }));
+    }
+
+    fn deliver(&mut self, e: WireChangeEvent) {
+        let root = self.modules[e.receiver_id.module_address.current() as usize].as_mut();
+        root.event_queue_mut().deliver(e, self.t);
+    }
+
+    pub fn pin_address(&self, id: &str) -> Result<PinAddress, String> {
         find_pin_addr(id, &self.id_map, &self.modules)
     }
 
-    pub fn find_module<'a>(&'a self, id: &str) -> &'a dyn Module {
-        let mut addr = *self.id_map.get(id).unwrap();
+    /// The component named `id`.
+    pub fn find_module<'a>(&'a self, id: &str) -> Result<&'a dyn Module, String> {
+        let unknown = || format!("unknown component `{}`", id);
+        let mut addr = *self.id_map.get(id).ok_or_else(unknown)?;
         let root = self.modules[addr.current() as usize].as_ref();
         addr.advance();
 
-        root.find(addr).unwrap()
+        root.find(addr).ok_or_else(unknown)
+    }
+
+    pub fn find_module_mut<'a>(&'a mut self, id: &str) -> Result<&'a mut dyn Module, String> {
+        let unknown = || format!("unknown component `{}`", id);
+        let mut addr = *self.id_map.get(id).ok_or_else(unknown)?;
+        let root = self.modules[addr.current() as usize].as_mut();
+        addr.advance();
+
+        root.find_mut(addr).ok_or_else(unknown)
+    }
+
+    /// The MCU with the given name, or `None` if there is no such MCU.
+    pub fn mcu_mut(&mut self, name: &str) -> Option<&mut Mcu> {
+        self.component_mut(name)
     }
 
-    pub fn find_module_mut<'a>(&'a mut self, id: &str) -> &'a mut dyn Module {
-        let mut addr = *self.id_map.get(id).unwrap();
+    /// Component named `name`, if it is a `T`.
+    pub fn component_mut<T: 'static>(&mut self, name: &str) -> Option<&mut T> {
+        let mut addr = *self.id_map.get(name)?;
         let root = self.modules[addr.current() as usize].as_mut();
         addr.advance();
+        root.find_mut(addr)?.as_any_mut().downcast_mut()
+    }
+
+    /// Runs the simulation for a duration of model time.
+    pub fn run_for_duration(&mut self, duration: Duration) {
+        self.run_for((duration.as_secs_f64() * self.freq as f64).round() as i64);
+    }
+
+    /// Value of a pin given by name, like `mcu:PB7`.
+    pub fn read_pin(&self, pin: &str) -> Result<WireState, String> {
+        Ok(self.get_pin(self.pin_address(pin)?))
+    }
+
+    /// Drives a pin given by name, like `mcu:PB7`, at the current time.
+    pub fn write_pin(&mut self, pin: &str, state: WireState) -> Result<(), String> {
+        let pin = self.pin_address(pin)?;
+        self.set_wire(pin, state);
+        Ok(())
+    }
+
+    /// Reads the data space of an MCU like its CPU does, including the side
+    /// effects of reading peripheral registers.
+    pub fn read_memory(&mut self, mcu: &str, address: u16) -> Result<u8, String> {
+        let mcu = self.mapped_mcu(mcu, address)?;
+        Ok(mcu.read(address))
+    }
+
+    /// Reads the data space of an MCU without side effects.
+    pub fn peek_memory(&mut self, mcu: &str, address: u16) -> Result<u8, String> {
+        self.mapped_mcu(mcu, address)
+            .map(|mcu| mcu.peek_memory(address).unwrap())
+    }
+
+    /// Writes the data space of an MCU like its CPU does.
+    pub fn write_memory(&mut self, mcu: &str, address: u16, value: u8) -> Result<(), String> {
+        self.mapped_mcu(mcu, address)?;
+        self.input(Input::Memory {
+            mcu: mcu.to_string(),
+            address,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Writes a CPU register of an MCU. Unlike a write of its data space
+    /// address, it doesn't trigger watchpoints.
+    pub fn set_register(&mut self, mcu: &str, index: u16, value: u8) -> Result<(), String> {
+        self.known_mcu(mcu)?;
+        check_range("register", index as u32, 32)?;
+        self.input(Input::Register {
+            mcu: mcu.to_string(),
+            index,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Writes the flash word at `address` of an MCU.
+    pub fn write_flash(&mut self, mcu: &str, address: u32, value: u16) -> Result<(), String> {
+        let size = self.known_mcu(mcu)?.flash_size();
+        check_range("flash address", address, size)?;
+        self.input(Input::Flash {
+            mcu: mcu.to_string(),
+            address,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Moves the program counter of an MCU, given as a word address.
+    pub fn set_pc(&mut self, mcu: &str, pc: u32) -> Result<(), String> {
+        let size = self.known_mcu(mcu)?.flash_size();
+        check_range("PC", pc, size)?;
+        self.input(Input::Pc {
+            mcu: mcu.to_string(),
+            pc,
+        });
+        Ok(())
+    }
+
+    pub fn set_sp(&mut self, mcu: &str, sp: u16) -> Result<(), String> {
+        self.known_mcu(mcu)?;
+        self.input(Input::Sp {
+            mcu: mcu.to_string(),
+            sp,
+        });
+        Ok(())
+    }
+
+    pub fn set_sreg(&mut self, mcu: &str, sreg: u8) -> Result<(), String> {
+        self.known_mcu(mcu)?;
+        self.input(Input::Sreg {
+            mcu: mcu.to_string(),
+            sreg,
+        });
+        Ok(())
+    }
 
-        root.find_mut(addr).unwrap()
+    fn known_mcu(&mut self, mcu: &str) -> Result<&mut Mcu, String> {
+        self.mcu_mut(mcu)
+            .ok_or_else(|| format!("unknown MCU `{}`", mcu))
     }
 
+    /// The MCU named `mcu`, if `address` is mapped in its data space.
+    fn mapped_mcu(&mut self, mcu: &str, address: u16) -> Result<&mut Mcu, String> {
+        let mcu = self.known_mcu(mcu)?;
+        match mcu.peek_memory(address) {
+            Some(_) => Ok(mcu),
+            None => Err(format!("no emulated register or SRAM at {:#x}", address)),
+        }
+    }
+
+    /// Value of the wire connected to a pin, or the value the pin drives if
+    /// it isn't connected.
     pub fn get_pin(&self, pin_addr: PinAddress) -> WireState {
         let root = self.modules[pin_addr.module_address.current() as usize].as_ref();
+        if let Some(state) = root.event_queue().net_state(pin_addr) {
+            return state;
+        }
         let translated_addr = root.event_queue().lookup_pin(pin_addr);
         let mut addr = translated_addr.module_address;
 
@@ -133,16 +1363,366 @@ impl System {
     }
 }
 
+/// Checks that `value` is below `end`, `what` naming it in the error.
+pub(crate) fn check_range(what: &str, value: u32, end: u32) -> Result<(), String> {
+    if value < end {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} {:#x} out of range, expected less than {:#x}",
+            what, value, end
+        ))
+    }
+}
+
+/// Resolves a `component:pin` name.
 pub fn find_pin_addr(
     name: &str,
     id_map: &HashMap<String, ModuleAddress>,
     components: &[Box<dyn ActiveModule>],
-) -> PinAddress {
-    let (name, pin) = name.split_once(':').unwrap();
-    let mut addr = *id_map.get(name).unwrap();
+) -> Result<PinAddress, String> {
+    let (component, pin) = name
+        .split_once(':')
+        .ok_or_else(|| format!("invalid pin `{}`, expected `component:pin`", name))?;
+    let mut addr = *id_map
+        .get(component)
+        .ok_or_else(|| format!("unknown component `{}`", component))?;
     let root = components[addr.current() as usize].as_ref();
     addr.advance();
 
-    let m = root.find(addr).unwrap();
-    PinAddress::from(m, pin.parse::<u8>().unwrap())
+    let m = root
+        .find(addr)
+        .ok_or_else(|| format!("unknown component `{}`", component))?;
+    let wireable = m
+        .to_wireable()
+        .ok_or_else(|| format!("`{}` has no pins", component))?;
+    let pin = match pin.parse::<usize>() {
+        Ok(index) if index < wireable.pin_count() => index,
+        Ok(index) => {
+            let count = wireable.pin_count();
+            return Err(format!(
+                "pin {} out of range, `{}` has {} pin{}",
+                index,
+                component,
+                count,
+                if count == 1 { "" } else { "s" }
+            ));
+        }
+        Err(_) => wireable
+            .pin_by_name(pin)
+            .ok_or_else(|| format!("unknown pin `{}` of `{}`", pin, component))?,
+    };
+    Ok(PinAddress::from(m, pin as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        components::led::Led,
+        test_util::{TempDir, PB7_HIGH, PB7_LOW},
+        SystemBuilder,
+    };
+
+    #[test]
+    fn find_modules() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &[0xCFFF]) // rjmp .-2
+            .led("mcu", "led")
+            .build()
+            .unwrap();
+        assert!(sys.find_module("mcu.led").is_ok());
+        assert!(sys
+            .find_module_mut("mcu.led")
+            .unwrap()
+            .as_any_mut()
+            .is::<Led>());
+        assert_eq!(
+            sys.find_module("mcu.lamp").err(),
+            Some("unknown component `mcu.lamp`".to_string())
+        );
+        assert_eq!(
+            sys.find_module_mut("other").err(),
+            Some("unknown component `other`".to_string())
+        );
+        sys.enable_history(100);
+        sys.run_for(10);
+        assert_eq!(
+            sys.last_change("other", 0x100).unwrap_err().to_string(),
+            "unknown component `other`"
+        );
+        assert_eq!(
+            sys.last_change("mcu.led", 0x100).unwrap_err().to_string(),
+            "mcu.led is not an MCU"
+        );
+    }
+
+    #[test]
+    fn pin_names() {
+        let sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &[0xCFFF]) // rjmp .-2
+            .led("mcu", "led")
+            .build()
+            .unwrap();
+        assert_eq!(sys.pin_address("mcu:85"), sys.pin_address("mcu:PL7"));
+        assert_eq!(sys.pin_address("mcu:PB7"), sys.pin_address("mcu:15"));
+        assert!(sys.pin_address("mcu.led:0").is_ok());
+        assert_eq!(
+            sys.pin_address("mcu:86"),
+            Err("pin 86 out of range, `mcu` has 86 pins".into())
+        );
+        assert_eq!(
+            sys.pin_address("mcu:999"),
+            Err("pin 999 out of range, `mcu` has 86 pins".into())
+        );
+        assert_eq!(
+            sys.pin_address("mcu.led:1"),
+            Err("pin 1 out of range, `mcu.led` has 1 pin".into())
+        );
+        assert_eq!(
+            sys.pin_address("mcu:PZ9"),
+            Err("unknown pin `PZ9` of `mcu`".into())
+        );
+    }
+
+    #[test]
+    fn realtime_slow_factor() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .build()
+            .unwrap();
+        let controller = sys.controller();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            controller.stop();
+        });
+        let start = Instant::now();
+        sys.run_realtime(RealtimeConfig {
+            freq: 16_000_000,
+            speed: RealtimeSpeed::Factor(0.1),
+            lag_policy: LagPolicy::CatchUp,
+            status_interval: None,
+        });
+        stopper.join().unwrap();
+        // A tenth of the frequency, and at most a frame ahead of it.
+        let due = start.elapsed().as_secs_f64() * 1_600_000.0;
+        assert!(
+            (sys.t as f64) <= due + 1_600_000.0 / 60.0 + 1.0,
+            "{} > {}",
+            sys.t,
+            due
+        );
+        assert!((sys.t as f64) >= due / 2.0, "{} < {}", sys.t, due);
+    }
+
+    #[test]
+    fn controller() {
+        let dir = TempDir::new();
+        let mut sys = SystemBuilder::new()
+            .vcd(dir.path(), false)
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .led("mcu", "led")
+            .wire("mcu:PB7", "mcu.led:0")
+            .trace("mcu.led")
+            .build()
+            .unwrap();
+        let vcd = sys.vcd.take().unwrap().deploy();
+        let controller = sys.controller();
+        // Paused from the start, the system only runs the steps.
+        controller.pause();
+        let run = std::thread::spawn(move || {
+            sys.run_realtime(RealtimeConfig {
+                freq: sys.freq,
+                speed: RealtimeSpeed::Factor(1.0),
+                lag_policy: LagPolicy::CatchUp,
+                status_interval: None,
+            });
+            sys
+        });
+        let wait = || std::thread::sleep(Duration::from_millis(50));
+        wait();
+        controller.step(1000);
+        wait();
+        controller.step(234);
+        wait();
+        controller.stop();
+        let mut sys = run.join().unwrap();
+        assert_eq!(sys.t, 1234);
+
+        // Resumed, it runs until stopped.
+        controller.resume();
+        let run = std::thread::spawn(move || {
+            let finished = sys.run_for_controlled(sys.freq, 100);
+            (sys, finished)
+        });
+        wait();
+        controller.stop();
+        let (sys, finished) = run.join().unwrap();
+        assert!(!finished);
+        assert!(sys.t > 1234 && sys.t < 1234 + sys.freq, "{}", sys.t);
+        // The changes are all written once the VCD output is dropped.
+        drop(vcd);
+        let out = std::fs::read_to_string(dir.path().join("out.vcd")).unwrap();
+        assert!(out.trim_end().ends_with("\n1!"), "{out}");
+    }
+
+    #[test]
+    fn run_until_events() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .led("mcu", "led")
+            .wire("mcu:PB7", "mcu.led:0")
+            .build()
+            .unwrap();
+        let led = sys.pin_address("mcu.led:0").unwrap();
+        assert_eq!(sys.run_until_pc("mcu", 1, 100), Ok(Some(1)));
+        assert_eq!(sys.run_until_edge(led, Edge::Rising, 100), Some(2));
+        // The MCU is already at the `rjmp .-2`, it stops after executing it
+        // once. Then it halts and nothing happens anymore.
+        assert_eq!(sys.run_until_pc("mcu", 3, 100), Ok(Some(5)));
+        assert_eq!(sys.run_until_edge(led, Edge::Any, 100), None);
+        assert_eq!(sys.run_until_pc("mcu", 3, 100), Ok(None));
+        assert_eq!(sys.t, 205);
+        assert_eq!(
+            sys.run_until_pc("other", 3, 100),
+            Err("unknown MCU `other`".into())
+        );
+    }
+
+    #[test]
+    fn stops_of_several_mcus() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("a", &PB7_HIGH)
+            .mcu_with_flash("b", &PB7_HIGH)
+            .build()
+            .unwrap();
+        // Both MCUs stop in the same window, the one that is run first the
+        // latest.
+        let portb = sys.add_breakpoint("a", Breakpoint::Write(0x25)).unwrap();
+        let ddrb = sys.add_breakpoint("b", Breakpoint::Write(0x24)).unwrap();
+        let hit = |id, t, pc| BreakpointHit {
+            id,
+            t,
+            pc,
+            value: Some(0x80),
+        };
+        assert_eq!(sys.run_until_change(100), Some(2));
+        assert_eq!(
+            sys.take_breakpoint_hits(),
+            [hit(ddrb, 2, 1), hit(portb, 3, 2)]
+        );
+        // Neither stop is left over, and both MCUs run on normally.
+        assert_eq!(sys.run_until_change(100), None);
+        assert_eq!(sys.t, 102);
+        assert_eq!(sys.read_pin("b:PB7"), Ok(WireState::High));
+    }
+
+    #[test]
+    fn idle_fast_forward() {
+        // Starts Timer 1 in CTC mode at clk/1024, matching every 62500
+        // ticks, then halts.
+        const SLOW_TIMER: [u16; 10] = [
+            0xEF04, // ldi r16, 0xF4
+            0x9300, 0x0089, // sts OCR1AH, r16
+            0xE203, // ldi r16, 0x23
+            0x9300, 0x0088, // sts OCR1AL, r16
+            0xE00D, // ldi r16, 0x0D
+            0x9300, 0x0081, // sts TCCR1B, r16
+            0xCFFF, // rjmp .-2
+        ];
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &SLOW_TIMER)
+            .build()
+            .unwrap();
+        // OCF1B, set when the counter wraps to OCR1B, 0.
+        let ocf1b = |sys: &mut System| (sys.read_memory("mcu", 0x36).unwrap() >> 2) & 1;
+        // The run jumps to the window of the first wrap, which happens on
+        // its exact cycle, and is seen from the next one.
+        let wrap = 62500 * 1024;
+        sys.run_for(wrap);
+        assert_eq!(ocf1b(&mut sys), 0);
+        assert!(sys.windows <= 2, "{}", sys.windows);
+        sys.run_for(1);
+        assert_eq!(ocf1b(&mut sys), 1);
+        // An hour wraps 900 times, each costing a window or two instead of
+        // the 576 million windows of stepping through.
+        let windows = sys.windows;
+        sys.run_for(3600 * sys.freq);
+        assert!(sys.windows - windows < 3 * 900, "{}", sys.windows);
+    }
+
+    #[test]
+    fn run_for_duration() {
+        let mut sys = SystemBuilder::new()
+            .frequency(8_000_000)
+            .mcu_with_flash("mcu", &PB7_HIGH)
+            .build()
+            .unwrap();
+        sys.run_for_duration(Duration::from_millis(1));
+        assert_eq!(sys.t, 8_000);
+        sys.run_for_duration(Duration::from_nanos(1_000));
+        assert_eq!(sys.t, 8_008);
+    }
+
+    #[test]
+    fn fault_replay() {
+        let mut sys = SystemBuilder::new()
+            .mcu_with_flash("mcu", &PB7_LOW)
+            .wire("mcu:PB7", "mcu:PB0")
+            .build()
+            .unwrap();
+        let pb7 = sys.pin_address("mcu:PB7").unwrap();
+        let pb0 = sys.pin_address("mcu:PB0").unwrap();
+        // PB7, PB0 as read in PINB, and r20.
+        let sample = |sys: &mut System| {
+            (
+                sys.read_pin("mcu:PB7").unwrap(),
+                sys.read_memory("mcu", 0x23).unwrap() & 1,
+                sys.read_memory("mcu", 20).unwrap(),
+            )
+        };
+        let mut samples = Vec::new();
+        sys.enable_history(100);
+
+        sys.run_for(50);
+        let stuck = sys.stick_pin(pb0, WireState::High, Some(200)).unwrap();
+        sys.run_for(100);
+        samples.push((sys.t, sample(&mut sys)));
+        assert_eq!(samples[0].1, (WireState::High, 1, 0));
+        sys.run_for(130);
+        samples.push((sys.t, sample(&mut sys)));
+        assert_eq!(samples[1].1, (WireState::Low, 0, 0));
+        assert_eq!(sys.flip_bit("mcu", 20, 3), Ok(0x08));
+        let drop = sys.add_link_fault(pb7, pb0, LinkFault::Drop(None)).unwrap();
+        sys.run_for(20);
+        sys.reset_mcu("mcu", ResetCause::External, Some(50))
+            .unwrap();
+        sys.run_for(20);
+        samples.push((sys.t, sample(&mut sys)));
+        assert_eq!(samples[2].1, (WireState::Z, 0, 0x08));
+        sys.run_for(60);
+        samples.push((sys.t, sample(&mut sys)));
+        sys.run_for(20);
+        assert!(sys.remove_fault(drop));
+        sys.run_for(50);
+        samples.push((sys.t, sample(&mut sys)));
+        assert_eq!(samples[4].1, (WireState::Low, 0, 0x08));
+
+        // The faults injected before a checkpoint are restored with it, the
+        // ones injected after it are replayed.
+        for &(t, sample_t) in samples.iter().rev() {
+            sys.travel_to(t).unwrap();
+            assert_eq!(sample(&mut sys), sample_t, "at {}", t);
+        }
+        let &(t, sample_t) = samples.last().unwrap();
+        sys.travel_to(t).unwrap();
+        assert_eq!(sample(&mut sys), sample_t);
+        sys.step_back(t - samples[0].0).unwrap();
+        assert_eq!(sample(&mut sys), samples[0].1);
+        assert!(sys.remove_fault(stuck));
+        sys.run_for(10);
+        assert_eq!(sample(&mut sys), (WireState::Low, 0, 0));
+    }
 }
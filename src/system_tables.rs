@@ -1,12 +1,22 @@
 use std::sync::{Arc, RwLock};
 
-use crate::wiring::{InboxTable, WiringTable};
+use crate::{
+    module_id::NameTable,
+    wiring::{InboxTable, WiringTable},
+};
 
 #[derive(Debug, Clone)]
 pub struct SystemTables {
     pub inbox: Arc<RwLock<InboxTable>>,
     pub wiring: Arc<RwLock<WiringTable>>,
     pub messages: Arc<RwLock<Vec<String>>>,
+    pub names: Arc<RwLock<NameTable>>,
+}
+
+impl Default for SystemTables {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SystemTables {
@@ -15,6 +25,7 @@ impl SystemTables {
             inbox: Arc::new(RwLock::new(InboxTable::new())),
             wiring: Arc::new(RwLock::new(WiringTable::new())),
             messages: Arc::new(RwLock::new(Vec::new())),
+            names: Arc::new(RwLock::new(NameTable::new())),
         }
     }
 }
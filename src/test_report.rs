@@ -0,0 +1,229 @@
+//! Results of Lua test files, written as JUnit XML or TAP for CI tools.
+
+use std::fmt::Write;
+
+use crate::lua::{TestCase, TestResult};
+
+/// The cases of a test file.
+pub struct TestFile {
+    pub path: String,
+    pub cases: Vec<TestCase>,
+}
+
+impl TestFile {
+    /// Name of a case in the reports: the file, followed by the name given
+    /// to `test` if there is one.
+    pub fn case_label(&self, case: &TestCase) -> String {
+        match &case.name {
+            Some(name) => format!("{}: {}", self.path, name),
+            None => self.path.clone(),
+        }
+    }
+
+    fn count(&self, f: impl Fn(&TestResult) -> bool) -> usize {
+        self.cases.iter().filter(|case| f(&case.result)).count()
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// JUnit XML report, with a test suite per file.
+pub fn junit(files: &[TestFile]) -> String {
+    let is_failure = |result: &TestResult| matches!(result, TestResult::Failure(_));
+    let is_error = |result: &TestResult| matches!(result, TestResult::Error(_));
+    let tests: usize = files.iter().map(|file| file.cases.len()).sum();
+    let failures: usize = files.iter().map(|file| file.count(is_failure)).sum();
+    let errors: usize = files.iter().map(|file| file.count(is_error)).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        xml,
+        "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\">",
+        tests, failures, errors
+    )
+    .unwrap();
+    for file in files {
+        let path = escape_xml(&file.path);
+        let time: f64 = file
+            .cases
+            .iter()
+            .map(|case| case.duration.as_secs_f64())
+            .sum();
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+            path,
+            file.cases.len(),
+            file.count(is_failure),
+            file.count(is_error),
+            time
+        )
+        .unwrap();
+        for case in &file.cases {
+            write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                escape_xml(case.name.as_deref().unwrap_or(&file.path)),
+                path,
+                case.duration.as_secs_f64()
+            )
+            .unwrap();
+            if case.passed() && case.messages.is_empty() {
+                xml.push_str("/>\n");
+                continue;
+            }
+            xml.push_str(">\n");
+            match &case.result {
+                TestResult::Success => {}
+                TestResult::Failure(failures) => {
+                    writeln!(
+                        xml,
+                        "      <failure message=\"{}\">{}</failure>",
+                        escape_xml(&failures[0]),
+                        escape_xml(&failures.join("\n"))
+                    )
+                    .unwrap();
+                }
+                TestResult::Error(err) => {
+                    let err = err.to_string();
+                    let message = err.lines().next().unwrap_or_default();
+                    writeln!(
+                        xml,
+                        "      <error message=\"{}\">{}</error>",
+                        escape_xml(message),
+                        escape_xml(&err)
+                    )
+                    .unwrap();
+                }
+            }
+            if !case.messages.is_empty() {
+                writeln!(
+                    xml,
+                    "      <system-out>{}</system-out>",
+                    escape_xml(&case.messages.join("\n"))
+                )
+                .unwrap();
+            }
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// TAP version 13 report. The failures and errors are written as
+/// diagnostics after the cases.
+pub fn tap(files: &[TestFile]) -> String {
+    let tests: usize = files.iter().map(|file| file.cases.len()).sum();
+    let mut tap = format!("TAP version 13\n1..{}\n", tests);
+    let cases = files
+        .iter()
+        .flat_map(|file| file.cases.iter().map(move |case| (file, case)));
+    for (i, (file, case)) in cases.enumerate() {
+        let status = if case.passed() { "ok" } else { "not ok" };
+        writeln!(tap, "{} {} - {}", status, i + 1, file.case_label(case)).unwrap();
+        let details = match &case.result {
+            TestResult::Success => continue,
+            TestResult::Failure(failures) => failures.join("\n"),
+            TestResult::Error(err) => err.to_string(),
+        };
+        for line in details.lines() {
+            writeln!(tap, "# {}", line).unwrap();
+        }
+    }
+    tap
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn case(name: Option<&str>, result: TestResult) -> TestCase {
+        TestCase {
+            name: name.map(str::to_string),
+            result,
+            duration: Duration::from_millis(1500),
+            messages: Vec::new(),
+        }
+    }
+
+    fn files() -> Vec<TestFile> {
+        vec![
+            TestFile {
+                path: "a.lua".to_string(),
+                cases: vec![
+                    case(Some("boots"), TestResult::Success),
+                    case(
+                        Some("a < b"),
+                        TestResult::Failure(vec![
+                            "a.lua:3: t=10: expected 1, got 2".to_string(),
+                            "a.lua:4: t=12: expected a true value, got nil".to_string(),
+                        ]),
+                    ),
+                ],
+            },
+            TestFile {
+                path: "b.lua".to_string(),
+                cases: vec![case(
+                    None,
+                    TestResult::Error(mlua::Error::RuntimeError("b.lua:1: oops".to_string())),
+                )],
+            },
+        ]
+    }
+
+    #[test]
+    fn junit_report() {
+        assert_eq!(
+            junit(&files()),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuites tests=\"3\" failures=\"1\" errors=\"1\">\n\
+             \x20 <testsuite name=\"a.lua\" tests=\"2\" failures=\"1\" errors=\"0\" time=\"3.000\">\n\
+             \x20   <testcase name=\"boots\" classname=\"a.lua\" time=\"1.500\"/>\n\
+             \x20   <testcase name=\"a &lt; b\" classname=\"a.lua\" time=\"1.500\">\n\
+             \x20     <failure message=\"a.lua:3: t=10: expected 1, got 2\">\
+             a.lua:3: t=10: expected 1, got 2\n\
+             a.lua:4: t=12: expected a true value, got nil</failure>\n\
+             \x20   </testcase>\n\
+             \x20 </testsuite>\n\
+             \x20 <testsuite name=\"b.lua\" tests=\"1\" failures=\"0\" errors=\"1\" time=\"1.500\">\n\
+             \x20   <testcase name=\"b.lua\" classname=\"b.lua\" time=\"1.500\">\n\
+             \x20     <error message=\"runtime error: b.lua:1: oops\">\
+             runtime error: b.lua:1: oops</error>\n\
+             \x20   </testcase>\n\
+             \x20 </testsuite>\n\
+             </testsuites>\n"
+        );
+    }
+
+    #[test]
+    fn tap_report() {
+        assert_eq!(
+            tap(&files()),
+            "TAP version 13\n\
+             1..3\n\
+             ok 1 - a.lua: boots\n\
+             not ok 2 - a.lua: a < b\n\
+             # a.lua:3: t=10: expected 1, got 2\n\
+             # a.lua:4: t=12: expected a true value, got nil\n\
+             not ok 3 - b.lua\n\
+             # runtime error: b.lua:1: oops\n"
+        );
+    }
+}
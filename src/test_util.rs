@@ -0,0 +1,64 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Directory of its own for a test, removed when dropped, so that tests
+/// running in parallel don't share files.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "amber-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes a file in the directory and returns its path.
+    pub fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Firmware that the configs of the tests can load.
+pub const FIRMWARE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/examples/blinker/blink_timer.hex"
+);
+
+/// Sets PB7 as an output and drives it high, then loops.
+pub const PB7_HIGH: [u16; 4] = [
+    0xE800, // ldi r16, 0x80
+    0xB904, // out DDRB, r16
+    0xB905, // out PORTB, r16
+    0xCFFF, // rjmp .-2
+];
+
+/// Sets PB7 as an output, low, then loops.
+pub const PB7_LOW: [u16; 3] = [
+    0xE800, // ldi r16, 0x80
+    0xB904, // out DDRB, r16
+    0xCFFF, // rjmp .-2
+];
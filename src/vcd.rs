@@ -7,6 +7,7 @@ use std::{
     cmp::Reverse,
     fs::File,
     io::{BufWriter, Write},
+    path::Path,
     thread::JoinHandle,
 };
 
@@ -108,10 +109,17 @@ impl VcdReceiver {
             ns_per_step: 1,
         }
     }
-    pub fn new(freq: i64, compressed: bool) -> Self {
+    /// Writes to `out.vcd` or `out.vcd.gz` in `dir`.
+    pub fn new(dir: &Path, freq: i64, compressed: bool) -> Self {
+        let filename = dir.join(if compressed { "out.vcd.gz" } else { "out.vcd" });
+        Self::with_file(&filename, freq, compressed)
+    }
+
+    /// Writes to `filename`, gzipped if `compressed`.
+    pub fn with_file(filename: &Path, freq: i64, compressed: bool) -> Self {
         let (sender, receiver) = kanal::bounded(128);
-        let filename = if compressed { "out.vcd.gz" } else { "out.vcd" };
-        let file = File::create(filename).expect("Couldn't create file out.vcd");
+        let file = File::create(filename)
+            .unwrap_or_else(|err| panic!("Couldn't create file {}: {}", filename.display(), err));
         let buf_writer = BufWriter::new(file);
         let writer = if compressed {
             VcdWriter::Gz(GzEncoder::new(buf_writer, Compression::default()))
@@ -2,46 +2,138 @@ use std::collections::HashMap;
 
 use kanal::{Receiver, Sender};
 
-use crate::{clock::Timestamp, events::WireChangeEvent, module_id::PinAddress};
+use crate::{
+    clock::Timestamp,
+    events::NetChangeEvent,
+    module_id::{ModuleIndex, PinAddress},
+};
 
 #[derive(Debug)]
-pub struct InboxTable(HashMap<u8, Sender<(WireChangeEvent, Timestamp)>>);
+pub struct InboxTable(HashMap<ModuleIndex, Sender<(NetChangeEvent, Timestamp)>>);
 
 impl InboxTable {
     pub fn new() -> Self {
         InboxTable(HashMap::new())
     }
-    pub fn add_listener(&mut self, id: u8) -> Receiver<(WireChangeEvent, Timestamp)> {
+    pub fn add_listener(&mut self, id: ModuleIndex) -> Receiver<(NetChangeEvent, Timestamp)> {
         let (s, r) = kanal::bounded(64);
         self.0.insert(id, s);
         r
     }
-    pub fn send(&self, e: WireChangeEvent, t: Timestamp) {
-        if let Some(s) = self.0.get(&e.receiver_id.module_address.current()) {
+    pub fn send(&self, receiver: ModuleIndex, e: NetChangeEvent, t: Timestamp) {
+        if let Some(s) = self.0.get(&receiver) {
             s.send((e, t)).expect("Couldn't send event");
         } else {
-            panic!("Unknown receiver id: {}", e.receiver_id);
+            panic!("Unknown receiver id: {}", receiver);
         }
     }
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|s| s.is_empty())
+    }
 }
 
-pub type WireId = u32;
+pub type NetId = usize;
 
+/// Set of pins connected together. Every pin on a net can drive it, and all
+/// of them read the value resolved from every driver.
 #[derive(Debug)]
-pub struct WiringTable(HashMap<PinAddress, Vec<PinAddress>>);
+pub struct Net {
+    pub name: String,
+    pub pins: Vec<PinAddress>,
+    /// Active modules having pins on this net, in ascending order.
+    pub modules: Vec<ModuleIndex>,
+}
+
+#[derive(Debug)]
+pub struct WiringTable {
+    nets: Vec<Net>,
+    pin_nets: HashMap<PinAddress, NetId>,
+}
 
 impl WiringTable {
     pub fn new() -> Self {
-        WiringTable(HashMap::new())
+        WiringTable {
+            nets: Vec::new(),
+            pin_nets: HashMap::new(),
+        }
     }
-    pub fn add_wire(&mut self, from: PinAddress, to: Vec<PinAddress>) {
-        if let Some(vec) = self.0.get_mut(&from) {
-            vec.extend(to);
-        } else {
-            self.0.insert(from, to);
+
+    /// Connects `pins` together. Nets that some of the pins are already on
+    /// are merged into one, which keeps the name of the oldest of them.
+    pub fn connect(&mut self, name: &str, pins: &[PinAddress]) -> NetId {
+        let id = pins
+            .iter()
+            .filter_map(|pin| self.pin_nets.get(pin).copied())
+            .min()
+            .unwrap_or_else(|| {
+                self.nets.push(Net {
+                    name: name.to_string(),
+                    pins: Vec::new(),
+                    modules: Vec::new(),
+                });
+                self.nets.len() - 1
+            });
+        let mut joined = pins.to_vec();
+        for pin in pins {
+            match self.pin_nets.get(pin) {
+                Some(&other) if other != id => {
+                    let other = &mut self.nets[other];
+                    joined.append(&mut other.pins);
+                    other.modules.clear();
+                }
+                _ => {}
+            }
+        }
+        let net = &mut self.nets[id];
+        for pin in joined {
+            if !net.pins.contains(&pin) {
+                net.pins.push(pin);
+            }
+            self.pin_nets.insert(pin, id);
+            let module = pin.module_address.current();
+            if let Err(i) = net.modules.binary_search(&module) {
+                net.modules.insert(i, module);
+            }
         }
+        id
     }
-    pub fn get_connected(&self, id: PinAddress) -> Option<&Vec<PinAddress>> {
-        self.0.get(&id)
+
+    pub fn net_of(&self, pin: PinAddress) -> Option<NetId> {
+        self.pin_nets.get(&pin).copied()
+    }
+
+    pub fn net(&self, id: NetId) -> &Net {
+        &self.nets[id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module_id::ModuleAddress;
+
+    #[test]
+    fn merge_nets() {
+        let pin = |module, pin| ModuleAddress::root().child_id(module).with_pin(pin);
+        let mut wiring = WiringTable::new();
+        let a = wiring.connect("a", &[pin(0, 1), pin(0, 2)]);
+        let b = wiring.connect("b", &[pin(2, 1), pin(2, 2)]);
+        assert_ne!(a, b);
+        assert_eq!(wiring.net(b).modules, [2]);
+
+        // Joining the two nets keeps the oldest one.
+        assert_eq!(wiring.connect("c", &[pin(2, 2), pin(1, 0), pin(0, 2)]), a);
+        let net = wiring.net(a);
+        assert_eq!(net.name, "a");
+        assert_eq!(net.pins.len(), 5);
+        assert_eq!(net.modules, [0, 1, 2]);
+        assert!(wiring.net(b).pins.is_empty());
+        assert!(wiring.net(b).modules.is_empty());
+        for p in [pin(0, 1), pin(0, 2), pin(1, 0), pin(2, 1), pin(2, 2)] {
+            assert_eq!(wiring.net_of(p), Some(a));
+        }
+        assert_eq!(wiring.net_of(pin(1, 1)), None);
+        assert_eq!(wiring.connect("d", &[pin(0, 1)]), a);
+        assert_eq!(wiring.net(a).pins.len(), 5);
     }
 }